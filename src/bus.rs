@@ -1,10 +1,20 @@
-use crate::{clint::Clint, dram::{DRAM_SIZE, Dram}, exception::Exception, rom::Rom, uart::Uart};
+use crate::{clint::{ClockSource, Clint, TIMEBASE_FREQUENCY}, dram::Dram, exception::Exception, fdt::{Chosen, DeviceTree, Fdt}, htif::Htif, plic::{self, Plic}, rom::Rom, test_finisher::{self, Shutdown, TestFinisher}, uart::Uart, virtio::{self, VirtioDevice, VirtioMmio}};
 
 pub const DRAM_START: u64 = 0x80000000;
-pub const DRAM_END: u64 = DRAM_START + DRAM_SIZE;
 
-pub const DTB_START: u64 = 0x1000;
-pub const DTB_END: u64 = DTB_START + 0xf000;
+pub const HARTS: usize = 1;
+
+pub const TEST_FINISHER_START: u64 = 0x100000;
+pub const TEST_FINISHER_END: u64 = TEST_FINISHER_START + 0x1000;
+
+/// the boot ROM, where the hart starts unless told otherwise
+pub const ROM_START: u64 = 0x1000;
+pub const ROM_END: u64 = ROM_START + 0xf000;
+
+/// the device tree goes in memory, where firmware can add to it, as far up
+/// as there's room for this much on a 2M boundary like QEMU puts it
+pub const DTB_MAX_SIZE: u64 = 0x10000;
+const DTB_ALIGN: u64 = 0x200000;
 
 pub const UART_START: u64 = 0x10000000;
 pub const UART_END: u64 = UART_START + 0x100;
@@ -12,48 +22,350 @@ pub const UART_END: u64 = UART_START + 0x100;
 pub const CLINT_START: u64 = 0x2000000;
 pub const CLINT_END: u64 = CLINT_START + 0x10000;
 
+pub const PLIC_START: u64 = 0xc000000;
+pub const PLIC_END: u64 = PLIC_START + 0x4000000;
+
+/// room for this many virtio-mmio devices one page apart
+pub const VIRTIO_SLOTS: u64 = 8;
+pub const VIRTIO_START: u64 = 0x10001000;
+pub const VIRTIO_STRIDE: u64 = 0x1000;
+pub const VIRTIO_END: u64 = VIRTIO_START + VIRTIO_SLOTS * VIRTIO_STRIDE;
+
+// PLIC interrupt sources, these match the interrupts properties in the device tree
+/// virtio devices use consecutive sources starting here
+pub const VIRTIO_IRQ: usize = 1;
+pub const UART_IRQ: usize = 10;
+
+/// the UART's input clock, it only matters to the guest working out divisors
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+// the local interrupt numbers devices are wired to on each hart
+const MACHINE_SOFTWARE_IRQ: u32 = 3;
+const MACHINE_TIMER_IRQ: u32 = 7;
+const SUPERVISOR_EXTERNAL_IRQ: u32 = 9;
+const MACHINE_EXTERNAL_IRQ: u32 = 11;
+
+// device tree phandles, each hart's interrupt controller gets one from
+// INTC_PHANDLE on and the harts themselves follow those
+const PLIC_PHANDLE: u32 = 1;
+const TEST_FINISHER_PHANDLE: u32 = 2;
+const INTC_PHANDLE: u32 = 3;
+const CPU_PHANDLE: u32 = INTC_PHANDLE + HARTS as u32;
+
 pub struct Bus {
-    pub dtb: Rom,
+    pub rom: Rom,
     pub dram: Dram,
-    uart: Uart,
-    clint: Clint,
+    pub uart: Uart,
+    pub clint: Clint,
+    pub plic: Plic,
+    pub test_finisher: TestFinisher,
+    /// only there when the program was built to talk to a host through tohost and fromhost
+    pub htif: Option<Htif>,
+    virtio: Vec<VirtioMmio>,
 }
 
 impl Bus {
-    pub fn new() -> Self {
+    pub fn new(memory_size: u64, clock: ClockSource, uart: Uart) -> Self {
         Self {
-            dtb: Rom::new(),
-            dram: Dram::new(),
-            uart: Uart::new(),
-            clint: Clint::new(),
+            rom: Rom::new(),
+            dram: Dram::new(memory_size),
+            uart,
+            clint: Clint::new(HARTS, clock),
+            plic: Plic::new(HARTS),
+            test_finisher: TestFinisher::new(),
+            htif: None,
+            virtio: Vec::new(),
+        }
+    }
+
+    /// one past the last byte of memory
+    pub fn dram_end(&self) -> u64 {
+        DRAM_START + self.dram.size()
+    }
+
+    /// where the device tree is put for the firmware, it doesn't move as long as the memory size doesn't
+    pub fn dtb_addr(&self) -> u64 {
+        self.dram_end().saturating_sub(DTB_MAX_SIZE) / DTB_ALIGN * DTB_ALIGN
+    }
+
+    /// puts every device back how it was at power on, memory is left alone
+    pub fn reset(&mut self) {
+        self.uart.reset();
+        self.clint.reset();
+        self.plic = Plic::new(HARTS);
+        self.test_finisher = TestFinisher::new();
+        if let Some(htif) = &mut self.htif {
+            htif.reset();
+        }
+        self.virtio.iter_mut().for_each(VirtioMmio::reset);
+    }
+
+    /// advances the devices by one instruction step
+    pub fn tick(&mut self) {
+        self.clint.tick();
+        self.uart.tick();
+        self.plic.set_irq(UART_IRQ, self.uart.interrupting());
+        for (i, virtio) in self.virtio.iter_mut().enumerate() {
+            virtio.tick(&mut self.dram);
+            self.plic.set_irq(VIRTIO_IRQ + i, virtio.interrupting());
+        }
+        if let Some(htif) = &mut self.htif {
+            htif.tick(&mut self.dram);
+        }
+    }
+
+    /// an exit or reset the guest asked for through the test finisher or HTIF
+    pub fn take_shutdown(&mut self) -> Option<Shutdown> {
+        self.test_finisher.take_request().or_else(|| self.htif.as_mut().and_then(Htif::take_request))
+    }
+
+    pub fn virtio_count(&self) -> usize {
+        self.virtio.len()
+    }
+
+    /// puts a virtio device in the next free slot, returning its base address
+    pub fn attach_virtio(&mut self, device: Box<dyn VirtioDevice>) -> u64 {
+        assert!((self.virtio.len() as u64) < VIRTIO_SLOTS, "out of virtio slots");
+        self.virtio.push(VirtioMmio::new(device));
+        VIRTIO_START + (self.virtio.len() as u64 - 1) * VIRTIO_STRIDE
+    }
+
+    /// a flattened device tree describing the machine as it's been put
+    /// together, `isa` is what each hart implements
+    pub fn device_tree(&self, isa: &str, chosen: &Chosen) -> Vec<u8> {
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "riscv-virtio");
+        fdt.property_string("model", "riscv-virtio,qemu");
+
+        fdt.begin_node("chosen");
+        fdt.property_string("bootargs", &chosen.bootargs);
+        fdt.property_string("stdout-path", &format!("/soc/serial@{UART_START:x}"));
+        if let Some((start, end)) = chosen.initrd {
+            fdt.property_u64s("linux,initrd-start", &[start]);
+            fdt.property_u64s("linux,initrd-end", &[end]);
+        }
+        fdt.end_node();
+
+        fdt.begin_node(&format!("memory@{DRAM_START:x}"));
+        fdt.property_string("device_type", "memory");
+        fdt.property_u64s("reg", &[DRAM_START, self.dram.size()]);
+        fdt.end_node();
+
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+        fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY as u32);
+        for hart in 0..HARTS as u32 {
+            fdt.begin_node(&format!("cpu@{hart}"));
+            fdt.property_u32("phandle", CPU_PHANDLE + hart);
+            fdt.property_string("device_type", "cpu");
+            fdt.property_u32("reg", hart);
+            fdt.property_string("status", "okay");
+            fdt.property_string("compatible", "riscv");
+            fdt.property_string("riscv,isa", isa);
+            fdt.property_string("mmu-type", "riscv,sv48");
+            fdt.begin_node("interrupt-controller");
+            fdt.property_u32("#interrupt-cells", 1);
+            fdt.property_empty("interrupt-controller");
+            fdt.property_string("compatible", "riscv,cpu-intc");
+            fdt.property_u32("phandle", INTC_PHANDLE + hart);
+            fdt.end_node();
+            fdt.end_node();
+        }
+        fdt.begin_node("cpu-map");
+        fdt.begin_node("cluster0");
+        for hart in 0..HARTS as u32 {
+            fdt.begin_node(&format!("core{hart}"));
+            fdt.property_u32("cpu", CPU_PHANDLE + hart);
+            fdt.end_node();
+        }
+        fdt.end_node();
+        fdt.end_node();
+        fdt.end_node();
+
+        // Linux powers off and reboots through the test finisher
+        for (name, value) in [("poweroff", test_finisher::PASS), ("reboot", test_finisher::RESET)] {
+            fdt.begin_node(name);
+            fdt.property_string("compatible", &format!("syscon-{name}"));
+            fdt.property_u32("regmap", TEST_FINISHER_PHANDLE);
+            fdt.property_u32("offset", 0);
+            fdt.property_u32("value", value as u32);
+            fdt.end_node();
+        }
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "simple-bus");
+        fdt.property_empty("ranges");
+
+        fdt.begin_node(&format!("test@{TEST_FINISHER_START:x}"));
+        fdt.property_u32("phandle", TEST_FINISHER_PHANDLE);
+        fdt.property_u64s("reg", &[TEST_FINISHER_START, TEST_FINISHER_END - TEST_FINISHER_START]);
+        fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        fdt.end_node();
+
+        fdt.begin_node(&format!("serial@{UART_START:x}"));
+        fdt.property_u32("interrupts", UART_IRQ as u32);
+        fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+        fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+        fdt.property_u64s("reg", &[UART_START, UART_END - UART_START]);
+        fdt.property_string("compatible", "ns16550a");
+        fdt.end_node();
+
+        // only the slots with something in them
+        for i in 0..self.virtio.len() {
+            let addr = VIRTIO_START + i as u64 * VIRTIO_STRIDE;
+            fdt.begin_node(&format!("virtio_mmio@{addr:x}"));
+            fdt.property_u32("interrupts", (VIRTIO_IRQ + i) as u32);
+            fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+            fdt.property_u64s("reg", &[addr, VIRTIO_STRIDE]);
+            fdt.property_string("compatible", "virtio,mmio");
+            fdt.end_node();
+        }
+
+        let per_hart = |irqs: &[u32]| -> Vec<u32> {
+            (0..HARTS as u32).flat_map(|hart| irqs.iter().flat_map(move |&irq| [INTC_PHANDLE + hart, irq])).collect()
+        };
+
+        fdt.begin_node(&format!("clint@{CLINT_START:x}"));
+        fdt.property_u32s("interrupts-extended", &per_hart(&[MACHINE_SOFTWARE_IRQ, MACHINE_TIMER_IRQ]));
+        fdt.property_u64s("reg", &[CLINT_START, CLINT_END - CLINT_START]);
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.end_node();
+
+        // each hart's machine context comes before its supervisor one
+        fdt.begin_node(&format!("plic@{PLIC_START:x}"));
+        fdt.property_u32("phandle", PLIC_PHANDLE);
+        fdt.property_u32("riscv,ndev", plic::NUM_SOURCES as u32 - 1);
+        fdt.property_u64s("reg", &[PLIC_START, PLIC_END - PLIC_START]);
+        fdt.property_u32s("interrupts-extended", &per_hart(&[MACHINE_EXTERNAL_IRQ, SUPERVISOR_EXTERNAL_IRQ]));
+        fdt.property_empty("interrupt-controller");
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_u32("#address-cells", 0);
+        fdt.end_node();
+
+        fdt.end_node();
+        fdt.end_node();
+        fdt.finish()
+    }
+
+    /// whether all of `addr` to `addr + size` is inside one device or memory,
+    /// an empty virtio slot doesn't count since there's nothing to find there
+    fn is_mapped(&self, addr: u64, size: u64) -> bool {
+        let virtio_end = VIRTIO_START + self.virtio.len() as u64 * VIRTIO_STRIDE;
+        let Some(end) = addr.checked_add(size) else { return false };
+        [
+            (ROM_START, ROM_END),
+            (TEST_FINISHER_START, TEST_FINISHER_END),
+            (UART_START, UART_END),
+            (CLINT_START, CLINT_END),
+            (PLIC_START, PLIC_END),
+            (VIRTIO_START, virtio_end),
+            (DRAM_START, self.dram_end()),
+        ]
+        .iter()
+        .any(|&(start, limit)| start <= addr && end <= limit)
+    }
+
+    /// what's wrong with a device tree that came from elsewhere as a
+    /// description of this machine, nodes for things that aren't here and
+    /// virtio devices it leaves out
+    pub fn check_device_tree(&self, tree: &DeviceTree) -> Vec<String> {
+        let regions = tree.regions();
+        let mut problems = Vec::new();
+        for region in &regions {
+            if !self.is_mapped(region.addr, region.size) {
+                let what = if region.memory { "memory" } else { "a device" };
+                problems.push(format!("{} puts {what} at {:#x}-{:#x} where there isn't any", region.path, region.addr, region.addr.saturating_add(region.size)));
+            }
+        }
+        for i in 0..self.virtio.len() as u64 {
+            let addr = VIRTIO_START + i * VIRTIO_STRIDE;
+            if !regions.iter().any(|region| region.addr == addr) {
+                problems.push(format!("there's no node for the virtio device at {addr:#x}, the guest won't find it"));
+            }
+        }
+        problems
+    }
+
+    /// copies a program into memory at `addr` followed by zeros up to `size`,
+    /// it has to fit in DRAM or the ROM
+    pub fn load(&mut self, addr: u64, data: &[u8], size: u64) -> Result<(), Exception> {
+        let end = addr.checked_add(size).ok_or(Exception::StoreAccessFault(addr))?;
+        match addr {
+            ROM_START..ROM_END if end <= ROM_END => {
+                self.rom.load_at((addr - ROM_START) as usize, data, size as usize);
+            }
+            DRAM_START.. if end <= self.dram_end() => {
+                let memory = self.dram.slice_mut(addr - DRAM_START, size as usize).ok_or(Exception::StoreAccessFault(addr))?;
+                memory[..data.len()].copy_from_slice(data);
+                memory[data.len()..].fill(0);
+            }
+            _ => return Err(Exception::StoreAccessFault(addr)),
         }
+        Ok(())
     }
 
-    pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
-        // dbg!(addr);
-        // dbg!(DTB_START);
+    pub fn read(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
         match addr {
-            DTB_START..DTB_END => self.dtb.read(addr-DTB_START, size),
+            ROM_START..ROM_END => self.rom.read(addr-ROM_START, size),
+            TEST_FINISHER_START..TEST_FINISHER_END => self.test_finisher.read(addr-TEST_FINISHER_START, size),
             UART_START..UART_END => self.uart.read(addr-UART_START, size),
             CLINT_START..CLINT_END => self.clint.read(addr-CLINT_START, size),
-            DRAM_START..DRAM_END => self.dram.read(addr-DRAM_START, size),
-            _ => {
-                println!("addr: {addr:x}");
-                Err(Exception::LoadAccessFault)
+            PLIC_START..PLIC_END => self.plic.read(addr-PLIC_START, size),
+            VIRTIO_START..VIRTIO_END => {
+                let slot = ((addr - VIRTIO_START) / VIRTIO_STRIDE) as usize;
+                match self.virtio.get_mut(slot) {
+                    Some(virtio) => virtio.read((addr - VIRTIO_START) % VIRTIO_STRIDE, size),
+                    None => Ok(virtio::read_empty_slot((addr - VIRTIO_START) % VIRTIO_STRIDE)),
+                }
             }
+            DRAM_START.. if addr.saturating_add(u64::from(size / 8)) <= self.dram_end() => self.dram.read(addr-DRAM_START, size),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
     pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
         match addr {
-            DTB_START..DTB_END => self.dram.write(addr-DTB_START, value, size),
+            ROM_START..ROM_END => Err(Exception::StoreAccessFault(addr)),
+            TEST_FINISHER_START..TEST_FINISHER_END => self.test_finisher.write(addr-TEST_FINISHER_START, value, size),
             UART_START..UART_END => self.uart.write(addr-UART_START, value, size),
             CLINT_START..CLINT_END => self.clint.write(addr-CLINT_START, value, size),
-            DRAM_START..DRAM_END => self.dram.write(addr-DRAM_START, value, size),
-            _ => {
-                println!("addr: {addr:x}");
-                Err(Exception::StoreAccessFault)
+            PLIC_START..PLIC_END => self.plic.write(addr-PLIC_START, value, size),
+            VIRTIO_START..VIRTIO_END => {
+                let slot = ((addr - VIRTIO_START) / VIRTIO_STRIDE) as usize;
+                match self.virtio.get_mut(slot) {
+                    Some(virtio) => virtio.write((addr - VIRTIO_START) % VIRTIO_STRIDE, value, size, &mut self.dram),
+                    None => Ok(()),
+                }
             }
+            DRAM_START.. if addr.saturating_add(u64::from(size / 8)) <= self.dram_end() => self.dram.write(addr-DRAM_START, value, size),
+            _ => Err(Exception::StoreAccessFault(addr)),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn accesses_past_the_end_of_memory_fault() {
+        let uart = Uart::with_io(None, Box::new(io::sink()));
+        let mut bus = Bus::new(0x1000, ClockSource::InstructionCount { instructions_per_tick: 1 }, uart);
+        let end = bus.dram_end();
+        assert_eq!(bus.write(end - 8, 0x0123_4567_89ab_cdef, 64), Ok(()));
+        assert_eq!(bus.read(end - 8, 64), Ok(0x0123_4567_89ab_cdef));
+        // straddling the end isn't in memory any more than starting past it
+        assert_eq!(bus.read(end - 4, 64), Err(Exception::LoadAccessFault(end - 4)));
+        assert_eq!(bus.write(end - 1, 0, 16), Err(Exception::StoreAccessFault(end - 1)));
+        assert_eq!(bus.read(end, 8), Err(Exception::LoadAccessFault(end)));
+        assert_eq!(bus.read(u64::MAX, 8), Err(Exception::LoadAccessFault(u64::MAX)));
+    }
+}
@@ -1,23 +1,157 @@
+use std::time::Instant;
+
 use crate::exception::Exception;
 
+/// matches timebase-frequency in the device tree
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
 
+const MSIP_BASE: u64 = 0x0;
+const MTIMECMP_BASE: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
 
-pub struct Clint {
+/// wall clock time is only sampled every this many ticks as it's relatively slow to read
+const WALL_CLOCK_SAMPLE_INTERVAL: u64 = 1024;
 
+#[derive(Clone, Copy, Debug)]
+pub enum ClockSource {
+    /// mtime follows the host clock at `TIMEBASE_FREQUENCY`
+    WallClock,
+    /// mtime advances by one every `instructions_per_tick` ticks so runs are reproducible
+    InstructionCount { instructions_per_tick: u64 },
+}
+
+pub struct Clint {
+    clock: ClockSource,
+    start: Instant,
+    ticks: u64,
+    /// added to the clock so writes to mtime stick
+    offset: u64,
+    mtime: u64,
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
 }
 
 impl Clint {
-    pub fn new() -> Self {
-        Self {  }
+    pub fn new(harts: usize, clock: ClockSource) -> Self {
+        Self {
+            clock,
+            start: Instant::now(),
+            ticks: 0,
+            offset: 0,
+            mtime: 0,
+            msip: vec![false; harts],
+            mtimecmp: vec![u64::MAX; harts],
+        }
+    }
+
+    fn clock(&self) -> u64 {
+        match self.clock {
+            ClockSource::WallClock => {
+                (self.start.elapsed().as_nanos() * TIMEBASE_FREQUENCY as u128 / 1_000_000_000) as u64
+            }
+            ClockSource::InstructionCount { instructions_per_tick } => {
+                self.ticks / instructions_per_tick.max(1)
+            }
+        }
     }
 
-    pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
-        println!("CLINT READ: addr={addr:X} size={size}");
-        Ok(0)
+    /// clears the software and timer interrupts, mtime keeps counting
+    pub fn reset(&mut self) {
+        self.msip.fill(false);
+        self.mtimecmp.fill(u64::MAX);
     }
 
-    pub fn write(&self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
-        println!("CLINT WRITE: addr={addr:X} value={value:X} size={size}");
+    /// called once per instruction step
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+        if matches!(self.clock, ClockSource::InstructionCount { .. }) || self.ticks.is_multiple_of(WALL_CLOCK_SAMPLE_INTERVAL) {
+            self.mtime = self.clock().wrapping_add(self.offset);
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn timer_pending(&self, hart: usize) -> bool {
+        self.mtime >= self.mtimecmp[hart]
+    }
+
+    pub fn software_pending(&self, hart: usize) -> bool {
+        self.msip[hart]
+    }
+
+    /// what an SBI set_timer call does for a supervisor that can't reach the CLINT itself
+    pub fn set_mtimecmp(&mut self, hart: usize, value: u64) {
+        self.mtimecmp[hart] = value;
+    }
+
+    fn set_mtime(&mut self, value: u64) {
+        self.offset = value.wrapping_sub(self.clock());
+        self.mtime = value;
+    }
+
+    /// finds which hart's register `addr` falls in for a block of `stride` sized registers
+    fn hart_register(&self, addr: u64, base: u64, stride: u64) -> Option<(usize, u64)> {
+        let hart = ((addr - base) / stride) as usize;
+        if hart < self.msip.len() {
+            Some((hart, (addr - base) % stride))
+        } else {
+            None
+        }
+    }
+
+    pub fn read(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
+        // MMIO reads see the exact time rather than the last sample
+        self.mtime = self.clock().wrapping_add(self.offset);
+
+        let (value, offset) = match addr {
+            MSIP_BASE..MTIMECMP_BASE => match self.hart_register(addr, MSIP_BASE, 4) {
+                Some((hart, offset)) => (self.msip[hart] as u64, offset),
+                None => (0, 0),
+            },
+            MTIMECMP_BASE..MTIME => match self.hart_register(addr, MTIMECMP_BASE, 8) {
+                Some((hart, offset)) => (self.mtimecmp[hart], offset),
+                None => (0, 0),
+            },
+            MTIME..=0xbfff => (self.mtime, addr - MTIME),
+            _ => (0, 0),
+        };
+
+        let value = value >> (offset * 8);
+        match size {
+            8 => Ok(value & 0xff),
+            16 => Ok(value & 0xffff),
+            32 => Ok(value & 0xffffffff),
+            64 => Ok(value),
+            _ => Err(Exception::HardwareError),
+        }
+    }
+
+    pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
+        /// replaces the bytes of `old` that the write covers
+        fn merge(old: u64, value: u64, offset: u64, size: u8) -> u64 {
+            let mask = if size == 64 { u64::MAX } else { ((1 << size) - 1) << (offset * 8) };
+            (old & !mask) | ((value << (offset * 8)) & mask)
+        }
+
+        match addr {
+            MSIP_BASE..MTIMECMP_BASE => {
+                if let Some((hart, 0)) = self.hart_register(addr, MSIP_BASE, 4) {
+                    self.msip[hart] = value & 1 != 0;
+                }
+            }
+            MTIMECMP_BASE..MTIME => {
+                if let Some((hart, offset)) = self.hart_register(addr, MTIMECMP_BASE, 8) {
+                    self.mtimecmp[hart] = merge(self.mtimecmp[hart], value, offset, size);
+                }
+            }
+            MTIME..=0xbfff => {
+                let mtime = merge(self.clock().wrapping_add(self.offset), value, addr - MTIME, size);
+                self.set_mtime(mtime);
+            }
+            _ => {}
+        }
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,410 @@
+use std::path::PathBuf;
+
+use crate::{bus::{DRAM_START, ROM_START}, clint::ClockSource, virtio::p9::Security};
+
+pub const USAGE: &str = "\
+usage: riscv-emulator [options] [disk image...] [-- program arguments...]
+
+  --bios=PATH              firmware, an ELF or a raw binary (default riscv-pk/build/bbl.bin)
+  --bios-addr=ADDR         where a raw firmware is loaded and started (default 0x80000000)
+  --reset-vector=ADDR      where the hart starts, the boot ROM that starts the firmware by default
+  --sbi                    no firmware, the kernel starts in S-mode and the emulator answers its SBI calls
+  --kernel=PATH            kernel for the firmware to jump to, a Linux Image where its header says, an ELF
+                           where it's linked and anything else at 0x80200000
+  --initrd=PATH            initial ramdisk
+  --dtb=PATH               device tree blob to use instead of the one generated for the machine,
+                           checked against it and with its memory size and /chosen used
+  --append=ARGS            kernel command line (default root=/dev/vda ro console=ttyS0)
+  -m, --memory=SIZE        memory size, with a K, M or G suffix or in MiB without one (default 1G)
+  --drive=PATH[,read-only][,overlay=PATH][,discard-overlay]
+                           a virtio block device, raw or qcow2, given more than once for more disks
+  --serial=DEST            where the UART goes, stdio (the default), null, file:PATH or unix:PATH
+  --trace                  print every instruction before it runs
+  --max-insns=N            stop after N instructions with exit status 124
+  --tlb-stats              print the TLB hits and misses to stderr on exit
+  --icount=N               advance the timer once every N instructions instead of with the host clock
+  --console-socket=PATH    a virtio console with a control port on a unix socket
+  --rng-seed=N             seed the virtio-rng device so every run gets the same numbers
+  --share=DIR              share a host directory over virtio-9p
+  --share-tag=TAG          what the guest mounts the share by (default hostshare)
+  --share-security=MODE    passthrough (the default) or read-only
+  --net=SPEC               virtio-net, user[,hostfwd=tcp|udp:HOSTPORT-GUESTPORT]...,
+                           socket,listen=PATH or socket,connect=PATH
+  --net-mac=MAC            the guest's MAC address (default 52:54:00:12:34:56)
+  --net-pcap=PATH          record every frame to a pcap file
+  -h, --help               print this
+
+options taking a value can have it after an = or as the next argument";
+
+/// what's booted unless told otherwise, read at startup so it can be swapped without a rebuild
+const DEFAULT_BIOS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/riscv-pk/build/bbl.bin");
+pub const DEFAULT_BOOTARGS: &str = "root=/dev/vda ro console=ttyS0";
+pub const DEFAULT_MEMORY_SIZE: u64 = 1024 * 1024 * 1024;
+/// what the guest mounts a shared directory by unless told otherwise
+const DEFAULT_SHARE_TAG: &str = "hostshare";
+/// a locally administered MAC, the same one QEMU hands out first
+const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// a disk image and how the guest gets to change it
+pub struct Drive {
+    pub path: PathBuf,
+    pub read_only: bool,
+    /// writes go here instead of the image
+    pub overlay: Option<PathBuf>,
+    /// the overlay goes away when the emulator does
+    pub discard_overlay: bool,
+}
+
+/// where the UART's output goes and its input comes from
+pub enum Serial {
+    /// the host's stdin and stderr, stdout is for logging
+    Stdio,
+    Null,
+    /// output only
+    File(PathBuf),
+    /// whoever connects to a unix socket
+    Socket(PathBuf),
+}
+
+pub struct Share {
+    pub dir: PathBuf,
+    pub tag: String,
+    pub security: Security,
+}
+
+pub struct Network {
+    /// what `net::open` takes
+    pub spec: String,
+    pub mac: [u8; 6],
+    pub pcap: Option<PathBuf>,
+}
+
+/// everything about the machine that can be chosen when it's started
+pub struct Config {
+    pub bios: PathBuf,
+    pub bios_addr: u64,
+    pub reset_vector: u64,
+    /// the emulator stands in for the firmware, which needs a kernel
+    pub sbi: bool,
+    pub kernel: Option<PathBuf>,
+    pub initrd: Option<PathBuf>,
+    /// used instead of generating one
+    pub dtb: Option<PathBuf>,
+    // these two are left unset so a device tree's can be used instead
+    pub append: Option<String>,
+    pub memory_size: Option<u64>,
+    pub drives: Vec<Drive>,
+    pub serial: Serial,
+    pub trace: bool,
+    pub max_insns: Option<u64>,
+    pub tlb_stats: bool,
+    pub clock: ClockSource,
+    pub console_socket: Option<PathBuf>,
+    pub rng_seed: Option<u64>,
+    pub share: Option<Share>,
+    pub net: Option<Network>,
+    /// the arguments after --, passed on to a program that asks for them over HTIF
+    pub program_args: Vec<String>,
+}
+
+impl Config {
+    /// `None` if all that was asked for was the usage
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut config = Self {
+            bios: PathBuf::from(DEFAULT_BIOS),
+            bios_addr: DRAM_START,
+            reset_vector: ROM_START,
+            sbi: false,
+            kernel: None,
+            initrd: None,
+            dtb: None,
+            append: None,
+            memory_size: None,
+            drives: Vec::new(),
+            serial: Serial::Stdio,
+            trace: false,
+            max_insns: None,
+            tlb_stats: false,
+            clock: ClockSource::WallClock,
+            console_socket: None,
+            rng_seed: None,
+            share: None,
+            net: None,
+            program_args: Vec::new(),
+        };
+        let mut share_tag = DEFAULT_SHARE_TAG.to_string();
+        let mut share_security = Security::Passthrough;
+        let mut net_mac = DEFAULT_MAC;
+        let mut net_pcap = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                config.program_args = args.collect();
+                break;
+            }
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with('-') => (name.to_string(), Some(value.to_string())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || inline.clone().or_else(|| args.next()).ok_or_else(|| format!("{name} needs a value"));
+
+            match name.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--bios" => config.bios = PathBuf::from(value()?),
+                "--bios-addr" => {
+                    let addr = value()?;
+                    config.bios_addr = parse_addr(&addr).ok_or_else(|| format!("bad address {addr}"))?;
+                }
+                "--reset-vector" => {
+                    let addr = value()?;
+                    config.reset_vector = parse_addr(&addr).ok_or_else(|| format!("bad address {addr}"))?;
+                }
+                "--sbi" => config.sbi = true,
+                "--kernel" => config.kernel = Some(PathBuf::from(value()?)),
+                "--initrd" => config.initrd = Some(PathBuf::from(value()?)),
+                "--dtb" => config.dtb = Some(PathBuf::from(value()?)),
+                "--append" => config.append = Some(value()?),
+                "-m" | "--memory" => {
+                    let size = value()?;
+                    config.memory_size = Some(parse_size(&size).filter(|&size| valid_memory_size(size)).ok_or_else(|| format!("bad memory size {size}"))?);
+                }
+                "--drive" => config.drives.push(parse_drive(&value()?)?),
+                "--serial" => config.serial = parse_serial(&value()?)?,
+                "--trace" => config.trace = true,
+                "--max-insns" => {
+                    let count = value()?;
+                    config.max_insns = Some(count.parse().map_err(|_| format!("bad instruction count {count}"))?);
+                }
+                "--tlb-stats" => config.tlb_stats = true,
+                "--icount" => {
+                    let count = value()?;
+                    let instructions_per_tick = count.parse().ok().filter(|&count| count > 0).ok_or_else(|| format!("bad instruction count {count}"))?;
+                    config.clock = ClockSource::InstructionCount { instructions_per_tick };
+                }
+                "--console-socket" => config.console_socket = Some(PathBuf::from(value()?)),
+                "--rng-seed" => {
+                    let seed = value()?;
+                    config.rng_seed = Some(seed.parse().map_err(|_| format!("bad rng seed {seed}"))?);
+                }
+                "--share" => config.share = Some(Share { dir: PathBuf::from(value()?), tag: String::new(), security: Security::Passthrough }),
+                "--share-tag" => share_tag = value()?,
+                "--share-security" => {
+                    share_security = match value()?.as_str() {
+                        "passthrough" => Security::Passthrough,
+                        "read-only" => Security::ReadOnly,
+                        mode => return Err(format!("unknown share security mode {mode}")),
+                    }
+                }
+                "--net" => config.net = Some(Network { spec: value()?, mac: DEFAULT_MAC, pcap: None }),
+                "--net-mac" => {
+                    let mac = value()?;
+                    net_mac = parse_mac(&mac).ok_or_else(|| format!("bad MAC address {mac}"))?;
+                }
+                "--net-pcap" => net_pcap = Some(PathBuf::from(value()?)),
+                _ if name.starts_with('-') => return Err(format!("unknown option {name}")),
+                // a bare path is a disk
+                _ => config.drives.push(parse_drive(&arg)?),
+            }
+        }
+
+        if config.sbi && config.kernel.is_none() {
+            return Err("--sbi needs a --kernel".to_string());
+        }
+
+        // these can come before or after what they apply to
+        if let Some(share) = &mut config.share {
+            share.tag = share_tag;
+            share.security = share_security;
+        }
+        if let Some(net) = &mut config.net {
+            net.mac = net_mac;
+            net.pcap = net_pcap;
+        }
+        Ok(Some(config))
+    }
+}
+
+/// whole pages that fit in the address space above DRAM_START
+pub fn valid_memory_size(size: u64) -> bool {
+    size > 0 && size.is_multiple_of(4096) && DRAM_START.checked_add(size).is_some()
+}
+
+/// hex with a 0x in front or decimal
+fn parse_addr(addr: &str) -> Option<u64> {
+    match addr.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => addr.parse().ok(),
+    }
+}
+
+/// bytes with a K, M or G suffix, or MiB without one like QEMU's -m
+fn parse_size(size: &str) -> Option<u64> {
+    let (number, unit) = match size.chars().last()? {
+        'K' | 'k' => (&size[..size.len() - 1], 1 << 10),
+        'M' | 'm' => (&size[..size.len() - 1], 1 << 20),
+        'G' | 'g' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1 << 20),
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let bytes: Vec<u8> = mac.split(':').map(|byte| u8::from_str_radix(byte, 16).ok()).collect::<Option<_>>()?;
+    bytes.try_into().ok()
+}
+
+/// `PATH[,read-only][,overlay=PATH][,discard-overlay]`
+fn parse_drive(spec: &str) -> Result<Drive, String> {
+    let mut parts = spec.split(',');
+    let path = parts.next().filter(|path| !path.is_empty()).ok_or_else(|| format!("bad drive {spec}"))?;
+    let mut drive = Drive { path: PathBuf::from(path), read_only: false, overlay: None, discard_overlay: false };
+    for part in parts {
+        match part.split_once('=') {
+            None if part == "read-only" => drive.read_only = true,
+            None if part == "discard-overlay" => drive.discard_overlay = true,
+            Some(("overlay", path)) => drive.overlay = Some(PathBuf::from(path)),
+            _ => return Err(format!("unknown drive option {part}")),
+        }
+    }
+    Ok(drive)
+}
+
+fn parse_serial(serial: &str) -> Result<Serial, String> {
+    match serial.split_once(':') {
+        None if serial == "stdio" => Ok(Serial::Stdio),
+        None if serial == "null" => Ok(Serial::Null),
+        Some(("file", path)) => Ok(Serial::File(PathBuf::from(path))),
+        Some(("unix", path)) => Ok(Serial::Socket(PathBuf::from(path))),
+        _ => Err(format!("unknown serial destination {serial}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Config>, String> {
+        Config::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn config(args: &[&str]) -> Config {
+        match parse(args) {
+            Ok(Some(config)) => config,
+            Ok(None) => panic!("{args:?} only asked for the usage"),
+            Err(e) => panic!("{args:?}: {e}"),
+        }
+    }
+
+    #[test]
+    fn values_after_an_equals_or_as_the_next_argument() {
+        assert_eq!(config(&["--bios=a.bin"]).bios, PathBuf::from("a.bin"));
+        assert_eq!(config(&["--bios", "a.bin"]).bios, PathBuf::from("a.bin"));
+        // only the first = splits
+        assert_eq!(config(&["--append=root=/dev/vda"]).append.as_deref(), Some("root=/dev/vda"));
+        assert_eq!(config(&["-m", "512M"]).memory_size, Some(512 << 20));
+        assert!(parse(&["--kernel"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+        assert!(matches!(parse(&["--trace", "-h"]), Ok(None)));
+    }
+
+    #[test]
+    fn defaults() {
+        let config = config(&[]);
+        assert_eq!((config.bios_addr, config.reset_vector), (DRAM_START, ROM_START));
+        assert!(config.memory_size.is_none() && config.append.is_none() && config.drives.is_empty());
+        assert!(matches!(config.serial, Serial::Stdio));
+    }
+
+    #[test]
+    fn program_arguments_after_double_dash() {
+        let config = config(&["--trace", "--tlb-stats", "--", "hello", "--trace", "-m"]);
+        assert!(config.trace && config.tlb_stats);
+        assert_eq!(config.program_args, ["hello", "--trace", "-m"]);
+        assert!(config.max_insns.is_none());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("64K"), Some(64 << 10));
+        assert_eq!(parse_size("64k"), Some(64 << 10));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size("2G"), Some(2 << 30));
+        // MiB without a suffix, like QEMU
+        assert_eq!(parse_size("128"), Some(128 << 20));
+        assert_eq!(parse_size("99999999999G"), None);
+        for bad in ["", "G", "1.5G", "-1M", "1T"] {
+            assert_eq!(parse_size(bad), None, "{bad}");
+        }
+        // memory has to be whole pages that fit above DRAM_START
+        assert!(parse(&["-m", "1K"]).is_err());
+        assert!(parse(&["-m", "17179869183G"]).is_err());
+        assert!(parse(&["-m", "0"]).is_err());
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(parse_addr("0x80000000"), Some(0x80000000));
+        assert_eq!(parse_addr("4096"), Some(4096));
+        assert_eq!(parse_addr("0x"), None);
+        assert_eq!(parse_addr("0x1_0000"), None);
+        assert_eq!(parse_addr("0x10000000000000000"), None);
+        assert_eq!(config(&["--reset-vector=0x80000000"]).reset_vector, 0x80000000);
+        assert!(parse(&["--bios-addr=80000000h"]).is_err());
+    }
+
+    #[test]
+    fn drives() {
+        let drive = parse_drive("a.qcow2,read-only,overlay=b.img,discard-overlay").unwrap();
+        assert_eq!((drive.path, drive.overlay), (PathBuf::from("a.qcow2"), Some(PathBuf::from("b.img"))));
+        assert!(drive.read_only && drive.discard_overlay);
+
+        let drive = parse_drive("a.img").unwrap();
+        assert!(!drive.read_only && !drive.discard_overlay && drive.overlay.is_none());
+        for bad in ["", ",read-only", "a.img,bogus", "a.img,read-only=yes"] {
+            assert!(parse_drive(bad).is_err(), "{bad}");
+        }
+
+        // bare paths are drives too, in order with --drive
+        let config = config(&["a.img", "--drive=b.img,read-only"]);
+        let paths: Vec<_> = config.drives.iter().map(|drive| drive.path.to_str().unwrap()).collect();
+        assert_eq!(paths, ["a.img", "b.img"]);
+    }
+
+    #[test]
+    fn serial() {
+        assert!(matches!(parse_serial("stdio"), Ok(Serial::Stdio)));
+        assert!(matches!(parse_serial("null"), Ok(Serial::Null)));
+        assert!(matches!(parse_serial("file:out.log"), Ok(Serial::File(path)) if path == Path::new("out.log")));
+        assert!(matches!(parse_serial("unix:/tmp/s"), Ok(Serial::Socket(path)) if path == Path::new("/tmp/s")));
+        for bad in ["", "file", "tcp:1234", "stdio:x"] {
+            assert!(parse_serial(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn options_apply_in_either_order() {
+        for args in [
+            &["--share-tag=host", "--share-security=read-only", "--share=/tmp"][..],
+            &["--share=/tmp", "--share-tag=host", "--share-security=read-only"][..],
+        ] {
+            let share = config(args).share.unwrap();
+            assert_eq!((share.dir, share.tag, share.security), (PathBuf::from("/tmp"), "host".to_string(), Security::ReadOnly));
+        }
+        for args in [&["--net-mac=02:00:00:00:00:01", "--net=user"][..], &["--net=user", "--net-mac=02:00:00:00:00:01"][..]] {
+            assert_eq!(config(args).net.unwrap().mac, [2, 0, 0, 0, 0, 1]);
+        }
+        // without the option they modify they do nothing
+        assert!(config(&["--share-tag=host"]).share.is_none());
+        assert_eq!(config(&["--share=/tmp"]).share.unwrap().tag, DEFAULT_SHARE_TAG);
+        assert!(parse(&["--net-mac=02:00:00:00:00"]).is_err());
+    }
+
+    #[test]
+    fn sbi_needs_a_kernel() {
+        assert!(parse(&["--sbi"]).is_err());
+        assert!(config(&["--kernel=Image", "--sbi"]).sbi);
+    }
+}
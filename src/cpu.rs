@@ -1,4 +1,17 @@
-use crate::{bus::{Bus, DRAM_END, DTB_START}, exception::Exception};
+use std::collections::BTreeMap;
+
+use crate::{bus::Bus, exception::Exception, float::{self, F32, F64, Format, RoundingMode}, interrupt::Interrupt, plic};
+
+use mmu::Access;
+use tlb::Tlb;
+
+mod mmu;
+mod sbi;
+#[cfg(test)]
+mod tests;
+mod tlb;
+
+pub use sbi::Sbi;
 
 struct Xregs {
     xregs: [u64;32]
@@ -64,13 +77,141 @@ impl Xregs {
     }
 }
 
+struct Fregs {
+    fregs: [u64;32]
+}
+
+impl Fregs {
+    fn new() -> Self {
+        Self { fregs: [0;32] }
+    }
+
+    /// single precision values that aren't properly NaN-boxed read as the canonical NaN
+    fn read(&self, fmt: Format, index: u64) -> u64 {
+        let value = self.fregs[index as usize];
+        if fmt == F64 {
+            value
+        } else if value >> 32 == 0xffffffff {
+            value & 0xffffffff
+        } else {
+            F32.canonical_nan()
+        }
+    }
+
+    /// single precision values are NaN-boxed by setting the upper 32 bits
+    fn write(&mut self, fmt: Format, index: u64, value: u64) {
+        self.fregs[index as usize] = if fmt == F64 {
+            value
+        } else {
+            (value & 0xffffffff) | 0xffffffff00000000
+        };
+    }
+}
+
 mod csr {
+    pub const FFLAGS: u64 = 0x001;
+    pub const FRM: u64 = 0x002;
+    pub const FCSR: u64 = 0x003;
+
+    pub const SSTATUS: u64 = 0x100;
+    pub const SIE: u64 = 0x104;
+    pub const STVEC: u64 = 0x105;
+    pub const SCOUNTEREN: u64 = 0x106;
+
+    pub const SSCRATCH: u64 = 0x140;
+    pub const SEPC: u64 = 0x141;
+    pub const SCAUSE: u64 = 0x142;
+    pub const STVAL: u64 = 0x143;
+    pub const SIP: u64 = 0x144;
+
+    pub const SATP: u64 = 0x180;
+
+    pub const MSTATUS: u64 = 0x300;
     pub const MISA: u64 = 0x301;
+    pub const MEDELEG: u64 = 0x302;
+    pub const MIDELEG: u64 = 0x303;
+    pub const MIE: u64 = 0x304;
     pub const MTVEC: u64 = 0x305;
+    pub const MCOUNTEREN: u64 = 0x306;
 
+    pub const MSCRATCH: u64 = 0x340;
     pub const MEPC: u64 = 0x341;
     pub const MCAUSE: u64 = 0x342;
     pub const MTVAL: u64 = 0x343;
+    pub const MIP: u64 = 0x344;
+
+    pub const PMPCFG0: u64 = 0x3a0;
+    pub const PMPCFG2: u64 = 0x3a2;
+    pub const PMPADDR0: u64 = 0x3b0;
+    pub const PMPADDR15: u64 = 0x3bf;
+
+    pub const CYCLE: u64 = 0xc00;
+    pub const TIME: u64 = 0xc01;
+    pub const INSTRET: u64 = 0xc02;
+    pub const MVENDORID: u64 = 0xf11;
+    pub const MARCHID: u64 = 0xf12;
+    pub const MIMPID: u64 = 0xf13;
+    pub const MHARTID: u64 = 0xf14;
+    pub const MCONFIGPTR: u64 = 0xf15;
+
+    /// whether the hart has a CSR, using any other is an illegal instruction
+    pub fn exists(index: u64) -> bool {
+        matches!(index,
+            FFLAGS | FRM | FCSR |
+            SSTATUS | SIE | STVEC | SCOUNTEREN | SSCRATCH | SEPC | SCAUSE | STVAL | SIP | SATP |
+            MSTATUS | MISA | MEDELEG | MIDELEG | MIE | MTVEC | MCOUNTEREN | MSCRATCH | MEPC | MCAUSE | MTVAL | MIP |
+            // 16 PMP entries, which keep what's written but don't check anything
+            PMPCFG0 | PMPCFG2 | PMPADDR0..=PMPADDR15 |
+            CYCLE | TIME | INSTRET | MVENDORID | MARCHID | MIMPID | MHARTID | MCONFIGPTR
+        )
+    }
+
+    /// the lowest privilege level that can get at a CSR, from bits 9:8 of its number
+    pub fn privilege(index: u64) -> u64 {
+        (index >> 8) & 0b11
+    }
+
+    /// CSRs numbered with 0b11 in bits 11:10 can only be read
+    pub fn is_read_only(index: u64) -> bool {
+        index >> 10 == 0b11
+    }
+}
+
+mod mip {
+    pub const SSIP: u64 = 1 << 1;
+    pub const STIP: u64 = 1 << 5;
+    pub const SEIP: u64 = 1 << 9;
+
+    /// the supervisor interrupts, everything else is M-mode only
+    pub const SUPERVISOR: u64 = SSIP | STIP | SEIP;
+    /// every interrupt this hart implements
+    pub const ALL: u64 = 0xaaa;
+}
+
+mod mstatus {
+    pub const SIE: u64 = 1 << 1;
+    pub const MIE: u64 = 1 << 3;
+    pub const SPIE: u64 = 1 << 5;
+    pub const MPIE: u64 = 1 << 7;
+    pub const SPP_SHIFT: u64 = 8;
+    pub const SPP: u64 = 1 << SPP_SHIFT;
+    pub const MPP_SHIFT: u64 = 11;
+    pub const MPP: u64 = 0b11 << MPP_SHIFT;
+    pub const FS: u64 = 0b11 << 13;
+    pub const FS_DIRTY: u64 = 0b11 << 13;
+    pub const MPRV: u64 = 1 << 17;
+    pub const SUM: u64 = 1 << 18;
+    pub const MXR: u64 = 1 << 19;
+    pub const TVM: u64 = 1 << 20;
+    pub const TW: u64 = 1 << 21;
+    pub const TSR: u64 = 1 << 22;
+    /// UXL and SXL are hardwired to 64 bits
+    pub const UXL_SXL_64: u64 = (0b10 << 32) | (0b10 << 34);
+    pub const SD: u64 = 1 << 63;
+
+    pub const WRITABLE: u64 = SIE | MIE | SPIE | MPIE | SPP | MPP | FS | MPRV | SUM | MXR | TVM | TW | TSR;
+    /// the subset of mstatus visible through sstatus
+    pub const SSTATUS: u64 = SIE | SPIE | SPP | FS | SUM | MXR | (0b11 << 32) | SD;
 }
 
 struct Csrs {
@@ -84,6 +225,25 @@ impl Csrs {
 
     fn get_name(index: u64) -> String {
         match index {
+            0x001 => "fflags",
+            0x002 => "frm",
+            0x003 => "fcsr",
+
+            0x100 => "sstatus",
+            0x104 => "sie",
+            0x105 => "stvec",
+            0x106 => "scounteren",
+            0x140 => "sscratch",
+            0x141 => "sepc",
+            0x142 => "scause",
+            0x143 => "stval",
+            0x144 => "sip",
+            0x180 => "satp",
+
+            0xc00 => "cycle",
+            0xc01 => "time",
+            0xc02 => "instret",
+
             0xf11 => "mvendorid",
             0xf12 => "marchid",
             0xf13 => "mimpid",
@@ -117,49 +277,173 @@ impl Csrs {
 
     fn read(&self, index: u64) -> u64 {
         // println!("CSR READ:  {} {:b}", Self::get_name(index), self.csrs[index as usize]);
-        self.csrs[index as usize]
+        match index {
+            // fflags and frm are views into fcsr
+            csr::FFLAGS => self.csrs[csr::FCSR as usize] & 0x1f,
+            csr::FRM => (self.csrs[csr::FCSR as usize] >> 5) & 0b111,
+            csr::SSTATUS => self.csrs[csr::MSTATUS as usize] & mstatus::SSTATUS,
+            // sip and sie only show the interrupts delegated to S-mode
+            csr::SIP => self.csrs[csr::MIP as usize] & self.csrs[csr::MIDELEG as usize],
+            csr::SIE => self.csrs[csr::MIE as usize] & self.csrs[csr::MIDELEG as usize],
+            _ => self.csrs[index as usize]
+        }
     }
 
     fn write(&mut self, index: u64, value: u64) {
         // println!("CSR WRITE: {} {value:b}", Self::get_name(index));
-        self.csrs[index as usize] = value;
+        match index {
+            csr::FFLAGS => {
+                let fcsr = self.csrs[csr::FCSR as usize];
+                self.csrs[csr::FCSR as usize] = (fcsr & !0x1f) | (value & 0x1f);
+            }
+            csr::FRM => {
+                let fcsr = self.csrs[csr::FCSR as usize];
+                self.csrs[csr::FCSR as usize] = (fcsr & !0xe0) | ((value & 0b111) << 5);
+            }
+            csr::FCSR => self.csrs[index as usize] = value & 0xff,
+            csr::MSTATUS => {
+                let prev = self.csrs[index as usize];
+                let mut value = (prev & !mstatus::WRITABLE) | (value & mstatus::WRITABLE);
+                // 0b10 is a reserved privilege so MPP keeps it's old value
+                if (value & mstatus::MPP) >> mstatus::MPP_SHIFT == 0b10 {
+                    value = (value & !mstatus::MPP) | (prev & mstatus::MPP);
+                }
+                // SD summarises whether any extension state is dirty
+                let sd = if value & mstatus::FS == mstatus::FS_DIRTY { mstatus::SD } else { 0 };
+                self.csrs[index as usize] = (value & !mstatus::SD) | mstatus::UXL_SXL_64 | sd;
+            }
+            csr::SSTATUS => {
+                let mask = mstatus::SSTATUS & mstatus::WRITABLE;
+                let status = self.csrs[csr::MSTATUS as usize];
+                self.write(csr::MSTATUS, (status & !mask) | (value & mask));
+            }
+            // ECALL from M-mode can never be delegated
+            csr::MEDELEG => self.csrs[index as usize] = value & 0xb3ff,
+            // only the supervisor software, timer and external interrupts can be delegated
+            csr::MIDELEG => self.csrs[index as usize] = value & mip::SUPERVISOR,
+            csr::MIE => self.csrs[index as usize] = value & mip::ALL,
+            // the machine level bits of mip are driven by devices, see `Cpu::set_interrupt_pending`
+            csr::MIP => self.write_masked(index, value, mip::SUPERVISOR),
+            csr::SIP => self.write_masked(csr::MIP, value, mip::SSIP & self.csrs[csr::MIDELEG as usize]),
+            csr::SIE => self.write_masked(csr::MIE, value, mip::SUPERVISOR & self.csrs[csr::MIDELEG as usize]),
+            // only the direct and vectored modes exist
+            csr::MTVEC | csr::STVEC => self.csrs[index as usize] = value & !0b10,
+            // IALIGN is 16 so only bit 0 of the exception PCs is fixed at zero
+            csr::MEPC | csr::SEPC => self.csrs[index as usize] = value & !1,
+            csr::SATP => {
+                if mmu::is_supported_satp(value) {
+                    self.csrs[index as usize] = value;
+                }
+            }
+            _ => self.csrs[index as usize] = value
+        }
+    }
+
+    /// only changes the bits of a CSR that are set in `mask`
+    fn write_masked(&mut self, index: u64, value: u64, mask: u64) {
+        let prev = self.csrs[index as usize];
+        self.csrs[index as usize] = (prev & !mask) | (value & mask);
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 enum Mode {
     User,
     Supervisor,
     Machine,
 }
 
+impl Mode {
+    /// decodes the 2 bit privilege encoding used by mstatus.MPP, 0b10 is reserved
+    fn from_bits(bits: u64) -> Self {
+        match bits & 0b11 {
+            0b00 => Mode::User,
+            0b01 => Mode::Supervisor,
+            _ => Mode::Machine,
+        }
+    }
+
+    fn to_bits(self) -> u64 {
+        match self {
+            Mode::User => 0b00,
+            Mode::Supervisor => 0b01,
+            Mode::Machine => 0b11,
+        }
+    }
+}
+
 pub struct Cpu {
     pub bus: Bus,
     xregs: Xregs,
+    fregs: Fregs,
     pc: u64,
     csrs: Csrs,
+    itlb: Tlb,
+    dtlb: Tlb,
     mode: Mode,
     wfi: bool,
-    debugging: bool,
+    /// where the hart starts after a reset
+    reset_vector: u64,
+    /// when there's no firmware the hart starts in the kernel and this answers its SBI calls
+    sbi: Option<Sbi>,
+    /// the physical address an LR reserved, which a following SC needs
+    reservation: Option<u64>,
+    /// when set every instruction is printed before it runs, next to the
+    /// closest symbol at or below it
+    pub trace: Option<BTreeMap<u64, String>>,
 }
 
 impl Cpu {
-    pub fn new() -> Self {
-        let mut xregs = Xregs::new();
-        xregs.write(2, DRAM_END);
-        xregs.write(11, DTB_START);
-
-        let mut csrs = Csrs::new();
-        csrs.write(csr::MISA, 0x8000000000001000);
-
-        Self {
-            bus: Bus::new(),
-            xregs,
+    pub fn new(bus: Bus, reset_vector: u64) -> Self {
+        let mut cpu = Self {
+            bus,
+            xregs: Xregs::new(),
+            fregs: Fregs::new(),
             pc: 0,
-            csrs,
+            csrs: Csrs::new(),
+            itlb: Tlb::new(),
+            dtlb: Tlb::new(),
             mode: Mode::Machine,
             wfi: false,
-            debugging: false,
-        }
+            reset_vector,
+            sbi: None,
+            reservation: None,
+            trace: None,
+        };
+        cpu.reset_hart();
+        cpu
+    }
+
+    /// the hart's power on state
+    fn reset_hart(&mut self) {
+        self.csrs = Csrs::new();
+        // RV64 with the A, C, D, F, I, M, S and U extensions
+        self.csrs.write(csr::MISA, 0x800000000014112d);
+        self.csrs.write(csr::MSTATUS, 0);
+
+        self.xregs = Xregs::new();
+        self.fregs = Fregs::new();
+
+        self.pc = self.reset_vector;
+        self.itlb = Tlb::new();
+        self.dtlb = Tlb::new();
+        self.mode = Mode::Machine;
+        self.wfi = false;
+        self.reservation = None;
+        self.enter_kernel();
+    }
+
+    /// stands in for firmware from now on, the hart is reset straight into the kernel
+    pub fn set_sbi(&mut self, sbi: Sbi) {
+        self.sbi = Some(sbi);
+        self.reset_hart();
+    }
+
+    /// resets the hart and every device, memory keeps its contents so the
+    /// firmware has to be loaded again
+    pub fn reset(&mut self) {
+        self.reset_hart();
+        self.bus.reset();
     }
 
     pub fn set_pc(&mut self, pc: u64) {
@@ -167,41 +451,463 @@ impl Cpu {
         self.pc = pc;
     }
 
-    fn fetch(&mut self, size: u8) -> Result<u64, Exception> {
-        self.bus.read(self.pc, size)
+    /// fetches the 16 bit instruction parcel at `addr`
+    fn fetch(&mut self, addr: u64) -> Result<u64, Exception> {
+        let paddr = self.translate(addr, Access::Instruction)?;
+        self.bus.read(paddr, 16).map_err(|_| Exception::InstructionAccessFault(addr))
+    }
+
+    // access faults report the virtual address rather than the physical one the bus saw
+
+    fn load(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
+        let paddr = self.translate(addr, Access::Load)?;
+        self.bus.read(paddr, size).map_err(|_| Exception::LoadAccessFault(addr))
+    }
+
+    fn store(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
+        let paddr = self.translate(addr, Access::Store)?;
+        self.bus.write(paddr, value, size).map_err(|_| Exception::StoreAccessFault(addr))
     }
 
     pub fn execute(&mut self) -> Result<(), Exception> {
-        let inst = self.fetch(16)?;
+        // a hart parked by WFI wakes up once any enabled interrupt is pending,
+        // even if it's globally disabled and won't be taken
+        if self.wfi {
+            if self.csrs.read(csr::MIP) & self.csrs.read(csr::MIE) == 0 {
+                return Ok(());
+            }
+            self.wfi = false;
+        }
+
+        let inst = self.fetch(self.pc)?;
+        if inst == 0 {
+            return Err(Exception::IllegalInstruction(inst))
+        }
         match inst & 0b11 {
-            0b10 | 0b01 => {
+            0b00..=0b10 => {
+                self.print_trace(inst);
                 self.execute_compressed(inst)?;
                 self.set_pc(self.pc.wrapping_add(2));
             },
-            0b11 => {
-                let inst = self.fetch(32)?;
+            _ => {
+                // the second parcel is fetched separately as it may be on another page
+                let inst = inst | self.fetch(self.pc.wrapping_add(2))? << 16;
+                self.print_trace(inst);
                 self.execute_uncompressed(inst)?;
                 self.set_pc(self.pc.wrapping_add(4));
             }
-            _ => return Err(Exception::IllegalInstruction("zero op code".to_owned()))
         }
 
         Ok(())
     }
 
+    /// the riscv,isa string for the device tree, from the extensions misa has
+    pub fn isa(&self) -> String {
+        let misa = self.csrs.read(csr::MISA);
+        let mut isa = "rv64".to_string();
+        for extension in "imafdc".bytes() {
+            if misa & 1 << (extension - b'a') != 0 {
+                isa.push(extension as char);
+            }
+        }
+        // csrs and fence.i are always there
+        isa + "_zicsr_zifencei"
+    }
+
+    /// the pc, privilege mode and integer registers, for seeing where a run got to
+    pub fn print_state(&self) {
+        println!("pc=0x{:X} mode={:?}", self.pc, self.mode);
+        self.xregs.print_all();
+    }
+
+    fn print_trace(&self, inst: u64) {
+        let Some(symbols) = &self.trace else { return };
+        // csr instructions get the name of the csr they're at
+        let csr = match inst & 0x7f == 0b1110011 && (inst >> 12) & 0b111 != 0 {
+            true => format!(" {}", Csrs::get_name(inst >> 20)),
+            false => String::new(),
+        };
+        let inst = if inst & 0b11 == 0b11 { format!("{inst:08x}") } else { format!("{inst:04x}    ") };
+        match symbols.range(..=self.pc).next_back() {
+            Some((addr, name)) => println!("{:?} {:016x}: {inst} <{name}+{:#x}>{csr}", self.mode, self.pc, self.pc - addr),
+            None => println!("{:?} {:016x}: {inst}{csr}", self.mode, self.pc),
+        }
+    }
+
+    /// advances the devices by one step and samples their interrupt lines
+    pub fn tick(&mut self) {
+        let hart = self.csrs.read(csr::MHARTID) as usize;
+
+        self.bus.tick();
+        self.set_interrupt_pending(Interrupt::MachineTimer, self.bus.clint.timer_pending(hart));
+        if self.sbi.is_some() {
+            // firmware would pass its timer interrupt on to the supervisor
+            self.set_interrupt_pending(Interrupt::SupervisorTimer, self.bus.clint.timer_pending(hart));
+        }
+        self.set_interrupt_pending(Interrupt::MachineSoftware, self.bus.clint.software_pending(hart));
+        self.set_interrupt_pending(Interrupt::MachineExternal, self.bus.plic.interrupting(plic::machine_context(hart)));
+        self.set_interrupt_pending(Interrupt::SupervisorExternal, self.bus.plic.interrupting(plic::supervisor_context(hart)));
+    }
+
+    /// drives an interrupt line from a device, the machine level bits of mip
+    /// can only be changed this way
+    pub fn set_interrupt_pending(&mut self, interrupt: Interrupt, pending: bool) {
+        let mip = self.csrs.csrs[csr::MIP as usize];
+        self.csrs.csrs[csr::MIP as usize] = if pending {
+            mip | interrupt.mask()
+        } else {
+            mip & !interrupt.mask()
+        };
+    }
+
+    /// the highest priority interrupt that is both pending and globally enabled
+    /// for the privilege level it would be taken in
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.csrs.read(csr::MIP) & self.csrs.read(csr::MIE);
+        if pending == 0 {
+            return None;
+        }
+
+        let status = self.csrs.read(csr::MSTATUS);
+        let mideleg = self.csrs.read(csr::MIDELEG);
+        let m_enabled = self.mode != Mode::Machine || status & mstatus::MIE != 0;
+        let s_enabled = self.mode == Mode::User || (self.mode == Mode::Supervisor && status & mstatus::SIE != 0);
+
+        Interrupt::PRIORITY.into_iter().find(|interrupt| {
+            let mask = interrupt.mask();
+            if pending & mask == 0 {
+                false
+            } else if mideleg & mask != 0 {
+                s_enabled
+            } else {
+                m_enabled
+            }
+        })
+    }
+
+    pub fn handle_interrupt(&mut self, interrupt: Interrupt) {
+        // too frequent to print unless every instruction is being printed as well
+        if self.trace.is_some() {
+            println!("--- INTERRUPT --- {interrupt:?}");
+        }
+        self.wfi = false;
+        self.take_trap(interrupt.to_code(), true, 0);
+    }
+
     pub fn handle_trap(&mut self, exception: Exception) {
-        println!("--- TRAP --- {exception:?}");
-        self.csrs.write(csr::MCAUSE, exception.to_code());
-        self.csrs.write(csr::MTVAL, 0);
-        self.csrs.write(csr::MEPC, self.pc);
+        if matches!(exception, Exception::ECallFromS) && self.sbi.is_some() {
+            self.sbi_call();
+            return;
+        }
+        // pk's syscalls are all ECALLs, so this would land in the middle of its output
+        if self.trace.is_some() {
+            println!("--- TRAP --- {exception:?}");
+        }
+        self.take_trap(exception.to_code(), false, exception.tval());
+    }
+
+    /// enters the trap handler in M-mode, or in S-mode when the cause is delegated
+    /// through medeleg/mideleg and the hart isn't already in M-mode
+    fn take_trap(&mut self, cause: u64, interrupt: bool, tval: u64) {
+        // whatever was interrupted has to start its LR/SC sequence over
+        self.reservation = None;
+        let deleg = self.csrs.read(if interrupt { csr::MIDELEG } else { csr::MEDELEG });
+        let delegated = self.mode != Mode::Machine && (deleg >> cause) & 1 != 0;
+        let cause = if interrupt { cause | 1 << 63 } else { cause };
+        let status = self.csrs.read(csr::MSTATUS);
+
+        if delegated {
+            self.csrs.write(csr::SCAUSE, cause);
+            self.csrs.write(csr::STVAL, tval);
+            self.csrs.write(csr::SEPC, self.pc);
+
+            // push the interrupt enable and privilege onto the sstatus stack
+            let spie = if status & mstatus::SIE != 0 { mstatus::SPIE } else { 0 };
+            let spp = self.mode.to_bits() << mstatus::SPP_SHIFT;
+            self.csrs.write(csr::MSTATUS, (status & !(mstatus::SIE | mstatus::SPIE | mstatus::SPP)) | spie | spp);
+            self.mode = Mode::Supervisor;
+
+            self.set_pc(Self::trap_vector(self.csrs.read(csr::STVEC), cause));
+        } else {
+            self.csrs.write(csr::MCAUSE, cause);
+            self.csrs.write(csr::MTVAL, tval);
+            self.csrs.write(csr::MEPC, self.pc);
+
+            // push the interrupt enable and privilege onto the mstatus stack
+            let mpie = if status & mstatus::MIE != 0 { mstatus::MPIE } else { 0 };
+            let mpp = self.mode.to_bits() << mstatus::MPP_SHIFT;
+            self.csrs.write(csr::MSTATUS, (status & !(mstatus::MIE | mstatus::MPIE | mstatus::MPP)) | mpie | mpp);
+            self.mode = Mode::Machine;
+
+            self.set_pc(Self::trap_vector(self.csrs.read(csr::MTVEC), cause));
+        }
+    }
 
-        self.set_pc(self.csrs.read(csr::MTVEC));
+    /// in vectored mode interrupts jump to BASE + 4 * cause, exceptions always go to BASE
+    fn trap_vector(tvec: u64, cause: u64) -> u64 {
+        let base = tvec & !0b11;
+        let interrupt = cause >> 63 == 1;
+        if tvec & 0b11 == 1 && interrupt {
+            base.wrapping_add(4 * (cause & !(1 << 63)))
+        } else {
+            base
+        }
+    }
+
+    /// floating point instructions are illegal while mstatus.FS is off
+    fn require_fp(&self, inst: u64) -> Result<(), Exception> {
+        if self.csrs.read(csr::MSTATUS) & mstatus::FS == 0 {
+            return Err(Exception::IllegalInstruction(inst))
+        }
+        Ok(())
+    }
+
+    fn mark_fp_dirty(&mut self) {
+        let status = self.csrs.read(csr::MSTATUS);
+        self.csrs.write(csr::MSTATUS, status | mstatus::FS_DIRTY);
+    }
 
-        // pause();
+    /// resolves the rm field of an instruction, 0b111 selects the dynamic mode in frm
+    fn rounding_mode(&self, rm: u64, inst: u64) -> Result<RoundingMode, Exception> {
+        let rm = if rm == 0b111 { self.csrs.read(csr::FRM) } else { rm };
+        RoundingMode::from_bits(rm).ok_or(Exception::IllegalInstruction(inst))
     }
 
-    fn execute_compressed(&mut self, _inst: u64) -> Result<(), Exception> {
-        Err(Exception::IllegalInstruction("Compressed Instruction".to_owned()))
+    fn accrue_fflags(&mut self, fflags: u64) {
+        if fflags != 0 {
+            self.csrs.write(csr::FFLAGS, self.csrs.read(csr::FFLAGS) | fflags);
+        }
+    }
+
+    fn execute_compressed(&mut self, inst: u64) -> Result<(), Exception> {
+        self.xregs.write(0, 0);
+
+        let quadrant = inst & 0b11;
+        let funct3 = (inst >> 13) & 0b111;
+
+        // full register fields
+        let rd  = (inst >> 7) & 0b11111;
+        let rs2 = (inst >> 2) & 0b11111;
+
+        // the 3 bit register fields only address x8-x15
+        let rd_prime  = ((inst >> 2) & 0b111) + 8;
+        let rs1_prime = ((inst >> 7) & 0b111) + 8;
+
+        // sign extended imm[5] | imm[4:0] used by most of quadrant 1
+        let imm6 = ((((inst >> 7) & 0x20) | ((inst >> 2) & 0x1f)) as i64) << 58 >> 58;
+
+        match (quadrant, funct3) {
+            (0b00, 0b000) => { // C.ADDI4SPN
+                let imm = ((inst >> 7) & 0x30) |
+                    ((inst >> 1) & 0x3c0) |
+                    ((inst >> 4) & 0x4) |
+                    ((inst >> 2) & 0x8);
+                if imm == 0 {
+                    return Err(Exception::IllegalInstruction(inst))
+                }
+                self.xregs.write(rd_prime, self.xregs.read(2).wrapping_add(imm));
+            }
+            (0b00, 0b001) => { // C.FLD
+                self.require_fp(inst)?;
+                let imm = ((inst >> 7) & 0x38) | ((inst << 1) & 0xc0);
+                let addr = self.xregs.read(rs1_prime).wrapping_add(imm);
+                let value = self.load(addr, 64)?;
+                self.fregs.write(F64, rd_prime, value);
+                self.mark_fp_dirty();
+            }
+            (0b00, 0b010) => { // C.LW
+                let imm = ((inst >> 7) & 0x38) | ((inst >> 4) & 0x4) | ((inst << 1) & 0x40);
+                let addr = self.xregs.read(rs1_prime).wrapping_add(imm);
+                let value = self.load(addr, 32)? as i32 as i64 as u64;
+                self.xregs.write(rd_prime, value);
+            }
+            (0b00, 0b011) => { // C.LD
+                let imm = ((inst >> 7) & 0x38) | ((inst << 1) & 0xc0);
+                let addr = self.xregs.read(rs1_prime).wrapping_add(imm);
+                let value = self.load(addr, 64)?;
+                self.xregs.write(rd_prime, value);
+            }
+            (0b00, 0b101) => { // C.FSD
+                self.require_fp(inst)?;
+                let imm = ((inst >> 7) & 0x38) | ((inst << 1) & 0xc0);
+                let addr = self.xregs.read(rs1_prime).wrapping_add(imm);
+                self.store(addr, self.fregs.read(F64, rd_prime), 64)?;
+            }
+            (0b00, 0b110) => { // C.SW
+                let imm = ((inst >> 7) & 0x38) | ((inst >> 4) & 0x4) | ((inst << 1) & 0x40);
+                let addr = self.xregs.read(rs1_prime).wrapping_add(imm);
+                self.store(addr, self.xregs.read(rd_prime), 32)?;
+            }
+            (0b00, 0b111) => { // C.SD
+                let imm = ((inst >> 7) & 0x38) | ((inst << 1) & 0xc0);
+                let addr = self.xregs.read(rs1_prime).wrapping_add(imm);
+                self.store(addr, self.xregs.read(rd_prime), 64)?;
+            }
+            (0b01, 0b000) => { // C.ADDI (C.NOP when rd is zero)
+                self.xregs.write(rd, self.xregs.read(rd).wrapping_add(imm6 as u64));
+            }
+            (0b01, 0b001) => { // C.ADDIW
+                if rd == 0 {
+                    return Err(Exception::IllegalInstruction(inst))
+                }
+                self.xregs.write(rd, (self.xregs.read(rd) as i32).wrapping_add(imm6 as i32) as i64 as u64);
+            }
+            (0b01, 0b010) => { // C.LI
+                self.xregs.write(rd, imm6 as u64);
+            }
+            (0b01, 0b011) => {
+                if rd == 2 { // C.ADDI16SP
+                    let imm = ((((inst >> 3) & 0x200) |
+                        ((inst >> 2) & 0x10) |
+                        ((inst << 1) & 0x40) |
+                        ((inst << 4) & 0x180) |
+                        ((inst << 3) & 0x20)) as i64) << 54 >> 54;
+                    if imm == 0 {
+                        return Err(Exception::IllegalInstruction(inst))
+                    }
+                    self.xregs.write(2, self.xregs.read(2).wrapping_add(imm as u64));
+                } else { // C.LUI
+                    if imm6 == 0 {
+                        return Err(Exception::IllegalInstruction(inst))
+                    }
+                    self.xregs.write(rd, (imm6 << 12) as u64);
+                }
+            }
+            (0b01, 0b100) => { // MISC-ALU
+                let rd = rs1_prime;
+                let shamt = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x1f);
+                match (inst >> 10) & 0b11 {
+                    0b00 => { // C.SRLI
+                        self.xregs.write(rd, self.xregs.read(rd) >> shamt);
+                    }
+                    0b01 => { // C.SRAI
+                        self.xregs.write(rd, ((self.xregs.read(rd) as i64) >> shamt) as u64);
+                    }
+                    0b10 => { // C.ANDI
+                        self.xregs.write(rd, self.xregs.read(rd) & imm6 as u64);
+                    }
+                    _ => {
+                        let rs2 = rd_prime;
+                        self.xregs.write(rd, match ((inst >> 12) & 1, (inst >> 5) & 0b11) {
+                            (0, 0b00) => { // C.SUB
+                                self.xregs.read(rd).wrapping_sub(self.xregs.read(rs2))
+                            }
+                            (0, 0b01) => { // C.XOR
+                                self.xregs.read(rd) ^ self.xregs.read(rs2)
+                            }
+                            (0, 0b10) => { // C.OR
+                                self.xregs.read(rd) | self.xregs.read(rs2)
+                            }
+                            (0, 0b11) => { // C.AND
+                                self.xregs.read(rd) & self.xregs.read(rs2)
+                            }
+                            (1, 0b00) => { // C.SUBW
+                                (self.xregs.read(rd) as i32).wrapping_sub(self.xregs.read(rs2) as i32) as i64 as u64
+                            }
+                            (1, 0b01) => { // C.ADDW
+                                (self.xregs.read(rd) as i32).wrapping_add(self.xregs.read(rs2) as i32) as i64 as u64
+                            }
+                            _ => return Err(Exception::IllegalInstruction(inst))
+                        });
+                    }
+                }
+            }
+            (0b01, 0b101) => { // C.J
+                let imm = ((((inst >> 1) & 0x800) |
+                    ((inst >> 7) & 0x10) |
+                    ((inst >> 1) & 0x300) |
+                    ((inst << 2) & 0x400) |
+                    ((inst >> 1) & 0x40) |
+                    ((inst << 1) & 0x80) |
+                    ((inst >> 2) & 0xe) |
+                    ((inst << 3) & 0x20)) as i64) << 52 >> 52;
+
+                self.set_pc(self.pc.wrapping_add(imm as u64).wrapping_sub(2));
+            }
+            (0b01, 0b110) | (0b01, 0b111) => { // C.BEQZ / C.BNEZ
+                let is_zero = self.xregs.read(rs1_prime) == 0;
+                if is_zero == (funct3 == 0b110) {
+                    let imm = ((((inst >> 4) & 0x100) |
+                        ((inst >> 7) & 0x18) |
+                        ((inst << 1) & 0xc0) |
+                        ((inst >> 2) & 0x6) |
+                        ((inst << 3) & 0x20)) as i64) << 55 >> 55;
+
+                    self.set_pc(self.pc.wrapping_add(imm as u64).wrapping_sub(2));
+                }
+            }
+            (0b10, 0b000) => { // C.SLLI
+                let shamt = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x1f);
+                self.xregs.write(rd, self.xregs.read(rd) << shamt);
+            }
+            (0b10, 0b001) => { // C.FLDSP
+                self.require_fp(inst)?;
+                let imm = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x18) | ((inst << 4) & 0x1c0);
+                let addr = self.xregs.read(2).wrapping_add(imm);
+                let value = self.load(addr, 64)?;
+                self.fregs.write(F64, rd, value);
+                self.mark_fp_dirty();
+            }
+            (0b10, 0b010) => { // C.LWSP
+                if rd == 0 {
+                    return Err(Exception::IllegalInstruction(inst))
+                }
+                let imm = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x1c) | ((inst << 4) & 0xc0);
+                let addr = self.xregs.read(2).wrapping_add(imm);
+                let value = self.load(addr, 32)? as i32 as i64 as u64;
+                self.xregs.write(rd, value);
+            }
+            (0b10, 0b011) => { // C.LDSP
+                if rd == 0 {
+                    return Err(Exception::IllegalInstruction(inst))
+                }
+                let imm = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x18) | ((inst << 4) & 0x1c0);
+                let addr = self.xregs.read(2).wrapping_add(imm);
+                let value = self.load(addr, 64)?;
+                self.xregs.write(rd, value);
+            }
+            (0b10, 0b100) => {
+                match ((inst >> 12) & 1, rd, rs2) {
+                    (0, 0, 0) => return Err(Exception::IllegalInstruction(inst)),
+                    (0, _, 0) => { // C.JR
+                        self.set_pc(self.xregs.read(rd).wrapping_sub(2) & !1);
+                    }
+                    (0, _, _) => { // C.MV
+                        self.xregs.write(rd, self.xregs.read(rs2));
+                    }
+                    (1, 0, 0) => { // C.EBREAK
+                        return Err(Exception::Breakpoint);
+                    }
+                    (1, _, 0) => { // C.JALR
+                        let target = self.xregs.read(rd);
+                        self.xregs.write(1, self.pc.wrapping_add(2));
+                        self.set_pc(target.wrapping_sub(2) & !1);
+                    }
+                    _ => { // C.ADD
+                        self.xregs.write(rd, self.xregs.read(rd).wrapping_add(self.xregs.read(rs2)));
+                    }
+                }
+            }
+            (0b10, 0b101) => { // C.FSDSP
+                self.require_fp(inst)?;
+                let imm = ((inst >> 7) & 0x38) | ((inst >> 1) & 0x1c0);
+                let addr = self.xregs.read(2).wrapping_add(imm);
+                self.store(addr, self.fregs.read(F64, rs2), 64)?;
+            }
+            (0b10, 0b110) => { // C.SWSP
+                let imm = ((inst >> 7) & 0x3c) | ((inst >> 1) & 0xc0);
+                let addr = self.xregs.read(2).wrapping_add(imm);
+                self.store(addr, self.xregs.read(rs2), 32)?;
+            }
+            (0b10, 0b111) => { // C.SDSP
+                let imm = ((inst >> 7) & 0x38) | ((inst >> 1) & 0x1c0);
+                let addr = self.xregs.read(2).wrapping_add(imm);
+                self.store(addr, self.xregs.read(rs2), 64)?;
+            }
+            _ => return Err(Exception::IllegalInstruction(inst))
+        }
+
+        Ok(())
     }
 
     fn execute_uncompressed(&mut self, inst: u64) -> Result<(), Exception> {
@@ -215,32 +921,45 @@ impl Cpu {
         let rs1 = (inst >> 15) & 0b11111;
         let rs2 = (inst >> 20) & 0b11111;
 
-        // if [].contains(&self.pc) {
-        //     println!("Breakpoint @ {:X}", self.pc);
-        //     self.debugging = true;
-        // }
-
-        // if self.debugging {
-        //     println!("\npc: {:X} inst: {:08X}", self.pc, inst);
-        //     self.xregs.print_all();
-        //     pause();
-        // }
-
-        // simple debuging
-        if inst == 0x00000013 {
-            println!("NOP encountered");
-            self.xregs.print_all();
-            pause();
-        }
 
         match inst {
             0x30200073 => { // MRET
-                println!("MRET");
-                self.set_pc(self.csrs.read(csr::MEPC)-4);
-                self.mode = Mode::Supervisor; // TODO
+                if self.mode != Mode::Machine {
+                    return Err(Exception::IllegalInstruction(inst))
+                }
+
+                // pop the mstatus stack, MPP is left as the least privileged mode
+                let status = self.csrs.read(csr::MSTATUS);
+                let mode = Mode::from_bits((status & mstatus::MPP) >> mstatus::MPP_SHIFT);
+                let mie = if status & mstatus::MPIE != 0 { mstatus::MIE } else { 0 };
+                let mprv = if mode == Mode::Machine { status & mstatus::MPRV } else { 0 };
+                let status = status & !(mstatus::MIE | mstatus::MPP | mstatus::MPRV);
+                self.csrs.write(csr::MSTATUS, status | mie | mstatus::MPIE | mprv);
+
+                self.mode = mode;
+                self.set_pc(self.csrs.read(csr::MEPC).wrapping_sub(4));
                 // self.xregs.print_all();
                 return Ok(());
             }
+            0x10200073 => { // SRET
+                let status = self.csrs.read(csr::MSTATUS);
+                if self.mode == Mode::User || (self.mode == Mode::Supervisor && status & mstatus::TSR != 0) {
+                    return Err(Exception::IllegalInstruction(inst))
+                }
+
+                // SPP only holds U or S so SRET always clears MPRV
+                let mode = Mode::from_bits((status & mstatus::SPP) >> mstatus::SPP_SHIFT);
+                let sie = if status & mstatus::SPIE != 0 { mstatus::SIE } else { 0 };
+                let status = status & !(mstatus::SIE | mstatus::SPP | mstatus::MPRV);
+                self.csrs.write(csr::MSTATUS, status | sie | mstatus::SPIE);
+
+                self.mode = mode;
+                self.set_pc(self.csrs.read(csr::SEPC).wrapping_sub(4));
+                return Ok(());
+            }
+            0x00100073 => { // EBREAK
+                return Err(Exception::Breakpoint);
+            }
             0x00000073 => { // ECALL
                 match self.mode {
                     Mode::User => {
@@ -294,27 +1013,35 @@ impl Cpu {
                         (self.xregs.read(rs1) as i64).wrapping_mul(self.xregs.read(rs2) as i64) as u64
                     }
                     (0b001, 1) => { // MULH
-                        ((self.xregs.read(rs1) as i128).wrapping_mul(self.xregs.read(rs2) as i128) >> 64) as u64
+                        ((self.xregs.read(rs1) as i64 as i128).wrapping_mul(self.xregs.read(rs2) as i64 as i128) >> 64) as u64
                     }
                     (0b010, 1) => { // MULHSU
-                        return Err(Exception::IllegalInstruction("MULHU".to_owned()))
+                        ((self.xregs.read(rs1) as i64 as i128).wrapping_mul(self.xregs.read(rs2) as i128) >> 64) as u64
                     }
                     (0b011, 1) => { // MULHU
                         ((self.xregs.read(rs1) as u128).wrapping_mul(self.xregs.read(rs2) as u128) >> 64) as u64
                     }
+                    // dividing by zero gives all ones and leaves the dividend as the
+                    // remainder, the most negative number over -1 overflows back to itself
                     (0b100, 1) => { // DIV
-                        (self.xregs.read(rs1) as i64).wrapping_div(self.xregs.read(rs2) as i64) as u64
+                        match self.xregs.read(rs2) as i64 {
+                            0 => u64::MAX,
+                            divisor => (self.xregs.read(rs1) as i64).wrapping_div(divisor) as u64,
+                        }
                     }
                     (0b101, 1) => { // DIVU
-                        self.xregs.read(rs1).wrapping_div(self.xregs.read(rs2))
+                        self.xregs.read(rs1).checked_div(self.xregs.read(rs2)).unwrap_or(u64::MAX)
                     }
                     (0b110, 1) => { // REM
-                        (self.xregs.read(rs1) as i64).wrapping_rem(self.xregs.read(rs2) as i64) as u64
+                        match self.xregs.read(rs2) as i64 {
+                            0 => self.xregs.read(rs1),
+                            divisor => (self.xregs.read(rs1) as i64).wrapping_rem(divisor) as u64,
+                        }
                     }
                     (0b111, 1) => { // REMU
-                        self.xregs.read(rs1).wrapping_rem(self.xregs.read(rs2))
+                        self.xregs.read(rs1).checked_rem(self.xregs.read(rs2)).unwrap_or(self.xregs.read(rs1))
                     }
-                    _ => return Err(Exception::IllegalInstruction("OP".to_owned()))
+                    _ => return Err(Exception::IllegalInstruction(inst))
                 });
             }
             0b0111011 => { // OP-32
@@ -325,31 +1052,38 @@ impl Cpu {
                     (0b000, 0b0100000) => { // SUBW
                         (self.xregs.read(rs1) as i32).wrapping_sub(self.xregs.read(rs2) as i32) as i64 as u64
                     }
+                    // word shifts only use the low 5 bits of the amount
                     (0b001, 0) => { // SLLW
-                        ((self.xregs.read(rs1) as i32) << (self.xregs.read(rs2) & 0b111111)) as i64 as u64
+                        ((self.xregs.read(rs1) as u32) << (self.xregs.read(rs2) & 0b11111)) as u64
                     }
                     (0b101, 0) => { // SRLW
-                        ((self.xregs.read(rs1) as i32) >> (self.xregs.read(rs2) & 0b111111)) as i64 as u64
+                        ((self.xregs.read(rs1) as u32) >> (self.xregs.read(rs2) & 0b11111)) as u64
                     }
                     (0b101, 0b0100000) => { // SRAW
-                        ((self.xregs.read(rs1) as i32) >> (self.xregs.read(rs2) & 0b111111)) as i64 as u64
+                        ((self.xregs.read(rs1) as i32) >> (self.xregs.read(rs2) & 0b11111)) as i64 as u64
                     }
                     (0b000, 1) => { // MULW
                         (self.xregs.read(rs1) as i32).wrapping_mul(self.xregs.read(rs2) as i32) as i64 as u64
                     }
                     (0b100, 1) => { // DIVW
-                        (self.xregs.read(rs1) as i32).wrapping_div(self.xregs.read(rs2) as i32) as i64 as u64
+                        match self.xregs.read(rs2) as i32 {
+                            0 => u64::MAX,
+                            divisor => (self.xregs.read(rs1) as i32).wrapping_div(divisor) as i64 as u64,
+                        }
                     }
                     (0b101, 1) => { // DIVUW
-                        (self.xregs.read(rs1) as u32).wrapping_div(self.xregs.read(rs2) as u32) as u64
+                        (self.xregs.read(rs1) as u32).checked_div(self.xregs.read(rs2) as u32).unwrap_or(u32::MAX) as u64
                     }
-                    (0b110, 1) => { // REM
-                        (self.xregs.read(rs1) as i32).wrapping_rem(self.xregs.read(rs2) as i32) as i64 as u64
+                    (0b110, 1) => { // REMW
+                        match self.xregs.read(rs2) as i32 {
+                            0 => self.xregs.read(rs1),
+                            divisor => (self.xregs.read(rs1) as i32).wrapping_rem(divisor) as i64 as u64,
+                        }
                     }
-                    (0b111, 1) => { // REMU
-                        (self.xregs.read(rs1) as u32).wrapping_rem(self.xregs.read(rs2) as u32) as u64
+                    (0b111, 1) => { // REMUW
+                        (self.xregs.read(rs1) as u32).checked_rem(self.xregs.read(rs2) as u32).unwrap_or(self.xregs.read(rs1) as u32) as u64
                     }
-                    _ => return Err(Exception::IllegalInstruction("OP".to_owned()))
+                    _ => return Err(Exception::IllegalInstruction(inst))
                 } as i32 as i64 as u64);
             }
             0b0010011 => { // OP-IMM
@@ -384,7 +1118,7 @@ impl Cpu {
                     (0b111, _) => { // ANDI
                         self.xregs.read(rs1) & imm
                     }
-                    _ => return Err(Exception::IllegalInstruction("OP-IMM".to_owned()))
+                    _ => return Err(Exception::IllegalInstruction(inst))
                 });
             }
             0b0011011 => { // OP-IMM-32
@@ -394,16 +1128,17 @@ impl Cpu {
                     (0b000, _) => { // ADDIW
                         self.xregs.read(rs1).wrapping_add(imm)
                     }
+                    // the value is cut to 32 bits before it's shifted
                     (0b001, 0) => { // SLLIW
-                        self.xregs.read(rs1) << ((inst >> 20) & 0b11111)
+                        ((self.xregs.read(rs1) as u32) << ((inst >> 20) & 0b11111)) as u64
                     }
                     (0b101, 0) => { // SRLIW
-                        self.xregs.read(rs1) >> ((inst >> 20) & 0b11111)
+                        ((self.xregs.read(rs1) as u32) >> ((inst >> 20) & 0b11111)) as u64
                     }
                     (0b101, 0b0100000) => { // SRAIW
-                        ((self.xregs.read(rs1) as i64) >> ((inst >> 20) & 0b11111)) as u64
+                        ((self.xregs.read(rs1) as i32) >> ((inst >> 20) & 0b11111)) as i64 as u64
                     }
-                    _ => return Err(Exception::IllegalInstruction("OP-IMM-32".to_owned()))
+                    _ => return Err(Exception::IllegalInstruction(inst))
                 } as i32 as i64 as u64);
             }
             0b0110111 => { // LUI
@@ -423,19 +1158,21 @@ impl Cpu {
             }
             0b1100111 => { // JALR
                 let imm = ((inst as i32 as i64) >> 20) as u64;
-                
+                // rd can be rs1, so the target has to be worked out first
+                let target = imm.wrapping_add(self.xregs.read(rs1)) & !1;
+
                 self.xregs.write(rd, self.pc.wrapping_add(4));
-                self.set_pc(imm.wrapping_add(self.xregs.read(rs1)).wrapping_sub(4) & !1);
+                self.set_pc(target.wrapping_sub(4));
             }
             0b1100011 => { // BRANCH
                 if match funct3 {
                     0b000 => {self.xregs.read(rs1) == self.xregs.read(rs2)}
                     0b001 => {self.xregs.read(rs1) != self.xregs.read(rs2)}
-                    0b100 => {self.xregs.read(rs1) <  self.xregs.read(rs2)}
-                    0b101 => {self.xregs.read(rs1) >= self.xregs.read(rs2)}
-                    0b110 => {(self.xregs.read(rs1) as u32) <  (self.xregs.read(rs2) as u32)}
-                    0b111 => {(self.xregs.read(rs1) as u32) >= (self.xregs.read(rs2) as u32)}
-                    _ => return Err(Exception::IllegalInstruction("BRANCH".to_owned()))
+                    0b100 => {(self.xregs.read(rs1) as i64) <  (self.xregs.read(rs2) as i64)}
+                    0b101 => {(self.xregs.read(rs1) as i64) >= (self.xregs.read(rs2) as i64)}
+                    0b110 => {self.xregs.read(rs1) <  self.xregs.read(rs2)}
+                    0b111 => {self.xregs.read(rs1) >= self.xregs.read(rs2)}
+                    _ => return Err(Exception::IllegalInstruction(inst))
                 } {
                     let imm = (((inst & 0x80000000) as i32 as i64 >> 19) as u64) |
                         ((inst & 0x80) << 4) |
@@ -448,56 +1185,141 @@ impl Cpu {
             0b0000011 => { // LOAD
                 let imm = ((inst as i32 as i64) >> 20) as u64;
                 let addr = imm.wrapping_add(self.xregs.read(rs1));
-                self.xregs.write(rd, match funct3 {
+                let value = match funct3 {
                     // LB
-                    0b000 => self.bus.read(addr, 8)? as i8 as i64 as u64,
+                    0b000 => self.load(addr, 8)? as i8 as i64 as u64,
                     // LH
-                    0b001 => self.bus.read(addr, 16)? as i16 as i64 as u64,
+                    0b001 => self.load(addr, 16)? as i16 as i64 as u64,
                     // LW
-                    0b010 => self.bus.read(addr, 32)? as i32 as i64 as u64,
+                    0b010 => self.load(addr, 32)? as i32 as i64 as u64,
                     // LD
-                    0b011 => self.bus.read(addr, 32)?,
+                    0b011 => self.load(addr, 64)?,
                     // LBU
-                    0b100 => self.bus.read(addr, 8)?,
+                    0b100 => self.load(addr, 8)?,
                     // LHU
-                    0b101 => self.bus.read(addr, 16)?,
+                    0b101 => self.load(addr, 16)?,
                     // LWU
-                    0b110 => self.bus.read(addr, 32)?,
-                    _ => return Err(Exception::IllegalInstruction("LOAD".to_owned()))
-                });
+                    0b110 => self.load(addr, 32)?,
+                    _ => return Err(Exception::IllegalInstruction(inst))
+                };
+                self.xregs.write(rd, value);
             }
             0b0100011 => { // STORE
                 let imm = (((inst & 0xfe000000) as i32 as i64 >> 20) as u64) | ((inst >> 7) & 0x1f);
                 let addr = imm.wrapping_add(self.xregs.read(rs1));
                 match funct3 {
                     // SB
-                    0b000 => self.bus.write(addr, self.xregs.read(rs2), 8)?,
+                    0b000 => self.store(addr, self.xregs.read(rs2), 8)?,
                     // SH
-                    0b001 => self.bus.write(addr, self.xregs.read(rs2), 16)?,
+                    0b001 => self.store(addr, self.xregs.read(rs2), 16)?,
                     // SW
-                    0b010 => self.bus.write(addr, self.xregs.read(rs2), 32)?,
+                    0b010 => self.store(addr, self.xregs.read(rs2), 32)?,
                     // SD
-                    0b011 => self.bus.write(addr, self.xregs.read(rs2), 64)?,
-                    _ => return Err(Exception::IllegalInstruction("STORE".to_owned()))
+                    0b011 => self.store(addr, self.xregs.read(rs2), 64)?,
+                    _ => return Err(Exception::IllegalInstruction(inst))
                 }
             }
+            0b0000111 => { // LOAD-FP
+                self.require_fp(inst)?;
+                let imm = ((inst as i32 as i64) >> 20) as u64;
+                let addr = imm.wrapping_add(self.xregs.read(rs1));
+                let (fmt, size) = match funct3 {
+                    // FLW
+                    0b010 => (F32, 32),
+                    // FLD
+                    0b011 => (F64, 64),
+                    _ => return Err(Exception::IllegalInstruction(inst))
+                };
+                let value = self.load(addr, size)?;
+                self.fregs.write(fmt, rd, value);
+                self.mark_fp_dirty();
+            }
+            0b0100111 => { // STORE-FP
+                self.require_fp(inst)?;
+                let imm = (((inst & 0xfe000000) as i32 as i64 >> 20) as u64) | ((inst >> 7) & 0x1f);
+                let addr = imm.wrapping_add(self.xregs.read(rs1));
+                match funct3 {
+                    // FSW stores the low bits without checking the NaN-boxing
+                    0b010 => self.store(addr, self.fregs.read(F64, rs2), 32)?,
+                    // FSD
+                    0b011 => self.store(addr, self.fregs.read(F64, rs2), 64)?,
+                    _ => return Err(Exception::IllegalInstruction(inst))
+                }
+            }
+            0b1000011 | 0b1000111 | 0b1001011 | 0b1001111 => { // FMADD / FMSUB / FNMSUB / FNMADD
+                self.require_fp(inst)?;
+                let rs3 = inst >> 27;
+                let fmt = match funct7 & 0b11 {
+                    0b00 => F32,
+                    0b01 => F64,
+                    _ => return Err(Exception::IllegalInstruction(inst))
+                };
+                let rm = self.rounding_mode(funct3, inst)?;
+                let (negate_product, negate_addend) = match opcode {
+                    0b1000011 => (false, false),
+                    0b1000111 => (false, true),
+                    0b1001011 => (true, false),
+                    _ => (true, true),
+                };
+
+                let mut fflags = 0;
+                let result = float::fused_mul_add(
+                    fmt,
+                    self.fregs.read(fmt, rs1),
+                    self.fregs.read(fmt, rs2),
+                    self.fregs.read(fmt, rs3),
+                    negate_product,
+                    negate_addend,
+                    rm,
+                    &mut fflags,
+                );
+                self.fregs.write(fmt, rd, result);
+                self.accrue_fflags(fflags);
+                self.mark_fp_dirty();
+            }
+            0b1010011 => { // OP-FP
+                self.require_fp(inst)?;
+                self.execute_op_fp(inst)?;
+                self.mark_fp_dirty();
+            }
             0b0001111 => {} // MISC-MEM
             0b1110011 => { // SYSTEM
-                match inst {
-                    0x10500073 => {
-                        self.wfi = true;
-                        panic!("WFI instruction waits for external interupts which are not implemented");
+                if inst == 0x10500073 { // WFI
+                    let status = self.csrs.read(csr::MSTATUS);
+                    if self.mode == Mode::User || (self.mode == Mode::Supervisor && status & mstatus::TW != 0) {
+                        return Err(Exception::IllegalInstruction(inst))
+                    }
+                    self.wfi = true;
+                    return Ok(());
+                }
+                if funct3 == 0 && funct7 == 0b0001001 { // SFENCE.VMA
+                    let status = self.csrs.read(csr::MSTATUS);
+                    if self.mode == Mode::User || (self.mode == Mode::Supervisor && status & mstatus::TVM != 0) {
+                        return Err(Exception::IllegalInstruction(inst))
                     }
-                    _ => ()
+                    let addr = if rs1 == 0 { None } else { Some(self.xregs.read(rs1)) };
+                    let asid = if rs2 == 0 { None } else { Some(self.xregs.read(rs2) & 0xffff) };
+                    self.flush_tlb(addr, asid);
+                    return Ok(());
                 }
                 let csr = inst >> 20;
+                // CSRRS and CSRRC with x0 and the immediate forms with 0 only read
+                let writes = matches!(funct3, 0b001 | 0b101) || rs1 != 0;
+                if !csr::exists(csr) || csr::privilege(csr) > self.mode.to_bits() || (writes && csr::is_read_only(csr)) {
+                    return Err(Exception::IllegalInstruction(inst))
+                }
+                // mstatus.TVM keeps the supervisor away from satp
+                if csr == csr::SATP && self.mode == Mode::Supervisor && self.csrs.read(csr::MSTATUS) & mstatus::TVM != 0 {
+                    return Err(Exception::IllegalInstruction(inst))
+                }
+                if matches!(csr, csr::FFLAGS | csr::FRM | csr::FCSR) {
+                    self.require_fp(inst)?;
+                }
 
-                let prev_val = self.csrs.read(csr);
+                // the time CSR is a read only shadow of the CLINT's mtime
+                let prev_val = if csr == csr::TIME { self.bus.clint.mtime() } else { self.csrs.read(csr) };
                 let new_val = match funct3 {
-                    0b000 => {
-                        println!("{inst:X}");
-                        return Err(Exception::IllegalInstruction("PRIV".to_owned()))
-                    },
+                    0b000 => return Err(Exception::IllegalInstruction(inst)),
                     0b001 => { // CSRRW
                         self.xregs.read(rs1)
                     }
@@ -516,27 +1338,76 @@ impl Cpu {
                     0b111 => { // CSRRCI
                         prev_val & !rs1
                     }
-                    _ => return Err(Exception::IllegalInstruction("SYSTEM".to_owned()))
+                    _ => return Err(Exception::IllegalInstruction(inst))
                 };
                 if new_val != prev_val {
                     self.csrs.write(csr, new_val);
+                    if csr == csr::SATP {
+                        self.satp_written(prev_val);
+                    }
+                    if matches!(csr, csr::FFLAGS | csr::FRM | csr::FCSR) {
+                        self.mark_fp_dirty();
+                    }
                 }
                 self.xregs.write(rd, prev_val);
             }
             0b0101111 => { // AMO
-                let addr = self.xregs.read(rs1);
+                let vaddr = self.xregs.read(rs1);
+                let size = match funct3 {
+                    0b010 => 4,
+                    0b011 => 8,
+                    _ => return Err(Exception::IllegalInstruction(inst))
+                };
+
+                match funct7 >> 2 {
+                    0b00010 => { // LR
+                        if rs2 != 0 {
+                            return Err(Exception::IllegalInstruction(inst))
+                        }
+                        if !vaddr.is_multiple_of(size) {
+                            return Err(Exception::LoadAddressMisaligned(vaddr))
+                        }
+                        let addr = self.translate(vaddr, Access::Load)?;
+                        let value = match size {
+                            4 => self.bus.read(addr, 32).map_err(|_| Exception::LoadAccessFault(vaddr))? as i32 as i64 as u64,
+                            _ => self.bus.read(addr, 64).map_err(|_| Exception::LoadAccessFault(vaddr))?,
+                        };
+                        self.xregs.write(rd, value);
+                        self.reservation = Some(addr);
+                        return Ok(())
+                    }
+                    0b00011 => { // SC
+                        if !vaddr.is_multiple_of(size) {
+                            return Err(Exception::StoreAddressMisaligned(vaddr))
+                        }
+                        let addr = self.translate(vaddr, Access::Store)?;
+                        // there's only the one hart, so nothing but a trap in
+                        // between can have broken the reservation
+                        let reserved = self.reservation.take() == Some(addr);
+                        if reserved {
+                            self.bus.write(addr, self.xregs.read(rs2), size as u8 * 8).map_err(|_| Exception::StoreAccessFault(vaddr))?;
+                        }
+                        self.xregs.write(rd, !reserved as u64);
+                        return Ok(())
+                    }
+                    _ => {}
+                }
+
+                // AMOs need write permission and report every fault as a store fault
+                if !vaddr.is_multiple_of(size) {
+                    return Err(Exception::StoreAddressMisaligned(vaddr))
+                }
+                let addr = self.translate(vaddr, Access::Store)?;
                 let value = match funct3 {
-                    0b010 => self.bus.read(addr, 32)? as i32 as i64,
-                    0b011 => self.bus.read(addr, 64)? as i64,
-                    _ => return Err(Exception::IllegalInstruction("AMO WRONG SIZE".to_string()))
+                    0b010 => self.bus.read(addr, 32).map_err(|_| Exception::StoreAccessFault(vaddr))? as i32 as i64,
+                    0b011 => self.bus.read(addr, 64).map_err(|_| Exception::StoreAccessFault(vaddr))? as i64,
+                    _ => return Err(Exception::IllegalInstruction(inst))
                 };
 
                 self.xregs.write(rd, value as u64);
 
                 let other_val = self.xregs.read(rs2) as i64;
                 let new_value = match funct7 >> 2 {
-                    0b00010 => panic!(),
-                    0b00011 => panic!(),
                     0b00001 => other_val, // could be wrong
                     0b00000 => value.wrapping_add(other_val),
                     0b00100 => value ^ other_val,
@@ -546,30 +1417,133 @@ impl Cpu {
                     0b10100 => value.max(other_val),
                     0b11000 => (value as u64).min(other_val as u64) as i64,
                     0b11100 => (value as u64).max(other_val as u64) as i64,
-                    _ => return Err(Exception::IllegalInstruction("AMO".to_string()))
+                    _ => return Err(Exception::IllegalInstruction(inst))
                 } as u64;
 
                 match funct3 {
-                    0b010 => self.bus.write(addr, new_value, 32)?,
-                    0b011 => self.bus.write(addr, new_value, 64)?,
+                    0b010 => self.bus.write(addr, new_value, 32),
+                    0b011 => self.bus.write(addr, new_value, 64),
                     _ => unreachable!()
-                };
+                }.map_err(|_| Exception::StoreAccessFault(vaddr))?;
             }
-            _ => return Err(Exception::IllegalInstruction("Not implemented".to_owned()))
+            _ => return Err(Exception::IllegalInstruction(inst))
         }
 
         Ok(())
     }
-}
 
-fn pause() {
-    use std::io::Read as _;
-    use std::io::Write as _;
+    fn execute_op_fp(&mut self, inst: u64) -> Result<(), Exception> {
+        let funct3 = (inst >> 12) & 0b111;
+        let funct7 = inst >> 25;
+
+        let rd  = (inst >> 7)  & 0b11111;
+        let rs1 = (inst >> 15) & 0b11111;
+        let rs2 = (inst >> 20) & 0b11111;
+
+        let fmt = match funct7 & 0b11 {
+            0b00 => F32,
+            0b01 => F64,
+            _ => return Err(Exception::IllegalInstruction(inst))
+        };
+        let a = self.fregs.read(fmt, rs1);
+        let b = self.fregs.read(fmt, rs2);
+        let mut fflags = 0;
 
-    let mut stdin = std::io::stdin();
-    let mut stdout = std::io::stdout();
+        match funct7 >> 2 {
+            0b00000 => { // FADD
+                let rm = self.rounding_mode(funct3, inst)?;
+                self.fregs.write(fmt, rd, float::add(fmt, a, b, rm, &mut fflags));
+            }
+            0b00001 => { // FSUB
+                let rm = self.rounding_mode(funct3, inst)?;
+                self.fregs.write(fmt, rd, float::sub(fmt, a, b, rm, &mut fflags));
+            }
+            0b00010 => { // FMUL
+                let rm = self.rounding_mode(funct3, inst)?;
+                self.fregs.write(fmt, rd, float::mul(fmt, a, b, rm, &mut fflags));
+            }
+            0b00011 => { // FDIV
+                let rm = self.rounding_mode(funct3, inst)?;
+                self.fregs.write(fmt, rd, float::div(fmt, a, b, rm, &mut fflags));
+            }
+            0b01011 if rs2 == 0 => { // FSQRT
+                let rm = self.rounding_mode(funct3, inst)?;
+                self.fregs.write(fmt, rd, float::sqrt(fmt, a, rm, &mut fflags));
+            }
+            0b00100 => { // FSGNJ / FSGNJN / FSGNJX
+                let result = float::sign_inject(fmt, a, b, funct3)
+                    .ok_or(Exception::IllegalInstruction(inst))?;
+                self.fregs.write(fmt, rd, result);
+            }
+            0b00101 => { // FMIN / FMAX
+                let max = match funct3 {
+                    0b000 => false,
+                    0b001 => true,
+                    _ => return Err(Exception::IllegalInstruction(inst))
+                };
+                self.fregs.write(fmt, rd, float::min_max(fmt, a, b, max, &mut fflags));
+            }
+            0b01000 => { // FCVT.S.D / FCVT.D.S
+                let from = match (rs2, fmt) {
+                    (1, F32) => F64,
+                    (0, F64) => F32,
+                    _ => return Err(Exception::IllegalInstruction(inst))
+                };
+                let rm = self.rounding_mode(funct3, inst)?;
+                let value = float::convert(from, fmt, self.fregs.read(from, rs1), rm, &mut fflags);
+                self.fregs.write(fmt, rd, value);
+            }
+            0b11000 => { // FCVT.W / FCVT.WU / FCVT.L / FCVT.LU
+                let (signed, width) = match rs2 {
+                    0b00 => (true, 32),
+                    0b01 => (false, 32),
+                    0b10 => (true, 64),
+                    0b11 => (false, 64),
+                    _ => return Err(Exception::IllegalInstruction(inst))
+                };
+                let rm = self.rounding_mode(funct3, inst)?;
+                self.xregs.write(rd, float::to_int(fmt, a, signed, width, rm, &mut fflags));
+            }
+            0b11010 => { // FCVT.S/D.W / WU / L / LU
+                let (signed, width) = match rs2 {
+                    0b00 => (true, 32),
+                    0b01 => (false, 32),
+                    0b10 => (true, 64),
+                    0b11 => (false, 64),
+                    _ => return Err(Exception::IllegalInstruction(inst))
+                };
+                let rm = self.rounding_mode(funct3, inst)?;
+                let value = float::from_int(fmt, self.xregs.read(rs1), signed, width, rm, &mut fflags);
+                self.fregs.write(fmt, rd, value);
+            }
+            0b11100 if rs2 == 0 => {
+                match funct3 {
+                    0b000 => { // FMV.X.W / FMV.X.D
+                        let raw = self.fregs.read(F64, rs1);
+                        self.xregs.write(rd, if fmt == F32 { raw as i32 as i64 as u64 } else { raw });
+                    }
+                    0b001 => { // FCLASS
+                        self.xregs.write(rd, float::classify(fmt, a));
+                    }
+                    _ => return Err(Exception::IllegalInstruction(inst))
+                }
+            }
+            0b10100 => { // FEQ / FLT / FLE
+                let result = match funct3 {
+                    0b010 => float::eq(fmt, a, b, &mut fflags),
+                    0b001 => float::lt(fmt, a, b, &mut fflags),
+                    0b000 => float::le(fmt, a, b, &mut fflags),
+                    _ => return Err(Exception::IllegalInstruction(inst))
+                };
+                self.xregs.write(rd, result as u64);
+            }
+            0b11110 if rs2 == 0 && funct3 == 0 => { // FMV.W.X / FMV.D.X
+                self.fregs.write(fmt, rd, self.xregs.read(rs1));
+            }
+            _ => return Err(Exception::IllegalInstruction(inst))
+        }
 
-    write!(stdout, "Press any key to continue...\n").unwrap();
-    stdout.flush().unwrap();
-    let _ = stdin.read(&mut [0u8]).unwrap();
-}
\ No newline at end of file
+        self.accrue_fflags(fflags);
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+use crate::exception::Exception;
+
+use super::{csr, mstatus, tlb::{Tlb, TlbStats}, Cpu, Mode};
+
+const PAGE_SHIFT: u64 = 12;
+const PTE_SIZE: u64 = 8;
+
+mod satp {
+    pub const MODE_SHIFT: u64 = 60;
+    pub const ASID_SHIFT: u64 = 44;
+    pub const ASID_MASK: u64 = 0xffff;
+    pub const PPN_MASK: u64 = (1 << 44) - 1;
+
+    pub const MODE_BARE: u64 = 0;
+    pub const MODE_SV39: u64 = 8;
+    pub const MODE_SV48: u64 = 9;
+}
+
+mod pte {
+    pub const V: u64 = 1 << 0;
+    pub const R: u64 = 1 << 1;
+    pub const W: u64 = 1 << 2;
+    pub const X: u64 = 1 << 3;
+    pub const U: u64 = 1 << 4;
+    pub const G: u64 = 1 << 5;
+    pub const A: u64 = 1 << 6;
+    pub const D: u64 = 1 << 7;
+
+    pub const PPN_SHIFT: u64 = 10;
+    pub const PPN_MASK: u64 = (1 << 44) - 1;
+    /// bits 63:54 hold Svnapot/Svpbmt fields which aren't implemented
+    pub const RESERVED: u64 = 0x3ff << 54;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Instruction,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn page_fault(self, addr: u64) -> Exception {
+        match self {
+            Access::Instruction => Exception::InstructionPageFault(addr),
+            Access::Load => Exception::LoadPageFault(addr),
+            Access::Store => Exception::StorePageFault(addr),
+        }
+    }
+
+    fn access_fault(self, addr: u64) -> Exception {
+        match self {
+            Access::Instruction => Exception::InstructionAccessFault(addr),
+            Access::Load => Exception::LoadAccessFault(addr),
+            Access::Store => Exception::StoreAccessFault(addr),
+        }
+    }
+}
+
+/// satp writes selecting a translation mode this hart doesn't support are ignored
+pub fn is_supported_satp(value: u64) -> bool {
+    matches!(value >> satp::MODE_SHIFT, satp::MODE_BARE | satp::MODE_SV39 | satp::MODE_SV48)
+}
+
+/// checks the permission bits of a leaf PTE against an access
+fn is_allowed(pte: u64, access: Access, mode: Mode, status: u64) -> bool {
+    let allowed = match access {
+        Access::Instruction => pte & pte::X != 0,
+        Access::Load => pte & pte::R != 0 || (status & mstatus::MXR != 0 && pte & pte::X != 0),
+        Access::Store => pte & pte::W != 0,
+    };
+    let user_ok = match mode {
+        Mode::User => pte & pte::U != 0,
+        Mode::Supervisor => pte & pte::U == 0 || (access != Access::Instruction && status & mstatus::SUM != 0),
+        Mode::Machine => true,
+    };
+    allowed && user_ok
+}
+
+fn levels(satp: u64) -> Option<u64> {
+    match satp >> satp::MODE_SHIFT {
+        satp::MODE_SV39 => Some(3),
+        satp::MODE_SV48 => Some(4),
+        _ => None,
+    }
+}
+
+/// the virtual page number of `addr` without the sign extended upper bits
+fn vpn(addr: u64, levels: u64) -> u64 {
+    (addr >> PAGE_SHIFT) & ((1 << (9 * levels)) - 1)
+}
+
+impl Cpu {
+    /// the privilege loads and stores are checked against, MPRV makes M-mode act as MPP
+    fn effective_mode(&self, access: Access) -> Mode {
+        let status = self.csrs.read(csr::MSTATUS);
+        if access != Access::Instruction && self.mode == Mode::Machine && status & mstatus::MPRV != 0 {
+            Mode::from_bits((status & mstatus::MPP) >> mstatus::MPP_SHIFT)
+        } else {
+            self.mode
+        }
+    }
+
+    fn tlb(&mut self, access: Access) -> &mut Tlb {
+        if access == Access::Instruction { &mut self.itlb } else { &mut self.dtlb }
+    }
+
+    /// hit and miss counts for the instruction and data TLBs
+    pub fn tlb_stats(&self) -> (TlbStats, TlbStats) {
+        (self.itlb.stats(), self.dtlb.stats())
+    }
+
+    /// SFENCE.VMA, `None` flushes every address or every address space
+    pub(super) fn flush_tlb(&mut self, addr: Option<u64>, asid: Option<u64>) {
+        let levels = levels(self.csrs.read(csr::SATP)).unwrap_or(4);
+        let vpn = addr.map(|addr| vpn(addr, levels));
+        self.itlb.flush(vpn, asid);
+        self.dtlb.flush(vpn, asid);
+    }
+
+    /// switching ASIDs keeps the cached translations around, but a new root
+    /// table or translation mode under the same ASID makes them all stale
+    pub(super) fn satp_written(&mut self, prev: u64) {
+        let satp = self.csrs.read(csr::SATP);
+        let asid = (satp >> satp::ASID_SHIFT) & satp::ASID_MASK;
+        let prev_asid = (prev >> satp::ASID_SHIFT) & satp::ASID_MASK;
+        if satp != prev && asid == prev_asid {
+            self.flush_tlb(None, None);
+        }
+    }
+
+    /// translates a virtual address to a physical one by walking the Sv39/Sv48 page tables
+    pub(super) fn translate(&mut self, addr: u64, access: Access) -> Result<u64, Exception> {
+        let satp = self.csrs.read(csr::SATP);
+        let Some(levels) = levels(satp) else {
+            return Ok(addr);
+        };
+
+        let mode = self.effective_mode(access);
+        if mode == Mode::Machine {
+            return Ok(addr);
+        }
+
+        // the upper bits must all be copies of the top bit of the virtual address
+        let va_bits = PAGE_SHIFT + 9 * levels;
+        let top = (addr as i64) >> (va_bits - 1);
+        if top != 0 && top != -1 {
+            return Err(access.page_fault(addr));
+        }
+
+        let status = self.csrs.read(csr::MSTATUS);
+        let asid = (satp >> satp::ASID_SHIFT) & satp::ASID_MASK;
+        let vpn = vpn(addr, levels);
+        let page_offset = addr & ((1 << PAGE_SHIFT) - 1);
+
+        if let Some((ppn, flags)) = self.tlb(access).lookup(vpn, asid) {
+            // stores to a clean page have to walk the table again to set D
+            if access != Access::Store || flags & pte::D != 0 {
+                if !is_allowed(flags, access, mode, status) {
+                    return Err(access.page_fault(addr));
+                }
+                return Ok((ppn << PAGE_SHIFT) | page_offset);
+            }
+        }
+
+        let mut table = (satp & satp::PPN_MASK) << PAGE_SHIFT;
+        let mut level = levels - 1;
+
+        let (pte_addr, pte) = loop {
+            let pte_addr = table + ((vpn >> (9 * level)) & 0x1ff) * PTE_SIZE;
+            let pte = self.bus.read(pte_addr, 64).map_err(|_| access.access_fault(addr))?;
+
+            if pte & pte::V == 0 || (pte & pte::R == 0 && pte & pte::W != 0) || pte & pte::RESERVED != 0 {
+                return Err(access.page_fault(addr));
+            }
+
+            if pte & (pte::R | pte::X) != 0 {
+                break (pte_addr, pte);
+            }
+
+            // a pointer to the next level of the table
+            if level == 0 {
+                return Err(access.page_fault(addr));
+            }
+            level -= 1;
+            table = ((pte >> pte::PPN_SHIFT) & pte::PPN_MASK) << PAGE_SHIFT;
+        };
+
+        if !is_allowed(pte, access, mode, status) {
+            return Err(access.page_fault(addr));
+        }
+
+        // superpages must be aligned to their size
+        let ppn = (pte >> pte::PPN_SHIFT) & pte::PPN_MASK;
+        let offset_mask = (1 << (9 * level)) - 1;
+        if ppn & offset_mask != 0 {
+            return Err(access.page_fault(addr));
+        }
+
+        // A and D are updated by the hart rather than faulting
+        let updated = pte | pte::A | if access == Access::Store { pte::D } else { 0 };
+        if updated != pte {
+            self.bus.write(pte_addr, updated, 64).map_err(|_| access.access_fault(addr))?;
+        }
+
+        let ppn = ppn | (vpn & offset_mask);
+        self.tlb(access).insert(vpn, ppn, asid, pte & pte::G != 0, level, updated);
+        Ok((ppn << PAGE_SHIFT) | page_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::DRAM_START;
+
+    use super::*;
+    use super::super::tests::{cpu, run};
+
+    /// where the tables go, each 4KiB apart
+    const ROOT: u64 = DRAM_START + 0x10000;
+    const LEVEL1: u64 = ROOT + 0x1000;
+    const LEVEL0: u64 = ROOT + 0x2000;
+    const SV48_ROOT: u64 = ROOT + 0x3000;
+
+    const RW: u64 = pte::V | pte::R | pte::W | pte::A | pte::D;
+
+    fn entry(addr: u64, flags: u64) -> u64 {
+        (addr >> PAGE_SHIFT) << pte::PPN_SHIFT | flags
+    }
+
+    fn set(cpu: &mut Cpu, table: u64, index: u64, entry: u64) {
+        cpu.bus.write(table + index * PTE_SIZE, entry, 64).unwrap();
+    }
+
+    fn get(cpu: &mut Cpu, table: u64, index: u64) -> u64 {
+        cpu.bus.read(table + index * PTE_SIZE, 64).unwrap()
+    }
+
+    /// an S-mode hart with ROOT -> LEVEL1 -> LEVEL0 linked up for the first 2M of
+    /// the address space and nothing mapped yet
+    fn supervisor(mode: u64, asid: u64) -> Cpu {
+        let mut cpu = cpu();
+        set(&mut cpu, ROOT, 0, entry(LEVEL1, pte::V));
+        set(&mut cpu, LEVEL1, 0, entry(LEVEL0, pte::V));
+        set(&mut cpu, SV48_ROOT, 0, entry(ROOT, pte::V));
+        let root = if mode == satp::MODE_SV48 { SV48_ROOT } else { ROOT };
+        cpu.csrs.write(csr::SATP, mode << satp::MODE_SHIFT | asid << satp::ASID_SHIFT | root >> PAGE_SHIFT);
+        cpu.mode = Mode::Supervisor;
+        cpu
+    }
+
+    #[test]
+    fn sv39_and_sv48_walks() {
+        for mode in [satp::MODE_SV39, satp::MODE_SV48] {
+            let mut cpu = supervisor(mode, 0);
+            set(&mut cpu, LEVEL0, 2, entry(DRAM_START + 0x20000, RW));
+            assert_eq!(cpu.translate(0x2123, Access::Load), Ok(DRAM_START + 0x20123), "{mode}");
+            assert_eq!(cpu.translate(0x3000, Access::Load), Err(Exception::LoadPageFault(0x3000)), "{mode}");
+
+            // a 2M megapage and a 1G gigapage keep the low bits of the address
+            set(&mut cpu, LEVEL1, 1, entry(DRAM_START + 0x400000, RW));
+            assert_eq!(cpu.translate(0x3f_f123, Access::Store), Ok(DRAM_START + 0x5f_f123), "{mode}");
+            set(&mut cpu, ROOT, 2, entry(DRAM_START, RW));
+            assert_eq!(cpu.translate(0x8765_4321, Access::Load), Ok(0x8765_4321), "{mode}");
+
+            // and have to be aligned to their size
+            set(&mut cpu, LEVEL1, 3, entry(DRAM_START + 0x1000, RW));
+            assert_eq!(cpu.translate(0x60_0000, Access::Load), Err(Exception::LoadPageFault(0x60_0000)), "{mode}");
+            set(&mut cpu, ROOT, 3, entry(DRAM_START + 0x200000, RW));
+            assert_eq!(cpu.translate(0xc000_0000, Access::Instruction), Err(Exception::InstructionPageFault(0xc000_0000)), "{mode}");
+        }
+
+        // bit 38 is the top of an Sv39 address but not of an Sv48 one
+        let addr = 0x40_0000_2000;
+        let mut cpu = supervisor(satp::MODE_SV39, 0);
+        assert_eq!(cpu.translate(addr, Access::Load), Err(Exception::LoadPageFault(addr)));
+        let mut cpu = supervisor(satp::MODE_SV48, 0);
+        set(&mut cpu, ROOT, 0x100, entry(LEVEL1, pte::V));
+        set(&mut cpu, LEVEL0, 2, entry(DRAM_START + 0x20000, RW));
+        assert_eq!(cpu.translate(addr, Access::Load), Ok(DRAM_START + 0x20000));
+    }
+
+    #[test]
+    fn bad_ptes_fault() {
+        let mut cpu = supervisor(satp::MODE_SV39, 0);
+        for (what, pte) in [
+            ("invalid", RW & !pte::V),
+            ("writable but not readable", pte::V | pte::W | pte::A | pte::D),
+            ("reserved bits", RW | 1 << 60),
+        ] {
+            set(&mut cpu, LEVEL0, 1, entry(DRAM_START, pte));
+            assert_eq!(cpu.translate(0x1000, Access::Load), Err(Exception::LoadPageFault(0x1000)), "{what}");
+        }
+
+        // a pointer at the last level
+        set(&mut cpu, LEVEL0, 1, entry(DRAM_START, pte::V));
+        assert_eq!(cpu.translate(0x1000, Access::Load), Err(Exception::LoadPageFault(0x1000)));
+        // tables outside memory are access faults rather than page faults
+        set(&mut cpu, LEVEL1, 1, entry(0x1_0000_0000, pte::V));
+        assert_eq!(cpu.translate(0x20_0000, Access::Store), Err(Exception::StoreAccessFault(0x20_0000)));
+    }
+
+    #[test]
+    fn accessed_and_dirty_bits() {
+        let mut cpu = supervisor(satp::MODE_SV39, 0);
+        let clean = entry(DRAM_START + 0x20000, pte::V | pte::R | pte::W);
+        set(&mut cpu, LEVEL0, 1, clean);
+
+        cpu.translate(0x1000, Access::Load).unwrap();
+        assert_eq!(get(&mut cpu, LEVEL0, 1), clean | pte::A);
+        // a store to the page the TLB has as clean still sets D
+        cpu.translate(0x1008, Access::Store).unwrap();
+        assert_eq!(get(&mut cpu, LEVEL0, 1), clean | pte::A | pte::D);
+    }
+
+    #[test]
+    fn permissions() {
+        let mut cpu = supervisor(satp::MODE_SV39, 0);
+        let user = 0x1000;
+        let execute_only = 0x2000;
+        let read_only = 0x3000;
+        set(&mut cpu, LEVEL0, 1, entry(DRAM_START, RW | pte::X | pte::U));
+        set(&mut cpu, LEVEL0, 2, entry(DRAM_START, pte::V | pte::X | pte::A));
+        set(&mut cpu, LEVEL0, 3, entry(DRAM_START, pte::V | pte::R | pte::A));
+
+        // S-mode only gets at user pages with SUM, and never runs them
+        assert_eq!(cpu.translate(user, Access::Load), Err(Exception::LoadPageFault(user)));
+        cpu.csrs.write(csr::MSTATUS, mstatus::SUM);
+        assert_eq!(cpu.translate(user, Access::Load), Ok(DRAM_START));
+        assert_eq!(cpu.translate(user, Access::Store), Ok(DRAM_START));
+        assert_eq!(cpu.translate(user, Access::Instruction), Err(Exception::InstructionPageFault(user)));
+
+        // U-mode only gets at user pages
+        cpu.mode = Mode::User;
+        assert_eq!(cpu.translate(user, Access::Instruction), Ok(DRAM_START));
+        assert_eq!(cpu.translate(read_only, Access::Load), Err(Exception::LoadPageFault(read_only)));
+        cpu.mode = Mode::Supervisor;
+
+        // MXR makes executable pages readable
+        assert_eq!(cpu.translate(execute_only, Access::Load), Err(Exception::LoadPageFault(execute_only)));
+        cpu.csrs.write(csr::MSTATUS, mstatus::MXR);
+        assert_eq!(cpu.translate(execute_only, Access::Load), Ok(DRAM_START));
+        assert_eq!(cpu.translate(execute_only, Access::Store), Err(Exception::StorePageFault(execute_only)));
+
+        assert_eq!(cpu.translate(read_only, Access::Load), Ok(DRAM_START));
+        assert_eq!(cpu.translate(read_only, Access::Store), Err(Exception::StorePageFault(read_only)));
+    }
+
+    #[test]
+    fn sfence_vma_by_address_and_asid() {
+        let mut cpu = supervisor(satp::MODE_SV39, 1);
+        let (a, b, global) = (0x1000, 0x2000, 0x3000);
+        set(&mut cpu, LEVEL0, 1, entry(DRAM_START, RW));
+        set(&mut cpu, LEVEL0, 2, entry(DRAM_START, RW));
+        set(&mut cpu, LEVEL0, 3, entry(DRAM_START, RW | pte::G));
+        for addr in [a, b, global] {
+            cpu.translate(addr, Access::Load).unwrap();
+        }
+
+        // move every page, the TLB keeps the old translations until it's told
+        for index in 1..=3 {
+            let pte = get(&mut cpu, LEVEL0, index);
+            set(&mut cpu, LEVEL0, index, pte + entry(0x10000, 0));
+        }
+        let moved = DRAM_START + 0x10000;
+        let sfence = |cpu: &mut Cpu, addr: u64, asid: u64| {
+            // sfence.vma x1, x2 with x0 for whichever isn't given
+            let (rs1, rs2) = (if addr == 0 { 0 } else { 1 }, if asid == 0 { 0 } else { 2 });
+            cpu.xregs.write(1, addr);
+            cpu.xregs.write(2, asid);
+            cpu.mode = Mode::Machine;
+            run(cpu, 0b0001001 << 25 | rs2 << 20 | rs1 << 15 | 0b1110011).unwrap();
+            cpu.mode = Mode::Supervisor;
+        };
+        let translations = |cpu: &mut Cpu| [a, b, global].map(|addr| cpu.translate(addr, Access::Load).unwrap());
+
+        sfence(&mut cpu, b, 0);
+        assert_eq!(translations(&mut cpu), [DRAM_START, moved, DRAM_START]);
+        // another address space's entries stay
+        sfence(&mut cpu, 0, 2);
+        assert_eq!(translations(&mut cpu), [DRAM_START, moved, DRAM_START]);
+        // and global ones stay in every address space
+        sfence(&mut cpu, 0, 1);
+        assert_eq!(translations(&mut cpu), [moved, moved, DRAM_START]);
+        sfence(&mut cpu, global, 0);
+        assert_eq!(translations(&mut cpu), [moved, moved, moved]);
+
+        let (_, dtlb) = cpu.tlb_stats();
+        assert!(dtlb.hits > 0 && dtlb.misses > 0);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::{bus::{DRAM_START, HARTS}, interrupt::Interrupt, test_finisher};
+
+use super::{csr, mip, Cpu, Mode};
+
+// extension IDs, the legacy ones take their arguments the same way but only return an error
+const LEGACY_SET_TIMER: u64 = 0x00;
+const LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const LEGACY_CLEAR_IPI: u64 = 0x03;
+const LEGACY_SEND_IPI: u64 = 0x04;
+const LEGACY_REMOTE_FENCE_I: u64 = 0x05;
+const LEGACY_REMOTE_SFENCE_VMA: u64 = 0x06;
+const LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+const LEGACY_SHUTDOWN: u64 = 0x08;
+const BASE: u64 = 0x10;
+const TIME: u64 = 0x54494d45;
+const IPI: u64 = 0x735049;
+const RFENCE: u64 = 0x52464e43;
+const HSM: u64 = 0x48534d;
+const SRST: u64 = 0x53525354;
+const DBCN: u64 = 0x4442434e;
+
+const EXTENSIONS: [u64; 16] = [
+    LEGACY_SET_TIMER,
+    LEGACY_CONSOLE_PUTCHAR,
+    LEGACY_CONSOLE_GETCHAR,
+    LEGACY_CLEAR_IPI,
+    LEGACY_SEND_IPI,
+    LEGACY_REMOTE_FENCE_I,
+    LEGACY_REMOTE_SFENCE_VMA,
+    LEGACY_REMOTE_SFENCE_VMA_ASID,
+    LEGACY_SHUTDOWN,
+    BASE,
+    TIME,
+    IPI,
+    RFENCE,
+    HSM,
+    SRST,
+    DBCN,
+];
+
+/// version 2.0
+const SPEC_VERSION: u64 = 2 << 24;
+/// not one of the IDs handed out to real implementations
+const IMPL_ID: u64 = 0x7265;
+const IMPL_VERSION: u64 = 1;
+
+mod error {
+    pub const SUCCESS: i64 = 0;
+    pub const FAILED: i64 = -1;
+    pub const NOT_SUPPORTED: i64 = -2;
+    pub const INVALID_PARAM: i64 = -3;
+    pub const INVALID_ADDRESS: i64 = -5;
+    pub const ALREADY_AVAILABLE: i64 = -6;
+}
+
+const HART_STARTED: u64 = 0;
+const SUSPEND_RETENTIVE: u64 = 0;
+
+const RESET_SHUTDOWN: u64 = 0;
+const RESET_COLD_REBOOT: u64 = 1;
+const RESET_WARM_REBOOT: u64 = 2;
+const RESET_REASON_FAILURE: u64 = 1;
+
+/// every exception a supervisor can take itself, which is all but its own
+/// ECALLs and the ones from M-mode
+const DELEGATED_EXCEPTIONS: u64 = 0xb1ff;
+
+/// a flush covering more pages than this just flushes everything
+const MAX_FLUSH_PAGES: u64 = 64;
+const PAGE_SIZE: u64 = 4096;
+
+/// the M-mode firmware the emulator stands in for when there isn't any, the
+/// hart starts in the kernel and its SBI calls are answered here
+pub struct Sbi {
+    kernel: u64,
+    dtb: u64,
+}
+
+impl Sbi {
+    pub fn new(kernel: u64, dtb: u64) -> Self {
+        Self { kernel, dtb }
+    }
+}
+
+/// what an SBI call hands back in a0 and a1
+type SbiResult = (i64, u64);
+
+impl Cpu {
+    /// sets the hart up the way firmware leaves it for a kernel, in S-mode
+    /// with the hart ID in a0 and the device tree in a1
+    pub(super) fn enter_kernel(&mut self) {
+        let Some(sbi) = &self.sbi else { return };
+        let (kernel, dtb) = (sbi.kernel, sbi.dtb);
+        self.csrs.write(csr::MEDELEG, DELEGATED_EXCEPTIONS);
+        self.csrs.write(csr::MIDELEG, mip::SUPERVISOR);
+        self.xregs.write(10, self.csrs.read(csr::MHARTID));
+        self.xregs.write(11, dtb);
+        self.mode = Mode::Supervisor;
+        self.pc = kernel;
+    }
+
+    /// answers the ECALL at pc and moves on past it
+    pub(super) fn sbi_call(&mut self) {
+        let eid = self.xregs.read(17);
+        let fid = self.xregs.read(16);
+        let args: [u64; 6] = std::array::from_fn(|i| self.xregs.read(10 + i as u64));
+
+        match eid {
+            // the legacy calls only return an error, or a character for getchar
+            LEGACY_SET_TIMER..=LEGACY_SHUTDOWN => {
+                let value = self.legacy_call(eid, args);
+                self.xregs.write(10, value as u64);
+            }
+            _ => {
+                let (error, value) = match eid {
+                    BASE => self.base_call(fid, args),
+                    TIME => self.time_call(fid, args),
+                    IPI => self.ipi_call(fid, args),
+                    RFENCE => self.rfence_call(fid, args),
+                    HSM => self.hsm_call(fid, args),
+                    SRST => self.srst_call(fid, args),
+                    DBCN => self.dbcn_call(fid, args),
+                    _ => (error::NOT_SUPPORTED, 0),
+                };
+                self.xregs.write(10, error as u64);
+                self.xregs.write(11, value);
+            }
+        }
+        self.pc = self.pc.wrapping_add(4);
+    }
+
+    fn legacy_call(&mut self, eid: u64, args: [u64; 6]) -> i64 {
+        match eid {
+            LEGACY_SET_TIMER => self.time_call(0, args).0,
+            LEGACY_CONSOLE_PUTCHAR => {
+                self.bus.uart.write_bytes(&[args[0] as u8]);
+                error::SUCCESS
+            }
+            LEGACY_CONSOLE_GETCHAR => self.bus.uart.read_byte().map_or(-1, i64::from),
+            LEGACY_CLEAR_IPI => {
+                self.set_interrupt_pending(Interrupt::SupervisorSoftware, false);
+                error::SUCCESS
+            }
+            // the hart mask is passed by its virtual address, with no base
+            LEGACY_SEND_IPI..=LEGACY_REMOTE_SFENCE_VMA_ASID => {
+                let Ok(mask) = self.load(args[0], 64) else { return error::INVALID_ADDRESS };
+                let args = [mask, 0, args[1], args[2], args[3], 0];
+                match eid {
+                    LEGACY_SEND_IPI => self.ipi_call(0, args).0,
+                    LEGACY_REMOTE_FENCE_I => self.rfence_call(0, args).0,
+                    LEGACY_REMOTE_SFENCE_VMA => self.rfence_call(1, args).0,
+                    _ => self.rfence_call(2, args).0,
+                }
+            }
+            _ => self.srst_call(0, [RESET_SHUTDOWN, 0, 0, 0, 0, 0]).0,
+        }
+    }
+
+    fn base_call(&mut self, fid: u64, args: [u64; 6]) -> SbiResult {
+        match fid {
+            0 => (error::SUCCESS, SPEC_VERSION),
+            1 => (error::SUCCESS, IMPL_ID),
+            2 => (error::SUCCESS, IMPL_VERSION),
+            3 => (error::SUCCESS, EXTENSIONS.contains(&args[0]) as u64),
+            4 => (error::SUCCESS, self.csrs.read(csr::MVENDORID)),
+            5 => (error::SUCCESS, self.csrs.read(csr::MARCHID)),
+            6 => (error::SUCCESS, self.csrs.read(csr::MIMPID)),
+            _ => (error::NOT_SUPPORTED, 0),
+        }
+    }
+
+    fn time_call(&mut self, fid: u64, args: [u64; 6]) -> SbiResult {
+        match fid {
+            0 => {
+                // the supervisor timer interrupt follows the CLINT's from here on, see `Cpu::tick`
+                let hart = self.csrs.read(csr::MHARTID) as usize;
+                self.bus.clint.set_mtimecmp(hart, args[0]);
+                self.set_interrupt_pending(Interrupt::SupervisorTimer, false);
+                (error::SUCCESS, 0)
+            }
+            _ => (error::NOT_SUPPORTED, 0),
+        }
+    }
+
+    /// whether this hart is in a hart mask, `None` if the mask names one that doesn't exist
+    fn targets_this_hart(&self, mask: u64, base: u64) -> Option<bool> {
+        // a base of -1 means every hart
+        if base == u64::MAX {
+            return Some(true);
+        }
+        let harts = (0..64).filter(|bit| mask & 1 << bit != 0).map(|bit| base.checked_add(bit));
+        let mut this = false;
+        for hart in harts {
+            let hart = hart.filter(|&hart| hart < HARTS as u64)?;
+            this |= hart == self.csrs.read(csr::MHARTID);
+        }
+        Some(this)
+    }
+
+    fn ipi_call(&mut self, fid: u64, args: [u64; 6]) -> SbiResult {
+        match fid {
+            0 => match self.targets_this_hart(args[0], args[1]) {
+                Some(this) => {
+                    if this {
+                        self.set_interrupt_pending(Interrupt::SupervisorSoftware, true);
+                    }
+                    (error::SUCCESS, 0)
+                }
+                None => (error::INVALID_PARAM, 0),
+            },
+            _ => (error::NOT_SUPPORTED, 0),
+        }
+    }
+
+    fn rfence_call(&mut self, fid: u64, args: [u64; 6]) -> SbiResult {
+        let Some(this) = self.targets_this_hart(args[0], args[1]) else { return (error::INVALID_PARAM, 0) };
+        let (start, size) = (args[2], args[3]);
+        let asid = match fid {
+            // instructions aren't cached so there's nothing to fence
+            0 => return (error::SUCCESS, 0),
+            1 => None,
+            2 => Some(args[4] & 0xffff),
+            _ => return (error::NOT_SUPPORTED, 0),
+        };
+        if this {
+            // a start and size of zero or a size of -1 mean the whole address space
+            if (start == 0 && size == 0) || size == u64::MAX || size.div_ceil(PAGE_SIZE) > MAX_FLUSH_PAGES {
+                self.flush_tlb(None, asid);
+            } else {
+                for page in 0..size.div_ceil(PAGE_SIZE) {
+                    self.flush_tlb(Some(start.wrapping_add(page * PAGE_SIZE)), asid);
+                }
+            }
+        }
+        (error::SUCCESS, 0)
+    }
+
+    fn hsm_call(&mut self, fid: u64, args: [u64; 6]) -> SbiResult {
+        let valid_hart = args[0] < HARTS as u64;
+        match fid {
+            // every hart there is is already running
+            0 if valid_hart => (error::ALREADY_AVAILABLE, 0),
+            // stopping the only hart would leave nothing to start it again
+            1 => (error::FAILED, 0),
+            2 if valid_hart => (error::SUCCESS, HART_STARTED),
+            0 | 2 => (error::INVALID_PARAM, 0),
+            3 => match args[0] {
+                // resumes where it left off once an interrupt's pending, like WFI
+                SUSPEND_RETENTIVE => {
+                    self.wfi = true;
+                    (error::SUCCESS, 0)
+                }
+                _ => (error::NOT_SUPPORTED, 0),
+            },
+            _ => (error::NOT_SUPPORTED, 0),
+        }
+    }
+
+    /// shutdowns and reboots go through the test finisher, the same as
+    /// firmware on QEMU does it
+    fn srst_call(&mut self, fid: u64, args: [u64; 6]) -> SbiResult {
+        if fid != 0 {
+            return (error::NOT_SUPPORTED, 0);
+        }
+        let value = match (args[0], args[1]) {
+            (RESET_SHUTDOWN, RESET_REASON_FAILURE) => 1 << 16 | test_finisher::FAIL,
+            (RESET_SHUTDOWN, _) => test_finisher::PASS,
+            (RESET_COLD_REBOOT | RESET_WARM_REBOOT, _) => test_finisher::RESET,
+            _ => return (error::INVALID_PARAM, 0),
+        };
+        let _ = self.bus.test_finisher.write(0, value, 32);
+        (error::SUCCESS, 0)
+    }
+
+    /// the debug console works on physical addresses, which have to be in memory
+    fn dbcn_call(&mut self, fid: u64, args: [u64; 6]) -> SbiResult {
+        let (len, addr) = (args[0], args[1] | args[2] << 32);
+        match fid {
+            0 => {
+                let Some(bytes) = addr.checked_sub(DRAM_START).and_then(|addr| self.bus.dram.slice(addr, len as usize)) else {
+                    return (error::INVALID_PARAM, 0);
+                };
+                let bytes = bytes.to_vec();
+                self.bus.uart.write_bytes(&bytes);
+                (error::SUCCESS, len)
+            }
+            1 => {
+                let Some(buffer) = addr.checked_sub(DRAM_START).and_then(|addr| self.bus.dram.slice_mut(addr, len as usize)) else {
+                    return (error::INVALID_PARAM, 0);
+                };
+                let mut read = 0;
+                while read < buffer.len() {
+                    let Some(byte) = self.bus.uart.read_byte() else { break };
+                    buffer[read] = byte;
+                    read += 1;
+                }
+                (error::SUCCESS, read as u64)
+            }
+            2 => {
+                self.bus.uart.write_bytes(&[args[0] as u8]);
+                (error::SUCCESS, 0)
+            }
+            _ => (error::NOT_SUPPORTED, 0),
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::io;
+
+use crate::{bus::{Bus, DRAM_START}, clint::ClockSource, uart::Uart};
+
+use super::*;
+
+/// a hart in M-mode at the start of 1M of memory
+pub(super) fn cpu() -> Cpu {
+    let uart = Uart::with_io(None, Box::new(io::sink()));
+    Cpu::new(Bus::new(1024 * 1024, ClockSource::InstructionCount { instructions_per_tick: 1 }, uart), DRAM_START)
+}
+
+/// runs one instruction at pc, which can be 16 or 32 bits
+pub(super) fn run(cpu: &mut Cpu, inst: u32) -> Result<(), Exception> {
+    let size = if inst & 0b11 == 0b11 { 32 } else { 16 };
+    cpu.bus.write(cpu.pc, inst as u64, size).unwrap();
+    cpu.execute()
+}
+
+fn r_type(opcode: u32, funct3: u32, funct7: u32) -> u32 {
+    // rd = x3, rs1 = x1, rs2 = x2
+    funct7 << 25 | 2 << 20 | 1 << 15 | funct3 << 12 | 3 << 7 | opcode
+}
+
+/// x3 after `x3 = x1 op x2`
+fn op(opcode: u32, funct3: u32, funct7: u32, a: u64, b: u64) -> u64 {
+    let mut cpu = cpu();
+    cpu.xregs.write(1, a);
+    cpu.xregs.write(2, b);
+    run(&mut cpu, r_type(opcode, funct3, funct7)).unwrap();
+    cpu.xregs.read(3)
+}
+
+const OP: u32 = 0b0110011;
+const OP_32: u32 = 0b0111011;
+const MULDIV: u32 = 1;
+
+#[test]
+fn division_by_zero() {
+    let a = 0x1234_5678_9abc_def0;
+    for (name, opcode, funct3, expected) in [
+        ("div", OP, 0b100, u64::MAX),
+        ("divu", OP, 0b101, u64::MAX),
+        ("rem", OP, 0b110, a),
+        ("remu", OP, 0b111, a),
+        // the word forms are sign extended from 32 bits
+        ("divw", OP_32, 0b100, u64::MAX),
+        ("divuw", OP_32, 0b101, u64::MAX),
+        ("remw", OP_32, 0b110, 0xffff_ffff_9abc_def0),
+        ("remuw", OP_32, 0b111, 0xffff_ffff_9abc_def0),
+    ] {
+        assert_eq!(op(opcode, funct3, MULDIV, a, 0), expected, "{name}");
+    }
+}
+
+#[test]
+fn division_overflow() {
+    let minus_one = u64::MAX;
+    assert_eq!(op(OP, 0b100, MULDIV, i64::MIN as u64, minus_one), i64::MIN as u64, "div");
+    assert_eq!(op(OP, 0b110, MULDIV, i64::MIN as u64, minus_one), 0, "rem");
+    assert_eq!(op(OP_32, 0b100, MULDIV, i32::MIN as u64, minus_one), i32::MIN as i64 as u64, "divw");
+    assert_eq!(op(OP_32, 0b110, MULDIV, i32::MIN as u64, minus_one), 0, "remw");
+}
+
+#[test]
+fn division_signs() {
+    let (seven, minus_two) = (7, -2i64 as u64);
+    assert_eq!(op(OP, 0b100, MULDIV, seven, minus_two), -3i64 as u64, "div");
+    assert_eq!(op(OP, 0b110, MULDIV, seven.wrapping_neg(), 2), -1i64 as u64, "rem");
+    assert_eq!(op(OP, 0b101, MULDIV, minus_two, 2), u64::MAX >> 1, "divu");
+    assert_eq!(op(OP_32, 0b101, MULDIV, minus_two, 2), 0x7fff_ffff, "divuw");
+}
+
+#[test]
+fn high_multiplies() {
+    let minus_two = -2i64 as u64;
+    assert_eq!(op(OP, 0b001, MULDIV, minus_two, 3), u64::MAX, "mulh");
+    assert_eq!(op(OP, 0b001, MULDIV, minus_two, minus_two), 0, "mulh");
+    // rs1 is signed and rs2 isn't
+    assert_eq!(op(OP, 0b010, MULDIV, minus_two, 3), u64::MAX, "mulhsu");
+    assert_eq!(op(OP, 0b010, MULDIV, minus_two, u64::MAX), minus_two, "mulhsu");
+    assert_eq!(op(OP, 0b010, MULDIV, 2, u64::MAX), 1, "mulhsu");
+    assert_eq!(op(OP, 0b011, MULDIV, minus_two, minus_two), minus_two - 2, "mulhu");
+}
+
+#[test]
+fn word_shifts() {
+    let a = 0xffff_ffff_8000_0001;
+    // amounts of 32 and up wrap to their low 5 bits
+    assert_eq!(op(OP_32, 0b001, 0, a, 33), 2, "sllw");
+    assert_eq!(op(OP_32, 0b001, 0, a, 31), 0xffff_ffff_8000_0000, "sllw");
+    assert_eq!(op(OP_32, 0b101, 0, a, 36), 0x0800_0000, "srlw");
+    assert_eq!(op(OP_32, 0b101, 0, a, 0), 0xffff_ffff_8000_0001, "srlw");
+    assert_eq!(op(OP_32, 0b101, 0b0100000, a, 36), 0xffff_ffff_f800_0000, "sraw");
+
+    // bits above 31 never shift in
+    let b = 0x0000_0001_0000_0000 | 0x8000_0000;
+    let op_imm_32 = |funct3: u32, funct7: u32, shamt: u32| {
+        let mut cpu = cpu();
+        cpu.xregs.write(1, b);
+        run(&mut cpu, funct7 << 25 | shamt << 20 | 1 << 15 | funct3 << 12 | 3 << 7 | 0b0011011).unwrap();
+        cpu.xregs.read(3)
+    };
+    assert_eq!(op_imm_32(0b001, 0, 1), 0, "slliw");
+    assert_eq!(op_imm_32(0b101, 0, 4), 0x0800_0000, "srliw");
+    assert_eq!(op_imm_32(0b101, 0b0100000, 4), 0xffff_ffff_f800_0000, "sraiw");
+}
+
+#[test]
+fn compressed_jumps_and_branches() {
+    // encodings from llvm-mc, x8 = s0 and x15 = a5 hold the register tested
+    for (name, inst, s0, a5, offset) in [
+        ("c.j -2048", 0xb001, 0, 0, -2048),
+        ("c.j 2046", 0xaffd, 0, 0, 2046),
+        ("c.j 42", 0xa02d, 0, 0, 42),
+        ("c.beqz s0, -256", 0xd001, 0, 0, -256),
+        ("c.beqz s0, -256 not taken", 0xd001, 1, 0, 2),
+        ("c.beqz s0, 254", 0xcc7d, 0, 0, 254),
+        ("c.bnez a5, 18", 0xeb89, 0, 5, 18),
+        ("c.bnez a5, 18 not taken", 0xeb89, 0, 0, 2),
+    ] {
+        let mut cpu = cpu();
+        cpu.pc = DRAM_START + 0x1000;
+        cpu.xregs.write(8, s0);
+        cpu.xregs.write(15, a5);
+        run(&mut cpu, inst).unwrap();
+        assert_eq!(cpu.pc, (DRAM_START + 0x1000).wrapping_add_signed(offset), "{name}");
+    }
+}
+
+#[test]
+fn compressed_immediates() {
+    let sp = DRAM_START + 0x800;
+    for (name, inst, rd, expected) in [
+        ("c.addi16sp sp, -512", 0x7101, 2, sp - 512),
+        ("c.addi16sp sp, 496", 0x617d, 2, sp + 496),
+        ("c.addi16sp sp, 16", 0x6141, 2, sp + 16),
+        // the immediate's top bit reaches all the way up
+        ("c.lui a0, 0xfffe0", 0x7501, 10, 0xffff_ffff_fffe_0000),
+        ("c.lui a0, 0x1f", 0x657d, 10, 0x1f000),
+        ("c.lui t0, 1", 0x6285, 5, 0x1000),
+    ] {
+        let mut cpu = cpu();
+        cpu.xregs.write(2, sp);
+        run(&mut cpu, inst).unwrap();
+        assert_eq!(cpu.xregs.read(rd), expected, "{name}");
+    }
+}
+
+#[test]
+fn compressed_stack_loads_and_stores() {
+    let sp = DRAM_START + 0x800;
+    let mut cpu = cpu();
+    cpu.xregs.write(2, sp);
+    cpu.bus.write(sp + 252, 0x8000_0001, 32).unwrap();
+    cpu.bus.write(sp + 4, 0x1234_5678, 32).unwrap();
+
+    run(&mut cpu, 0x557e).unwrap(); // c.lwsp a0, 252(sp)
+    assert_eq!(cpu.xregs.read(10), 0xffff_ffff_8000_0001, "c.lwsp sign extends");
+    cpu.pc += 2;
+    run(&mut cpu, 0x4092).unwrap(); // c.lwsp ra, 4(sp)
+    assert_eq!(cpu.xregs.read(1), 0x1234_5678, "c.lwsp");
+
+    cpu.xregs.write(10, 0x0123_4567_89ab_cdef);
+    cpu.xregs.write(9, 0xfedc_ba98_7654_3210);
+    cpu.pc += 2;
+    run(&mut cpu, 0xffaa).unwrap(); // c.sdsp a0, 504(sp)
+    cpu.pc += 2;
+    run(&mut cpu, 0xe426).unwrap(); // c.sdsp s1, 8(sp)
+    assert_eq!(cpu.bus.read(sp + 504, 64).unwrap(), 0x0123_4567_89ab_cdef, "c.sdsp");
+    assert_eq!(cpu.bus.read(sp + 8, 64).unwrap(), 0xfedc_ba98_7654_3210, "c.sdsp");
+
+    cpu.pc += 2;
+    run(&mut cpu, 0x75fe).unwrap(); // c.ldsp a1, 504(sp)
+    assert_eq!(cpu.xregs.read(11), 0x0123_4567_89ab_cdef, "c.ldsp");
+}
+
+#[test]
+fn reserved_compressed_encodings_trap() {
+    for (name, inst) in [
+        ("c.addi4spn with a zero immediate", 0x0010),
+        ("quadrant 0 funct3 100", 0x8000),
+        ("c.addiw to x0", 0x2005),
+        ("c.addi16sp with a zero immediate", 0x6101),
+        ("c.lui with a zero immediate", 0x6081),
+        ("quadrant 1 reserved alu op", 0x9c41),
+        ("c.lwsp to x0", 0x4002),
+        ("c.ldsp to x0", 0x6002),
+        ("c.jr x0", 0x8002),
+    ] {
+        let mut cpu = cpu();
+        assert_eq!(run(&mut cpu, inst), Err(Exception::IllegalInstruction(inst as u64)), "{name}");
+        assert_eq!(cpu.pc, DRAM_START, "{name}");
+    }
+}
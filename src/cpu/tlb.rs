@@ -0,0 +1,77 @@
+const TLB_SIZE: usize = 256;
+
+/// a cached leaf translation, superpages are cached one 4KiB page at a time
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    vpn: u64,
+    ppn: u64,
+    asid: u64,
+    global: bool,
+    /// the page table level of the leaf, used to flush whole superpages
+    level: u64,
+    /// the permission bits of the leaf PTE
+    flags: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TlbStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub struct Tlb {
+    entries: Vec<Option<TlbEntry>>,
+    stats: TlbStats,
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self {
+            entries: vec![None; TLB_SIZE],
+            stats: TlbStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> TlbStats {
+        self.stats
+    }
+
+    /// returns the physical page number and PTE flags for `vpn` if it's cached
+    pub fn lookup(&mut self, vpn: u64, asid: u64) -> Option<(u64, u64)> {
+        match self.entries[vpn as usize % TLB_SIZE] {
+            Some(entry) if entry.vpn == vpn && (entry.global || entry.asid == asid) => {
+                self.stats.hits += 1;
+                Some((entry.ppn, entry.flags))
+            }
+            _ => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, vpn: u64, ppn: u64, asid: u64, global: bool, level: u64, flags: u64) {
+        self.entries[vpn as usize % TLB_SIZE] = Some(TlbEntry { vpn, ppn, asid, global, level, flags });
+    }
+
+    /// SFENCE.VMA semantics, `None` means every address or every address space.
+    /// Global mappings are only flushed when no ASID is given.
+    pub fn flush(&mut self, vpn: Option<u64>, asid: Option<u64>) {
+        for slot in self.entries.iter_mut() {
+            let Some(entry) = slot else { continue };
+
+            let vpn_matches = match vpn {
+                Some(vpn) => entry.vpn >> (9 * entry.level) == vpn >> (9 * entry.level),
+                None => true,
+            };
+            let asid_matches = match asid {
+                Some(asid) => !entry.global && entry.asid == asid,
+                None => true,
+            };
+
+            if vpn_matches && asid_matches {
+                *slot = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::Path};
+
+use qcow2::Qcow2;
+
+mod qcow2;
+
+/// storage behind a block device, offsets and lengths are in bytes
+pub trait Disk {
+    fn len(&self) -> u64;
+
+    fn is_read_only(&self) -> bool;
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+fn read_only_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "disk is read-only")
+}
+
+/// somewhere for a test to put an image, unique to the test
+#[cfg(test)]
+fn scratch_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("riscv-emulator-test-{}-{name}", std::process::id()))
+}
+
+/// opens a raw or qcow2 image, qcow2 images can only be read
+pub fn open(path: &Path, read_only: bool) -> io::Result<Box<dyn Disk>> {
+    if qcow2::is_qcow2(path)? {
+        if !read_only {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "qcow2 images can only be opened read-only or with an overlay"));
+        }
+        return Ok(Box::new(Qcow2::open(path)?));
+    }
+    Ok(Box::new(Raw::open(path, read_only)?))
+}
+
+/// a plain image file
+pub struct Raw {
+    file: File,
+    read_only: bool,
+    len: u64,
+}
+
+impl Raw {
+    pub fn open(path: &Path, read_only: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self { file, read_only, len })
+    }
+}
+
+impl Disk for Raw {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(read_only_error());
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+const OVERLAY_MAGIC: &[u8; 8] = b"RVCOWOVL";
+const OVERLAY_BLOCK_SIZE: u64 = 4096;
+/// the header is the magic followed by the size of the base disk
+const OVERLAY_HEADER_SIZE: u64 = 16;
+
+/// redirects writes away from a base disk into a sparse overlay file.
+///
+/// The overlay file starts with a header and a bitmap of which blocks have been
+/// written, then a block-for-block copy of the disk where only written blocks
+/// take up space.
+pub struct Overlay {
+    base: Box<dyn Disk>,
+    file: File,
+    bitmap: Vec<u8>,
+    data_start: u64,
+}
+
+impl Overlay {
+    /// opens or creates an overlay over `base`, a discarded overlay is deleted
+    /// straight away so nothing is left behind however the emulator exits
+    pub fn open(base: Box<dyn Disk>, path: &Path, discard: bool) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(discard).open(path)?;
+        if discard {
+            fs::remove_file(path)?;
+        }
+
+        let blocks = base.len().div_ceil(OVERLAY_BLOCK_SIZE);
+        let bitmap_len = blocks.div_ceil(8);
+        let data_start = (OVERLAY_HEADER_SIZE + bitmap_len).next_multiple_of(OVERLAY_BLOCK_SIZE);
+
+        let mut bitmap = vec![0; bitmap_len as usize];
+        if file.metadata()?.len() == 0 {
+            let mut header = OVERLAY_MAGIC.to_vec();
+            header.extend_from_slice(&base.len().to_le_bytes());
+            file.write_all(&header)?;
+            file.write_all(&bitmap)?;
+        } else {
+            let mut header = [0; OVERLAY_HEADER_SIZE as usize];
+            file.read_exact(&mut header)?;
+            if &header[0..8] != OVERLAY_MAGIC || u64::from_le_bytes(header[8..16].try_into().unwrap()) != base.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "overlay doesn't belong to this disk"));
+            }
+            file.read_exact(&mut bitmap)?;
+        }
+
+        Ok(Self { base, file, bitmap, data_start })
+    }
+
+    fn is_written(&self, block: u64) -> bool {
+        self.bitmap[(block / 8) as usize] & (1 << (block % 8)) != 0
+    }
+
+    fn block_len(&self, block: u64) -> usize {
+        (self.base.len() - block * OVERLAY_BLOCK_SIZE).min(OVERLAY_BLOCK_SIZE) as usize
+    }
+
+    /// runs `f` over each block an access touches with the block number, the
+    /// offset within the block and the range of the buffer that falls in it
+    fn for_each_block(offset: u64, len: usize, mut f: impl FnMut(u64, u64, std::ops::Range<usize>) -> io::Result<()>) -> io::Result<()> {
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let block = position / OVERLAY_BLOCK_SIZE;
+            let within = position % OVERLAY_BLOCK_SIZE;
+            let n = ((OVERLAY_BLOCK_SIZE - within) as usize).min(len - done);
+            f(block, within, done..done + n)?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+impl Disk for Overlay {
+    fn len(&self) -> u64 {
+        self.base.len()
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        Self::for_each_block(offset, buf.len(), |block, within, range| {
+            if self.is_written(block) {
+                self.file.seek(SeekFrom::Start(self.data_start + block * OVERLAY_BLOCK_SIZE + within))?;
+                self.file.read_exact(&mut buf[range])
+            } else {
+                self.base.read_at(block * OVERLAY_BLOCK_SIZE + within, &mut buf[range])
+            }
+        })
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        Self::for_each_block(offset, data.len(), |block, within, range| {
+            let block_start = self.data_start + block * OVERLAY_BLOCK_SIZE;
+            if self.is_written(block) {
+                self.file.seek(SeekFrom::Start(block_start + within))?;
+                return self.file.write_all(&data[range]);
+            }
+
+            // the first write to a block copies it up from the base
+            let mut contents = vec![0; self.block_len(block)];
+            self.base.read_at(block * OVERLAY_BLOCK_SIZE, &mut contents)?;
+            contents[within as usize..within as usize + range.len()].copy_from_slice(&data[range]);
+            self.file.seek(SeekFrom::Start(block_start))?;
+            self.file.write_all(&contents)?;
+
+            let byte = (block / 8) as usize;
+            self.bitmap[byte] |= 1 << (block % 8);
+            self.file.seek(SeekFrom::Start(OVERLAY_HEADER_SIZE + byte as u64))?;
+            self.file.write_all(&self.bitmap[byte..byte + 1])
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_keeps_writes_off_the_base() {
+        // two and a bit blocks of a pattern
+        let contents: Vec<u8> = (0..2 * OVERLAY_BLOCK_SIZE + 100).map(|i| i as u8).collect();
+        let base_path = scratch_path("overlay-base");
+        let overlay_path = scratch_path("overlay");
+        fs::write(&base_path, &contents).unwrap();
+        let _ = fs::remove_file(&overlay_path);
+
+        let mut overlay = Overlay::open(Box::new(Raw::open(&base_path, true).unwrap()), &overlay_path, false).unwrap();
+        assert_eq!(overlay.len(), contents.len() as u64);
+        // across the boundary between the first two blocks
+        let start = OVERLAY_BLOCK_SIZE - 3;
+        overlay.write_at(start, b"written").unwrap();
+
+        let mut expected = contents.clone();
+        expected[start as usize..start as usize + 7].copy_from_slice(b"written");
+        let mut buf = vec![0; contents.len()];
+        overlay.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, expected);
+        assert_eq!(fs::read(&base_path).unwrap(), contents);
+
+        // reopening picks the writes back up and the last block still comes from the base
+        drop(overlay);
+        let mut overlay = Overlay::open(Box::new(Raw::open(&base_path, true).unwrap()), &overlay_path, false).unwrap();
+        overlay.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, expected);
+        let mut tail = [0; 100];
+        overlay.read_at(2 * OVERLAY_BLOCK_SIZE, &mut tail).unwrap();
+        assert_eq!(tail[..], contents[2 * OVERLAY_BLOCK_SIZE as usize..]);
+
+        fs::remove_file(&base_path).unwrap();
+        fs::remove_file(&overlay_path).unwrap();
+    }
+}
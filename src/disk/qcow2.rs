@@ -0,0 +1,302 @@
+use std::{collections::HashMap, fs::File, io::{self, Read, Seek, SeekFrom}, path::{Path, PathBuf}};
+
+use crate::inflate::inflate;
+
+use super::{Disk, read_only_error};
+
+const MAGIC: &[u8; 4] = b"QFI\xfb";
+
+mod incompatible {
+    pub const DIRTY: u64 = 1 << 0;
+    pub const COMPRESSION_TYPE: u64 = 1 << 3;
+}
+
+/// bits 9-55 of L1 and L2 entries hold host offsets
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_COMPRESSED: u64 = 1 << 62;
+/// version 3 can mark a cluster as reading as zero
+const L2_ZERO: u64 = 1 << 0;
+
+const COMPRESSED_SECTOR_SIZE: u64 = 512;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("qcow2: {message}"))
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+pub fn is_qcow2(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// a read-only qcow2 (version 2 or 3) image, unallocated clusters come from
+/// the backing file if there is one
+pub struct Qcow2 {
+    file: File,
+    len: u64,
+    cluster_bits: u32,
+    l1: Vec<u64>,
+    /// L2 tables by host offset, loaded as they're first needed
+    l2_cache: HashMap<u64, Vec<u64>>,
+    backing: Option<Box<dyn Disk>>,
+}
+
+impl Qcow2 {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header = [0; 104];
+        file.read_exact(&mut header[..72])?;
+        if &header[0..4] != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+
+        let version = be_u32(&header, 4);
+        let backing_file_offset = be_u64(&header, 8);
+        let backing_file_size = be_u32(&header, 16);
+        let cluster_bits = be_u32(&header, 20);
+        let len = be_u64(&header, 24);
+        let crypt_method = be_u32(&header, 32);
+        let l1_size = be_u32(&header, 36);
+        let l1_table_offset = be_u64(&header, 40);
+
+        match version {
+            2 => {}
+            3 => {
+                file.read_exact(&mut header[72..104])?;
+                // dirty images just have stale refcounts which reading doesn't care about
+                let unknown = be_u64(&header, 72) & !(incompatible::DIRTY | incompatible::COMPRESSION_TYPE);
+                if unknown != 0 {
+                    return Err(invalid(&format!("unsupported incompatible features {unknown:#x}")));
+                }
+                if be_u64(&header, 72) & incompatible::COMPRESSION_TYPE != 0 {
+                    file.seek(SeekFrom::Start(104))?;
+                    let mut compression_type = [0];
+                    file.read_exact(&mut compression_type)?;
+                    if compression_type[0] != 0 {
+                        return Err(invalid("only zlib compression is supported"));
+                    }
+                }
+            }
+            _ => return Err(invalid(&format!("unsupported version {version}"))),
+        }
+        if crypt_method != 0 {
+            return Err(invalid("encrypted images aren't supported"));
+        }
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid("bad cluster size"));
+        }
+
+        let mut raw = vec![0; l1_size as usize * 8];
+        file.seek(SeekFrom::Start(l1_table_offset))?;
+        file.read_exact(&mut raw)?;
+        let l1 = raw.chunks_exact(8).map(|entry| u64::from_be_bytes(entry.try_into().unwrap())).collect();
+
+        let backing = if backing_file_offset != 0 {
+            let mut name = vec![0; backing_file_size as usize];
+            file.seek(SeekFrom::Start(backing_file_offset))?;
+            file.read_exact(&mut name)?;
+            let name = PathBuf::from(String::from_utf8(name).map_err(|_| invalid("backing file name isn't UTF-8"))?);
+            // relative backing files are relative to the image that names them
+            let name = match path.parent() {
+                Some(parent) if name.is_relative() => parent.join(name),
+                _ => name,
+            };
+            Some(super::open(&name, true)?)
+        } else {
+            None
+        };
+
+        Ok(Self { file, len, cluster_bits, l1, l2_cache: HashMap::new(), backing })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// the L2 entry covering guest `offset`, 0 if nothing is allocated there
+    fn l2_entry(&mut self, offset: u64) -> io::Result<u64> {
+        let l2_entries = self.cluster_size() / 8;
+        let cluster = offset >> self.cluster_bits;
+        let Some(&l1_entry) = self.l1.get((cluster / l2_entries) as usize) else {
+            return Ok(0);
+        };
+        let l2_offset = l1_entry & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+
+        if !self.l2_cache.contains_key(&l2_offset) {
+            let mut raw = vec![0; self.cluster_size() as usize];
+            self.file.seek(SeekFrom::Start(l2_offset))?;
+            self.file.read_exact(&mut raw)?;
+            let table = raw.chunks_exact(8).map(|entry| u64::from_be_bytes(entry.try_into().unwrap())).collect();
+            self.l2_cache.insert(l2_offset, table);
+        }
+        Ok(self.l2_cache[&l2_offset][(cluster % l2_entries) as usize])
+    }
+
+    /// reads part of one guest cluster
+    fn read_cluster(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let entry = self.l2_entry(offset)?;
+        let within = offset & (self.cluster_size() - 1);
+
+        if entry & L2_COMPRESSED != 0 {
+            // the descriptor packs the host offset below a count of extra 512 byte sectors
+            let offset_bits = 62 - (self.cluster_bits - 8);
+            let host_offset = entry & ((1 << offset_bits) - 1);
+            let sectors = ((entry >> offset_bits) & ((1 << (self.cluster_bits - 8)) - 1)) + 1;
+            let compressed_len = sectors * COMPRESSED_SECTOR_SIZE - (host_offset % COMPRESSED_SECTOR_SIZE);
+
+            let mut compressed = Vec::new();
+            self.file.seek(SeekFrom::Start(host_offset))?;
+            // the last compressed cluster can run past the end of the file
+            (&mut self.file).take(compressed_len).read_to_end(&mut compressed)?;
+            let cluster = inflate(&compressed, self.cluster_size() as usize).ok_or_else(|| invalid("corrupt compressed cluster"))?;
+            let data = cluster.get(within as usize..within as usize + buf.len()).ok_or_else(|| invalid("short compressed cluster"))?;
+            buf.copy_from_slice(data);
+            return Ok(());
+        }
+
+        let host_offset = entry & OFFSET_MASK;
+        if entry & L2_ZERO != 0 {
+            buf.fill(0);
+        } else if host_offset != 0 {
+            self.file.seek(SeekFrom::Start(host_offset + within))?;
+            self.file.read_exact(buf)?;
+        } else if let Some(backing) = &mut self.backing {
+            // backing files can be smaller than the image on top of them
+            let available = backing.len().saturating_sub(offset).min(buf.len() as u64) as usize;
+            backing.read_at(offset, &mut buf[..available])?;
+            buf[available..].fill(0);
+        } else {
+            buf.fill(0);
+        }
+        Ok(())
+    }
+}
+
+impl Disk for Qcow2 {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = position & (self.cluster_size() - 1);
+            let n = ((self.cluster_size() - within) as usize).min(buf.len() - done);
+            self.read_cluster(position, &mut buf[done..done + n])?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, _offset: u64, _data: &[u8]) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::disk::scratch_path;
+
+    const CLUSTER: usize = 512;
+
+    /// a 4 cluster version 2 image with 512 byte clusters: the header, the L1
+    /// table, one L2 table, an allocated cluster and a compressed one
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; 5 * CLUSTER];
+        image[0..4].copy_from_slice(MAGIC);
+        image[4..8].copy_from_slice(&2u32.to_be_bytes());
+        image[20..24].copy_from_slice(&9u32.to_be_bytes());
+        image[24..32].copy_from_slice(&(4 * CLUSTER as u64).to_be_bytes());
+        image[36..40].copy_from_slice(&1u32.to_be_bytes());
+        image[40..48].copy_from_slice(&(CLUSTER as u64).to_be_bytes());
+
+        image[CLUSTER..CLUSTER + 8].copy_from_slice(&(2 * CLUSTER as u64).to_be_bytes());
+        // guest cluster 0 stays unallocated
+        let l2 = 2 * CLUSTER;
+        image[l2 + 8..l2 + 16].copy_from_slice(&(3 * CLUSTER as u64).to_be_bytes());
+        // one sector of deflated "qcow" repeated, so no extra sectors
+        image[l2 + 16..l2 + 24].copy_from_slice(&(L2_COMPRESSED | (4 * CLUSTER as u64)).to_be_bytes());
+
+        image[3 * CLUSTER..4 * CLUSTER].fill(0xaa);
+        let compressed = [0x2b, 0x4c, 0xce, 0x2f, 0x2f, 0x1c, 0xc5, 0x23, 0x16, 0x03, 0x00];
+        image[4 * CLUSTER..4 * CLUSTER + compressed.len()].copy_from_slice(&compressed);
+        image
+    }
+
+    #[test]
+    fn reads_each_kind_of_cluster() {
+        let path = scratch_path("qcow2");
+        fs::write(&path, image()).unwrap();
+        assert!(is_qcow2(&path).unwrap());
+        let mut disk = Qcow2::open(&path).unwrap();
+        assert_eq!(disk.len(), 4 * CLUSTER as u64);
+
+        let mut buf = vec![0xff; 4 * CLUSTER];
+        disk.read_at(0, &mut buf).unwrap();
+        assert!(buf[..CLUSTER].iter().all(|&b| b == 0), "unallocated");
+        assert!(buf[CLUSTER..2 * CLUSTER].iter().all(|&b| b == 0xaa), "allocated");
+        assert_eq!(buf[2 * CLUSTER..3 * CLUSTER], b"qcow".repeat(CLUSTER / 4)[..], "compressed");
+        // and so is the last cluster
+        assert!(buf[3 * CLUSTER..].iter().all(|&b| b == 0));
+
+        // reads within a cluster
+        let mut part = [0; 6];
+        disk.read_at(2 * CLUSTER as u64 + 2, &mut part).unwrap();
+        assert_eq!(&part, b"owqcow");
+        assert!(disk.write_at(0, &part).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let path = scratch_path("qcow2-bad");
+        let mut bad = image();
+        bad[4..8].copy_from_slice(&4u32.to_be_bytes());
+        fs::write(&path, &bad).unwrap();
+        assert!(Qcow2::open(&path).is_err(), "version");
+
+        let mut bad = image();
+        bad[20..24].copy_from_slice(&30u32.to_be_bytes());
+        fs::write(&path, &bad).unwrap();
+        assert!(Qcow2::open(&path).is_err(), "cluster size");
+
+        // a reserved block type where the compressed cluster should be
+        let mut bad = image();
+        bad[4 * CLUSTER] = 0x07;
+        fs::write(&path, &bad).unwrap();
+        let mut buf = [0; CLUSTER];
+        assert!(Qcow2::open(&path).unwrap().read_at(2 * CLUSTER as u64, &mut buf).is_err(), "compressed cluster");
+
+        fs::write(&path, b"QFI").unwrap();
+        assert!(!is_qcow2(&path).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
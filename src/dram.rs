@@ -1,18 +1,27 @@
 use crate::exception::Exception;
 
-pub const DRAM_SIZE: u64 = 1024 * 1024 * 1024;
-
 pub struct Dram {
     dram: Vec<u8>
 }
 
 impl Dram {
-    pub fn new() -> Self {
-        Self { dram: vec![0;DRAM_SIZE as usize] }
+    pub fn new(size: u64) -> Self {
+        Self { dram: vec![0;size as usize] }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.dram.len() as u64
+    }
+
+    /// the `len` bytes at `addr`, devices use this to DMA into guest memory
+    pub fn slice(&self, addr: u64, len: usize) -> Option<&[u8]> {
+        let start = usize::try_from(addr).ok()?;
+        self.dram.get(start..start.checked_add(len)?)
     }
 
-    pub fn load(&mut self, data: &[u8]) {
-        self.dram[..data.len()].copy_from_slice(data);
+    pub fn slice_mut(&mut self, addr: u64, len: usize) -> Option<&mut [u8]> {
+        let start = usize::try_from(addr).ok()?;
+        self.dram.get_mut(start..start.checked_add(len)?)
     }
 
     pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
@@ -0,0 +1,197 @@
+use std::{collections::HashMap, io};
+
+use crate::bus::Bus;
+
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const MACHINE_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHN_UNDEF: u16 = 0;
+
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+/// a PT_LOAD segment, anything past the end of `data` up to `size` is zeros
+pub struct Segment {
+    pub addr: u64,
+    /// where the segment is linked to run, which paging may map to `addr`
+    pub vaddr: u64,
+    pub data: Vec<u8>,
+    pub size: u64,
+}
+
+/// a little endian RISC-V ELF64 executable
+pub struct Elf {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    symbols: HashMap<String, u64>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> io::Result<u16> {
+    let bytes = bytes.get(offset..offset.saturating_add(2)).ok_or_else(|| invalid("truncated ELF"))?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> io::Result<u32> {
+    let bytes = bytes.get(offset..offset.saturating_add(4)).ok_or_else(|| invalid("truncated ELF"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> io::Result<u64> {
+    let bytes = bytes.get(offset..offset.saturating_add(8)).ok_or_else(|| invalid("truncated ELF"))?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// `len` bytes at `offset`, both straight out of a header
+fn range(bytes: &[u8], offset: u64, len: u64) -> io::Result<&[u8]> {
+    let start = usize::try_from(offset).map_err(|_| invalid("truncated ELF"))?;
+    let len = usize::try_from(len).map_err(|_| invalid("truncated ELF"))?;
+    bytes.get(start..start.checked_add(len).ok_or_else(|| invalid("truncated ELF"))?).ok_or_else(|| invalid("truncated ELF"))
+}
+
+/// whether `bytes` start like an ELF file of any kind
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+impl Elf {
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        if !is_elf(bytes) {
+            return Err(invalid("not an ELF file"));
+        }
+        if bytes.get(4) != Some(&CLASS_64) || bytes.get(5) != Some(&DATA_LITTLE_ENDIAN) {
+            return Err(invalid("not a little endian ELF64 file"));
+        }
+        if u16_at(bytes, 18)? != MACHINE_RISCV {
+            return Err(invalid("not a RISC-V ELF file"));
+        }
+
+        let entry = u64_at(bytes, 24)?;
+        let phoff = u64_at(bytes, 32)? as usize;
+        let shoff = u64_at(bytes, 40)? as usize;
+        let phnum = u16_at(bytes, 56)? as usize;
+        let shnum = u16_at(bytes, 60)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let header = phoff.saturating_add(i * PROGRAM_HEADER_SIZE);
+            if u32_at(bytes, header)? != PT_LOAD {
+                continue;
+            }
+            let offset = u64_at(bytes, header + 8)?;
+            let vaddr = u64_at(bytes, header + 16)?;
+            // the physical address, which is where a kernel linked to run at a
+            // virtual one still has to be put
+            let addr = u64_at(bytes, header + 24)?;
+            let file_size = u64_at(bytes, header + 32)?;
+            let size = u64_at(bytes, header + 40)?;
+            if file_size > size {
+                return Err(invalid("segment bigger in the file than in memory"));
+            }
+            segments.push(Segment { addr, vaddr, data: range(bytes, offset, file_size)?.to_vec(), size });
+        }
+
+        let mut symbols = HashMap::new();
+        for i in 0..shnum {
+            let header = shoff.saturating_add(i * SECTION_HEADER_SIZE);
+            if u32_at(bytes, header.saturating_add(4))? != SHT_SYMTAB {
+                continue;
+            }
+            let table = range(bytes, u64_at(bytes, header + 24)?, u64_at(bytes, header + 32)?)?;
+            let strings_header = shoff.saturating_add(u32_at(bytes, header + 40)? as usize * SECTION_HEADER_SIZE);
+            let strings = range(bytes, u64_at(bytes, strings_header.saturating_add(24))?, u64_at(bytes, strings_header.saturating_add(32))?)?;
+
+            for symbol in table.chunks_exact(SYMBOL_SIZE) {
+                let name = u32_at(symbol, 0)? as usize;
+                if name == 0 || u16_at(symbol, 6)? == SHN_UNDEF {
+                    continue;
+                }
+                let Some(name) = strings.get(name..) else { continue };
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                symbols.insert(String::from_utf8_lossy(name).into_owned(), u64_at(symbol, 8)?);
+            }
+        }
+
+        Ok(Self { entry, segments, symbols })
+    }
+
+    /// where the entry point ends up in memory, the entry is a virtual address
+    /// so it moves with the segment it's in
+    pub fn physical_entry(&self) -> u64 {
+        self.segments.iter()
+            .find(|segment| self.entry.wrapping_sub(segment.vaddr) < segment.size)
+            .map_or(self.entry, |segment| segment.addr.wrapping_add(self.entry - segment.vaddr))
+    }
+
+    /// the address of a symbol, if the file wasn't stripped of it
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols.get(name).copied()
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.symbols.iter().map(|(name, &addr)| (name.as_str(), addr))
+    }
+
+    /// copies every segment to its physical address, zeroing whatever the file doesn't fill
+    pub fn load(&self, bus: &mut Bus) -> io::Result<()> {
+        for segment in &self.segments {
+            bus.load(segment.addr, &segment.data, segment.size)
+                .map_err(|_| invalid(&format!("segment at {:#x} isn't in memory", segment.addr)))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an ELF with one PT_LOAD segment per (vaddr, paddr), each 0x100 bytes of nops
+    fn build(entry: u64, segments: &[(u64, u64)]) -> Vec<u8> {
+        let mut bytes = vec![0; 64];
+        bytes[0..4].copy_from_slice(MAGIC);
+        bytes[4] = CLASS_64;
+        bytes[5] = DATA_LITTLE_ENDIAN;
+        bytes[18..20].copy_from_slice(&MACHINE_RISCV.to_le_bytes());
+        bytes[24..32].copy_from_slice(&entry.to_le_bytes());
+        bytes[32..40].copy_from_slice(&64u64.to_le_bytes());
+        bytes[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        let data_start = 64 + segments.len() * PROGRAM_HEADER_SIZE;
+        for (i, &(vaddr, paddr)) in segments.iter().enumerate() {
+            let mut header = [0; PROGRAM_HEADER_SIZE];
+            header[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            header[8..16].copy_from_slice(&((data_start + i * 0x100) as u64).to_le_bytes());
+            header[16..24].copy_from_slice(&vaddr.to_le_bytes());
+            header[24..32].copy_from_slice(&paddr.to_le_bytes());
+            header[32..40].copy_from_slice(&0x100u64.to_le_bytes());
+            header[40..48].copy_from_slice(&0x100u64.to_le_bytes());
+            bytes.extend_from_slice(&header);
+        }
+        for _ in segments {
+            bytes.extend_from_slice(&0x0001u16.to_le_bytes().repeat(0x80));
+        }
+        bytes
+    }
+
+    #[test]
+    fn entry_moves_with_its_segment() {
+        let segments = [(0xffff_ffff_8000_0000, 0x8020_0000), (0xffff_ffff_8000_1000, 0x8030_0000)];
+        let elf = Elf::parse(&build(0xffff_ffff_8000_1010, &segments)).unwrap();
+        assert_eq!(elf.entry, 0xffff_ffff_8000_1010);
+        assert_eq!(elf.physical_entry(), 0x8030_0010);
+        assert_eq!(elf.segments[0].addr, 0x8020_0000);
+
+        // an entry outside every segment is left alone, as is one linked where it's loaded
+        assert_eq!(Elf::parse(&build(0x1234, &segments)).unwrap().physical_entry(), 0x1234);
+        assert_eq!(Elf::parse(&build(0x8000_0004, &[(0x8000_0000, 0x8000_0000)])).unwrap().physical_entry(), 0x8000_0004);
+    }
+}
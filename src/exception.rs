@@ -1,32 +1,59 @@
 #![allow(unused)]
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Exception {
-    IllegalInstruction(String),
-    LoadAddressMisaligned,
-    LoadAccessFault,
-    StoreAddressMisaligned,
-    StoreAccessFault,
+    InstructionAccessFault(u64),
+    /// holds the encoding of the instruction
+    IllegalInstruction(u64),
+    Breakpoint,
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
     ECallFromU,
     ECallFromS,
     ECallFromM,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
     HardwareError,
 }
 
 impl Exception {
     pub fn to_code(&self) -> u64 {
         match self {
+            Exception::InstructionAccessFault(_) => 1,
             Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint => 3,
 
-            Exception::LoadAddressMisaligned => 4,
-            Exception::LoadAccessFault => 5,
-            Exception::StoreAddressMisaligned => 6,
-            Exception::StoreAccessFault => 7,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
             Exception::ECallFromU => 8,
             Exception::ECallFromS => 9,
             Exception::ECallFromM => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
 
             Exception::HardwareError => 19,
         }
     }
+
+    /// the value written to mtval when this exception is taken
+    pub fn tval(&self) -> u64 {
+        match self {
+            Exception::InstructionAccessFault(addr) |
+            Exception::LoadAddressMisaligned(addr) |
+            Exception::LoadAccessFault(addr) |
+            Exception::StoreAddressMisaligned(addr) |
+            Exception::StoreAccessFault(addr) |
+            Exception::InstructionPageFault(addr) |
+            Exception::LoadPageFault(addr) |
+            Exception::StorePageFault(addr) => *addr,
+            Exception::IllegalInstruction(inst) => *inst,
+            _ => 0,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,558 @@
+use std::{collections::HashMap, io};
+
+const MAGIC: u32 = 0xd00dfeed;
+const VERSION: u32 = 17;
+/// the oldest version a reader of ours has to understand
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+/// an address and a size, the map ends with one that's all zeros
+const RESERVATION_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// what the kernel is told in the device tree's /chosen
+pub struct Chosen {
+    pub bootargs: String,
+    /// start and end
+    pub initrd: Option<(u64, u64)>,
+}
+
+/// writes a flattened device tree one node at a time, every node begun has to be ended
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    /// where each property name already is in `strings`
+    names: HashMap<String, u32>,
+    /// memory the kernel has to keep its hands off, as addresses and sizes
+    reservations: Vec<(u64, u64)>,
+}
+
+impl Fdt {
+    pub fn new() -> Self {
+        Self { structure: Vec::new(), strings: Vec::new(), names: HashMap::new(), reservations: Vec::new() }
+    }
+
+    pub fn reserve(&mut self, addr: u64, size: u64) {
+        self.reservations.push((addr, size));
+    }
+
+    fn pad(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    /// the root node is named ""
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+    }
+
+    pub fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let offset = match self.names.get(name) {
+            Some(&offset) => offset,
+            None => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend_from_slice(name.as_bytes());
+                self.strings.push(0);
+                self.names.insert(name.to_string(), offset);
+                offset
+            }
+        };
+        self.token(FDT_PROP);
+        self.structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.structure.extend_from_slice(&offset.to_be_bytes());
+        self.structure.extend_from_slice(value);
+        self.pad();
+    }
+
+    /// a property that's there or not, like interrupt-controller
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u32s(&mut self, name: &str, values: &[u32]) {
+        let value: Vec<u8> = values.iter().flat_map(|value| value.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    /// each value takes two cells, which is what reg needs with #address-cells
+    /// and #size-cells both 2
+    pub fn property_u64s(&mut self, name: &str, values: &[u64]) {
+        let value: Vec<u8> = values.iter().flat_map(|value| value.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let value: Vec<u8> = values.iter().flat_map(|value| value.bytes().chain([0])).collect();
+        self.property(name, &value);
+    }
+
+    /// the blob, with the header and the memory reservation map in front
+    pub fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+
+        let structure_offset = HEADER_SIZE + (self.reservations.len() + 1) * RESERVATION_SIZE;
+        let strings_offset = structure_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            MAGIC,
+            total_size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            HEADER_SIZE as u32,
+            VERSION,
+            LAST_COMPATIBLE_VERSION,
+            // boot_cpuid_phys
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        for (addr, size) in self.reservations.iter().chain([&(0, 0)]) {
+            blob.extend_from_slice(&addr.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// a node of a device tree read back from a blob, properties are kept as
+/// they were and in the same order
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<(String, Vec<u8>)>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    fn new(name: &str) -> Self {
+        Self { name: name.to_string(), properties: Vec::new(), children: Vec::new() }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_slice())
+    }
+
+    /// the first string of a string or string list property
+    pub fn string(&self, name: &str) -> Option<&str> {
+        let value = self.property(name)?;
+        std::str::from_utf8(&value[..value.iter().position(|&b| b == 0).unwrap_or(value.len())]).ok()
+    }
+
+    pub fn u32(&self, name: &str) -> Option<u32> {
+        Some(u32::from_be_bytes(self.property(name)?.try_into().ok()?))
+    }
+
+    /// a one or two cell property, the way linux,initrd-start can be either
+    fn u64(&self, name: &str) -> Option<u64> {
+        let value = self.property(name)?;
+        match value.len() {
+            4 => Some(u32::from_be_bytes(value.try_into().unwrap()) as u64),
+            8 => Some(u64::from_be_bytes(value.try_into().unwrap())),
+            _ => None,
+        }
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn set_property(&mut self, name: &str, value: Vec<u8>) {
+        match self.properties.iter_mut().find(|(n, _)| n == name) {
+            Some((_, old)) => *old = value,
+            None => self.properties.push((name.to_string(), value)),
+        }
+    }
+
+    fn remove_property(&mut self, name: &str) {
+        self.properties.retain(|(n, _)| n != name);
+    }
+
+    fn write(&self, fdt: &mut Fdt) {
+        fdt.begin_node(&self.name);
+        for (name, value) in &self.properties {
+            fdt.property(name, value);
+        }
+        for child in &self.children {
+            child.write(fdt);
+        }
+        fdt.end_node();
+    }
+}
+
+/// a range of the CPU's address space a node says something is at
+pub struct Region {
+    /// the node's full path, like /soc/serial@10000000
+    pub path: String,
+    pub addr: u64,
+    pub size: u64,
+    /// from a node with device_type "memory" rather than a device
+    pub memory: bool,
+}
+
+/// a device tree blob that came from somewhere else, parsed so it can be
+/// checked against the machine and have /chosen changed
+pub struct DeviceTree {
+    pub root: Node,
+    reservations: Vec<(u64, u64)>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> io::Result<u32> {
+    let bytes = bytes.get(offset..offset.saturating_add(4)).ok_or_else(|| invalid("truncated device tree"))?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> io::Result<u64> {
+    let bytes = bytes.get(offset..offset.saturating_add(8)).ok_or_else(|| invalid("truncated device tree"))?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// the nul terminated string at `offset`
+fn string_at(bytes: &[u8], offset: usize) -> io::Result<&str> {
+    let rest = bytes.get(offset..).ok_or_else(|| invalid("truncated device tree"))?;
+    let len = rest.iter().position(|&b| b == 0).ok_or_else(|| invalid("unterminated string in device tree"))?;
+    std::str::from_utf8(&rest[..len]).map_err(|_| invalid("device tree name isn't UTF-8"))
+}
+
+/// a number made of `count` big endian cells off the front of `cells`, and what's left
+fn take_cells(cells: &[u8], count: u32) -> Option<(u64, &[u8])> {
+    let len = count as usize * 4;
+    if cells.len() < len {
+        return None;
+    }
+    let (number, rest) = cells.split_at(len);
+    // anything above 64 bits can't be an address here anyway
+    let value = number.chunks_exact(4).fold(0u64, |value, cell| value << 32 | u32::from_be_bytes(cell.try_into().unwrap()) as u64);
+    Some((value, rest))
+}
+
+/// where an address a node's children use is in the CPU's address space, as
+/// (child address, CPU address, size) windows
+type Translation = Vec<(u64, u64, u64)>;
+
+/// `None` if no window has `addr` or it'd land past the end of the address space
+fn translate(translation: &Translation, addr: u64) -> Option<u64> {
+    translation
+        .iter()
+        .find(|&&(child, _, size)| addr.wrapping_sub(child) < size)
+        .and_then(|&(child, cpu, _)| cpu.checked_add(addr - child))
+}
+
+impl DeviceTree {
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        if u32_at(bytes, 0)? != MAGIC {
+            return Err(invalid("not a device tree blob"));
+        }
+        let total_size = u32_at(bytes, 4)? as usize;
+        let structure_offset = u32_at(bytes, 8)? as usize;
+        let strings_offset = u32_at(bytes, 12)? as usize;
+        let reservations_offset = u32_at(bytes, 16)? as usize;
+        if u32_at(bytes, 24)? > VERSION {
+            return Err(invalid("device tree version too new"));
+        }
+        let bytes = bytes.get(..total_size).ok_or_else(|| invalid("truncated device tree"))?;
+        let strings = bytes.get(strings_offset..).ok_or_else(|| invalid("truncated device tree"))?;
+
+        let mut reservations = Vec::new();
+        let mut offset = reservations_offset;
+        loop {
+            let (addr, size) = (u64_at(bytes, offset)?, u64_at(bytes, offset + 8)?);
+            if addr == 0 && size == 0 {
+                break;
+            }
+            reservations.push((addr, size));
+            offset += RESERVATION_SIZE;
+        }
+
+        // every node begun is on the stack until it ends, the root is the last to
+        let mut stack: Vec<Node> = Vec::new();
+        let mut root = None;
+        let mut offset = structure_offset;
+        loop {
+            let token = u32_at(bytes, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = string_at(bytes, offset)?;
+                    offset = (offset + name.len() + 1).next_multiple_of(4);
+                    if root.is_some() {
+                        return Err(invalid("more than one root node in device tree"));
+                    }
+                    stack.push(Node::new(name));
+                }
+                FDT_END_NODE => {
+                    let node = stack.pop().ok_or_else(|| invalid("unbalanced nodes in device tree"))?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => root = Some(node),
+                    }
+                }
+                FDT_PROP => {
+                    let len = u32_at(bytes, offset)? as usize;
+                    let name = string_at(strings, u32_at(bytes, offset + 4)? as usize)?;
+                    let value = bytes.get(offset + 8..offset + 8 + len).ok_or_else(|| invalid("truncated device tree"))?;
+                    let node = stack.last_mut().ok_or_else(|| invalid("property outside a node in device tree"))?;
+                    node.properties.push((name.to_string(), value.to_vec()));
+                    offset = (offset + 8 + len).next_multiple_of(4);
+                }
+                FDT_NOP => {}
+                FDT_END => break,
+                _ => return Err(invalid(&format!("bad token {token:#x} in device tree"))),
+            }
+        }
+        if !stack.is_empty() {
+            return Err(invalid("unbalanced nodes in device tree"));
+        }
+        let root = root.ok_or_else(|| invalid("no root node in device tree"))?;
+        Ok(Self { root, reservations })
+    }
+
+    /// every range of the CPU's address space the tree puts memory or a device
+    /// at, leaving out disabled nodes and ones under a bus that isn't mapped
+    pub fn regions(&self) -> Vec<Region> {
+        let mut regions = Vec::new();
+        // the root's children use CPU addresses as they are
+        Self::node_regions(&self.root, "", &vec![(0, 0, u64::MAX)], &mut regions);
+        regions
+    }
+
+    /// the regions of `node`'s children, which use `translation` from their addresses to the CPU's
+    fn node_regions(node: &Node, path: &str, translation: &Translation, regions: &mut Vec<Region>) {
+        // the defaults when a node doesn't say
+        let address_cells = node.u32("#address-cells").unwrap_or(2);
+        let size_cells = node.u32("#size-cells").unwrap_or(1);
+
+        for child in &node.children {
+            if child.string("status").is_some_and(|status| status != "okay" && status != "ok") {
+                continue;
+            }
+            let child_path = format!("{path}/{}", child.name);
+
+            let mut reg = child.property("reg").unwrap_or_default();
+            while let Some((addr, rest)) = take_cells(reg, address_cells)
+                && let Some((size, rest)) = take_cells(rest, size_cells)
+            {
+                reg = rest;
+                if size_cells == 0 {
+                    // an ID like a hart's rather than an address
+                    break;
+                }
+                if let Some(addr) = translate(translation, addr) {
+                    let memory = child.string("device_type") == Some("memory");
+                    regions.push(Region { path: child_path.clone(), addr, size, memory });
+                }
+            }
+
+            // without ranges the child's children aren't in the CPU's address space at all
+            let Some(ranges) = child.property("ranges") else { continue };
+            let child_translation = if ranges.is_empty() {
+                translation.clone()
+            } else {
+                let child_address_cells = child.u32("#address-cells").unwrap_or(2);
+                let child_size_cells = child.u32("#size-cells").unwrap_or(1);
+                let mut windows = Vec::new();
+                let mut ranges = ranges;
+                while let Some((child_addr, rest)) = take_cells(ranges, child_address_cells)
+                    && let Some((parent_addr, rest)) = take_cells(rest, address_cells)
+                    && let Some((size, rest)) = take_cells(rest, child_size_cells)
+                {
+                    ranges = rest;
+                    if let Some(cpu_addr) = translate(translation, parent_addr) {
+                        windows.push((child_addr, cpu_addr, size));
+                    }
+                }
+                windows
+            };
+            Self::node_regions(child, &child_path, &child_translation, regions);
+        }
+    }
+
+    /// what /chosen says now, with no bootargs as an empty string
+    pub fn chosen(&self) -> Chosen {
+        let chosen = self.root.child("chosen");
+        let bootargs = chosen.and_then(|chosen| chosen.string("bootargs")).unwrap_or_default().to_string();
+        let initrd = chosen.and_then(|chosen| Some((chosen.u64("linux,initrd-start")?, chosen.u64("linux,initrd-end")?)));
+        Chosen { bootargs, initrd }
+    }
+
+    /// makes /chosen say just what `chosen` does about the command line and
+    /// initrd, anything else in it is left alone
+    pub fn set_chosen(&mut self, chosen: &Chosen) {
+        let node = match self.root.children.iter().position(|child| child.name == "chosen") {
+            Some(i) => &mut self.root.children[i],
+            None => {
+                self.root.children.insert(0, Node::new("chosen"));
+                &mut self.root.children[0]
+            }
+        };
+        if chosen.bootargs.is_empty() {
+            node.remove_property("bootargs");
+        } else {
+            node.set_property("bootargs", chosen.bootargs.bytes().chain([0]).collect());
+        }
+        match chosen.initrd {
+            Some((start, end)) => {
+                node.set_property("linux,initrd-start", start.to_be_bytes().to_vec());
+                node.set_property("linux,initrd-end", end.to_be_bytes().to_vec());
+            }
+            None => {
+                node.remove_property("linux,initrd-start");
+                node.remove_property("linux,initrd-end");
+            }
+        }
+    }
+
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut fdt = Fdt::new();
+        for &(addr, size) in &self.reservations {
+            fdt.reserve(addr, size);
+        }
+        self.root.write(&mut fdt);
+        fdt.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{bus::{Bus, DRAM_START, UART_START, VIRTIO_START}, clint::ClockSource, uart::Uart, virtio::rng::{Prng, Rng}};
+
+    fn machine() -> Bus {
+        let uart = Uart::with_io(None, Box::new(io::sink()));
+        let mut bus = Bus::new(64 * 1024 * 1024, ClockSource::InstructionCount { instructions_per_tick: 1 }, uart);
+        bus.attach_virtio(Box::new(Rng::new(Prng::from_seed(0))));
+        bus
+    }
+
+    fn generated() -> Vec<u8> {
+        let chosen = Chosen { bootargs: "console=ttyS0".to_string(), initrd: Some((0x84000000, 0x84001000)) };
+        machine().device_tree("rv64imafdc", &chosen)
+    }
+
+    #[test]
+    fn generated_tree_round_trips() {
+        let blob = generated();
+        let tree = DeviceTree::parse(&blob).unwrap();
+        assert_eq!(tree.to_blob(), blob);
+        assert!(machine().check_device_tree(&tree).is_empty());
+
+        let regions = tree.regions();
+        let memory = regions.iter().find(|region| region.memory).unwrap();
+        assert_eq!((memory.addr, memory.size), (DRAM_START, 64 * 1024 * 1024));
+        for addr in [UART_START, VIRTIO_START] {
+            assert!(regions.iter().any(|region| region.addr == addr && !region.memory), "{addr:#x}");
+        }
+
+        let chosen = tree.chosen();
+        assert_eq!(chosen.bootargs, "console=ttyS0");
+        assert_eq!(chosen.initrd, Some((0x84000000, 0x84001000)));
+    }
+
+    #[test]
+    fn set_chosen_replaces_and_removes() {
+        let mut tree = DeviceTree::parse(&generated()).unwrap();
+        tree.set_chosen(&Chosen { bootargs: String::new(), initrd: None });
+        let tree = DeviceTree::parse(&tree.to_blob()).unwrap();
+        let chosen = tree.chosen();
+        assert_eq!((chosen.bootargs.as_str(), chosen.initrd), ("", None));
+        // the rest of /chosen stays
+        assert!(tree.root.child("chosen").unwrap().string("stdout-path").is_some());
+
+        // and a tree without /chosen gets one
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.end_node();
+        let mut tree = DeviceTree::parse(&fdt.finish()).unwrap();
+        tree.set_chosen(&Chosen { bootargs: "quiet".to_string(), initrd: Some((1, 2)) });
+        let chosen = DeviceTree::parse(&tree.to_blob()).unwrap().chosen();
+        assert_eq!((chosen.bootargs.as_str(), chosen.initrd), ("quiet", Some((1, 2))));
+    }
+
+    #[test]
+    fn truncated_blobs_are_rejected() {
+        let blob = generated();
+        for len in 0..blob.len() {
+            assert!(DeviceTree::parse(&blob[..len]).is_err(), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn unbalanced_blobs_are_rejected() {
+        let mut unended = Fdt::new();
+        unended.begin_node("");
+        unended.begin_node("child");
+        unended.end_node();
+        assert!(DeviceTree::parse(&unended.finish()).is_err());
+
+        let mut ended_twice = Fdt::new();
+        ended_twice.begin_node("");
+        ended_twice.end_node();
+        ended_twice.end_node();
+        assert!(DeviceTree::parse(&ended_twice.finish()).is_err());
+
+        let mut two_roots = Fdt::new();
+        for _ in 0..2 {
+            two_roots.begin_node("");
+            two_roots.end_node();
+        }
+        assert!(DeviceTree::parse(&two_roots.finish()).is_err());
+    }
+
+    #[test]
+    fn ranges_past_the_address_space_are_dropped() {
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.begin_node("bus");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        // child 0 is near the top of the CPU's address space, with a window that goes past it
+        fdt.property_u64s("ranges", &[0, u64::MAX - 0xfff, 0x10000]);
+        for (name, addr) in [("low@100", 0x100), ("high@2000", 0x2000)] {
+            fdt.begin_node(name);
+            fdt.property_u64s("reg", &[addr, 0x10]);
+            fdt.end_node();
+        }
+        fdt.end_node();
+        fdt.end_node();
+
+        let regions = DeviceTree::parse(&fdt.finish()).unwrap().regions();
+        assert_eq!(regions.len(), 1);
+        assert_eq!((regions[0].path.as_str(), regions[0].addr), ("/bus/low@100", u64::MAX - 0xeff));
+    }
+}
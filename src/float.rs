@@ -0,0 +1,684 @@
+// Software floating point used by the F and D extensions. The host FPU can't be
+// told which rounding mode to use and doesn't report the IEEE exception flags, so
+// every operation is done on integers and rounded by `round_pack`.
+
+pub mod flags {
+    pub const NX: u64 = 1 << 0; // inexact
+    pub const UF: u64 = 1 << 1; // underflow
+    pub const OF: u64 = 1 << 2; // overflow
+    pub const DZ: u64 = 1 << 3; // divide by zero
+    pub const NV: u64 = 1 << 4; // invalid operation
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RoundingMode {
+    NearestEven,
+    TowardZero,
+    Down,
+    Up,
+    NearestMaxMagnitude,
+}
+
+impl RoundingMode {
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0b000 => Some(RoundingMode::NearestEven),
+            0b001 => Some(RoundingMode::TowardZero),
+            0b010 => Some(RoundingMode::Down),
+            0b011 => Some(RoundingMode::Up),
+            0b100 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Format {
+    exp_bits: u32,
+    frac_bits: u32,
+}
+
+pub const F32: Format = Format { exp_bits: 8, frac_bits: 23 };
+pub const F64: Format = Format { exp_bits: 11, frac_bits: 52 };
+
+impl Format {
+    fn width(self) -> u32 {
+        1 + self.exp_bits + self.frac_bits
+    }
+
+    fn bias(self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+
+    fn max_exp(self) -> u64 {
+        (1 << self.exp_bits) - 1
+    }
+
+    fn frac_mask(self) -> u64 {
+        (1 << self.frac_bits) - 1
+    }
+
+    fn sign_bit(self) -> u64 {
+        1 << (self.width() - 1)
+    }
+
+    pub fn canonical_nan(self) -> u64 {
+        (self.max_exp() << self.frac_bits) | (1 << (self.frac_bits - 1))
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        if sign { self.sign_bit() } else { 0 }
+    }
+
+    fn infinity(self, sign: bool) -> u64 {
+        self.zero(sign) | (self.max_exp() << self.frac_bits)
+    }
+
+    fn max_finite(self, sign: bool) -> u64 {
+        self.zero(sign) | ((self.max_exp() - 1) << self.frac_bits) | self.frac_mask()
+    }
+
+    fn sign(self, bits: u64) -> bool {
+        bits & self.sign_bit() != 0
+    }
+
+    fn biased_exp(self, bits: u64) -> u64 {
+        (bits >> self.frac_bits) & self.max_exp()
+    }
+
+    fn is_nan(self, bits: u64) -> bool {
+        self.biased_exp(bits) == self.max_exp() && bits & self.frac_mask() != 0
+    }
+
+    fn is_signaling_nan(self, bits: u64) -> bool {
+        self.is_nan(bits) && bits & (1 << (self.frac_bits - 1)) == 0
+    }
+
+    fn is_inf(self, bits: u64) -> bool {
+        self.biased_exp(bits) == self.max_exp() && bits & self.frac_mask() == 0
+    }
+
+    fn is_zero(self, bits: u64) -> bool {
+        bits & !self.sign_bit() == 0
+    }
+}
+
+/// A finite, non zero value equal to (-1)^sign * sig * 2^exp
+#[derive(Clone, Copy)]
+struct Unpacked {
+    sign: bool,
+    exp: i32,
+    sig: u128,
+}
+
+fn unpack(fmt: Format, bits: u64) -> Unpacked {
+    let biased = fmt.biased_exp(bits);
+    let frac = (bits & fmt.frac_mask()) as u128;
+    let (exp, sig) = if biased == 0 {
+        (1 - fmt.bias() - fmt.frac_bits as i32, frac)
+    } else {
+        (biased as i32 - fmt.bias() - fmt.frac_bits as i32, frac | (1 << fmt.frac_bits))
+    };
+    Unpacked { sign: fmt.sign(bits), exp, sig }
+}
+
+/// shift right keeping any bits shifted out as a sticky lsb
+fn shift_jam(sig: u128, shift: u32) -> u128 {
+    if shift == 0 {
+        sig
+    } else if shift >= 128 {
+        (sig != 0) as u128
+    } else {
+        (sig >> shift) | ((sig & ((1 << shift) - 1) != 0) as u128)
+    }
+}
+
+/// shift right rounding the result, also returns if the result is inexact
+fn shift_round(sig: u128, shift: u32, sign: bool, rm: RoundingMode) -> (u128, bool) {
+    if shift == 0 {
+        return (sig, false);
+    }
+    // callers keep `sig` below 2^125 so anything this far right is under half an ulp
+    let (sig, shift) = if shift >= 126 { ((sig != 0) as u128, 2) } else { (sig, shift) };
+
+    let kept = sig >> shift;
+    let rem = sig & ((1 << shift) - 1);
+    let half = 1 << (shift - 1);
+    let inexact = rem != 0;
+
+    let round_up = match rm {
+        RoundingMode::NearestEven => rem > half || (rem == half && kept & 1 == 1),
+        RoundingMode::NearestMaxMagnitude => rem >= half,
+        RoundingMode::TowardZero => false,
+        RoundingMode::Down => sign && inexact,
+        RoundingMode::Up => !sign && inexact,
+    };
+
+    (kept + round_up as u128, inexact)
+}
+
+/// rounds (-1)^sign * sig * 2^exp to the nearest representable value in `fmt`
+fn round_pack(fmt: Format, sign: bool, exp: i32, sig: u128, rm: RoundingMode, fflags: &mut u64) -> u64 {
+    if sig == 0 {
+        return fmt.zero(sign);
+    }
+
+    let (mut exp, mut sig) = (exp, sig);
+    let mut msb = 127 - sig.leading_zeros() as i32;
+    if msb > 120 {
+        sig = shift_jam(sig, (msb - 120) as u32);
+        exp += msb - 120;
+        msb = 120;
+    }
+
+    let frac_bits = fmt.frac_bits as i32;
+    let leading_exp = exp + msb;
+    let emin = 1 - fmt.bias();
+    let mut ulp = (leading_exp - frac_bits).max(emin - frac_bits);
+
+    let (mut mant, inexact) = if ulp > exp {
+        shift_round(sig, (ulp - exp) as u32, sign, rm)
+    } else {
+        (sig << (exp - ulp), false)
+    };
+
+    // tininess is detected after rounding, as if the exponent range was unbounded
+    if leading_exp < emin && inexact {
+        let tiny = if msb > frac_bits {
+            let (unbounded, _) = shift_round(sig, (msb - frac_bits) as u32, sign, rm);
+            !(unbounded >> (frac_bits + 1) != 0 && leading_exp + 1 == emin)
+        } else {
+            true
+        };
+        if tiny {
+            *fflags |= flags::UF;
+        }
+    }
+    if inexact {
+        *fflags |= flags::NX;
+    }
+
+    if mant >> (frac_bits + 1) != 0 {
+        mant >>= 1;
+        ulp += 1;
+    }
+
+    let biased = if mant >> frac_bits == 0 {
+        0
+    } else {
+        (ulp + frac_bits + fmt.bias()) as u64
+    };
+
+    if biased >= fmt.max_exp() {
+        *fflags |= flags::OF | flags::NX;
+        return match rm {
+            RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => fmt.infinity(sign),
+            RoundingMode::TowardZero => fmt.max_finite(sign),
+            RoundingMode::Down => if sign { fmt.infinity(sign) } else { fmt.max_finite(sign) },
+            RoundingMode::Up => if sign { fmt.max_finite(sign) } else { fmt.infinity(sign) },
+        };
+    }
+
+    fmt.zero(sign) | (biased << fmt.frac_bits) | (mant as u64 & fmt.frac_mask())
+}
+
+/// returns the canonical NaN if any input is a NaN, raising NV for signaling ones
+fn propagate_nan(fmt: Format, inputs: &[u64], fflags: &mut u64) -> Option<u64> {
+    if inputs.iter().any(|&bits| fmt.is_signaling_nan(bits)) {
+        *fflags |= flags::NV;
+    }
+    if inputs.iter().any(|&bits| fmt.is_nan(bits)) {
+        Some(fmt.canonical_nan())
+    } else {
+        None
+    }
+}
+
+fn invalid(fmt: Format, fflags: &mut u64) -> u64 {
+    *fflags |= flags::NV;
+    fmt.canonical_nan()
+}
+
+/// the sign of an exact zero sum of operands with different signs
+fn zero_sum_sign(rm: RoundingMode) -> bool {
+    rm == RoundingMode::Down
+}
+
+/// adds two finite non zero values with a single rounding
+fn add_unpacked(fmt: Format, a: Unpacked, b: Unpacked, rm: RoundingMode, fflags: &mut u64) -> u64 {
+    // move both msbs up to bit 122 so the smaller operand only loses bits into the sticky bit
+    let normalize = |x: Unpacked| {
+        let shift = 122 - (127 - x.sig.leading_zeros() as i32);
+        Unpacked { sign: x.sign, exp: x.exp - shift, sig: x.sig << shift }
+    };
+    let (mut a, mut b) = (normalize(a), normalize(b));
+    if a.exp < b.exp {
+        std::mem::swap(&mut a, &mut b);
+    }
+    b.sig = shift_jam(b.sig, (a.exp - b.exp).min(128) as u32);
+
+    if a.sign == b.sign {
+        round_pack(fmt, a.sign, a.exp, a.sig + b.sig, rm, fflags)
+    } else if a.sig > b.sig {
+        round_pack(fmt, a.sign, a.exp, a.sig - b.sig, rm, fflags)
+    } else if b.sig > a.sig {
+        round_pack(fmt, b.sign, a.exp, b.sig - a.sig, rm, fflags)
+    } else {
+        fmt.zero(zero_sum_sign(rm))
+    }
+}
+
+pub fn add(fmt: Format, a: u64, b: u64, rm: RoundingMode, fflags: &mut u64) -> u64 {
+    if let Some(nan) = propagate_nan(fmt, &[a, b], fflags) {
+        return nan;
+    }
+    match (fmt.is_inf(a), fmt.is_inf(b)) {
+        (true, true) if fmt.sign(a) != fmt.sign(b) => return invalid(fmt, fflags),
+        (true, _) => return a,
+        (_, true) => return b,
+        _ => {}
+    }
+    match (fmt.is_zero(a), fmt.is_zero(b)) {
+        (true, true) if fmt.sign(a) == fmt.sign(b) => a,
+        (true, true) => fmt.zero(zero_sum_sign(rm)),
+        (true, false) => b,
+        (false, true) => a,
+        (false, false) => add_unpacked(fmt, unpack(fmt, a), unpack(fmt, b), rm, fflags),
+    }
+}
+
+pub fn sub(fmt: Format, a: u64, b: u64, rm: RoundingMode, fflags: &mut u64) -> u64 {
+    let b = if fmt.is_nan(b) { b } else { b ^ fmt.sign_bit() };
+    add(fmt, a, b, rm, fflags)
+}
+
+pub fn mul(fmt: Format, a: u64, b: u64, rm: RoundingMode, fflags: &mut u64) -> u64 {
+    if let Some(nan) = propagate_nan(fmt, &[a, b], fflags) {
+        return nan;
+    }
+    let sign = fmt.sign(a) != fmt.sign(b);
+    if fmt.is_inf(a) || fmt.is_inf(b) {
+        if fmt.is_zero(a) || fmt.is_zero(b) {
+            return invalid(fmt, fflags);
+        }
+        return fmt.infinity(sign);
+    }
+    if fmt.is_zero(a) || fmt.is_zero(b) {
+        return fmt.zero(sign);
+    }
+    let (a, b) = (unpack(fmt, a), unpack(fmt, b));
+    round_pack(fmt, sign, a.exp + b.exp, a.sig * b.sig, rm, fflags)
+}
+
+pub fn div(fmt: Format, a: u64, b: u64, rm: RoundingMode, fflags: &mut u64) -> u64 {
+    if let Some(nan) = propagate_nan(fmt, &[a, b], fflags) {
+        return nan;
+    }
+    let sign = fmt.sign(a) != fmt.sign(b);
+    match (fmt.is_inf(a), fmt.is_inf(b)) {
+        (true, true) => return invalid(fmt, fflags),
+        (true, false) => return fmt.infinity(sign),
+        (false, true) => return fmt.zero(sign),
+        _ => {}
+    }
+    match (fmt.is_zero(a), fmt.is_zero(b)) {
+        (true, true) => return invalid(fmt, fflags),
+        (true, false) => return fmt.zero(sign),
+        (false, true) => {
+            *fflags |= flags::DZ;
+            return fmt.infinity(sign);
+        }
+        _ => {}
+    }
+
+    let (a, b) = (unpack(fmt, a), unpack(fmt, b));
+    // line both significands up at bit 60 so the quotient has plenty of bits
+    let a_shift = 60 - (127 - a.sig.leading_zeros() as i32);
+    let b_shift = 60 - (127 - b.sig.leading_zeros() as i32);
+    let dividend = (a.sig << a_shift) << 62;
+    let divisor = b.sig << b_shift;
+
+    let quotient = dividend / divisor;
+    let sticky = !dividend.is_multiple_of(divisor) as u128;
+    let exp = (a.exp - a_shift - 62) - (b.exp - b_shift);
+    round_pack(fmt, sign, exp, quotient | sticky, rm, fflags)
+}
+
+fn isqrt(value: u128) -> (u128, bool) {
+    let mut rem = value;
+    let mut root = 0u128;
+    let mut bit = 1u128 << 126;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    (root, rem != 0)
+}
+
+pub fn sqrt(fmt: Format, a: u64, rm: RoundingMode, fflags: &mut u64) -> u64 {
+    if let Some(nan) = propagate_nan(fmt, &[a], fflags) {
+        return nan;
+    }
+    if fmt.is_zero(a) {
+        return a;
+    }
+    if fmt.sign(a) {
+        return invalid(fmt, fflags);
+    }
+    if fmt.is_inf(a) {
+        return a;
+    }
+
+    let a = unpack(fmt, a);
+    // put the msb at bit 119 or 120 so the exponent left over is even
+    let mut shift = 120 - (127 - a.sig.leading_zeros() as i32);
+    if (a.exp - shift) % 2 != 0 {
+        shift -= 1;
+    }
+    let (root, inexact) = isqrt(a.sig << shift);
+    round_pack(fmt, false, (a.exp - shift) / 2, root | inexact as u128, rm, fflags)
+}
+
+/// a * b + c with a single rounding, negating the product and/or addend as asked
+#[allow(clippy::too_many_arguments)]
+pub fn fused_mul_add(fmt: Format, a: u64, b: u64, c: u64, negate_product: bool, negate_addend: bool, rm: RoundingMode, fflags: &mut u64) -> u64 {
+    let product_invalid = (fmt.is_inf(a) && fmt.is_zero(b)) || (fmt.is_zero(a) && fmt.is_inf(b));
+    if let Some(nan) = propagate_nan(fmt, &[a, b, c], fflags) {
+        if product_invalid {
+            *fflags |= flags::NV;
+        }
+        return nan;
+    }
+    if product_invalid {
+        return invalid(fmt, fflags);
+    }
+
+    let product_sign = (fmt.sign(a) != fmt.sign(b)) != negate_product;
+    let c = if negate_addend { c ^ fmt.sign_bit() } else { c };
+
+    if fmt.is_inf(a) || fmt.is_inf(b) {
+        if fmt.is_inf(c) && fmt.sign(c) != product_sign {
+            return invalid(fmt, fflags);
+        }
+        return fmt.infinity(product_sign);
+    }
+    if fmt.is_inf(c) {
+        return c;
+    }
+    if fmt.is_zero(a) || fmt.is_zero(b) {
+        return if !fmt.is_zero(c) || fmt.sign(c) == product_sign {
+            c
+        } else {
+            fmt.zero(zero_sum_sign(rm))
+        };
+    }
+
+    let (a, b) = (unpack(fmt, a), unpack(fmt, b));
+    let product = Unpacked { sign: product_sign, exp: a.exp + b.exp, sig: a.sig * b.sig };
+    if fmt.is_zero(c) {
+        return round_pack(fmt, product.sign, product.exp, product.sig, rm, fflags);
+    }
+    add_unpacked(fmt, product, unpack(fmt, c), rm, fflags)
+}
+
+pub fn convert(from: Format, to: Format, a: u64, rm: RoundingMode, fflags: &mut u64) -> u64 {
+    if let Some(_nan) = propagate_nan(from, &[a], fflags) {
+        return to.canonical_nan();
+    }
+    let sign = from.sign(a);
+    if from.is_inf(a) {
+        return to.infinity(sign);
+    }
+    if from.is_zero(a) {
+        return to.zero(sign);
+    }
+    let a = unpack(from, a);
+    round_pack(to, a.sign, a.exp, a.sig, rm, fflags)
+}
+
+/// converts to a `width` bit integer, returning it sign extended to 64 bits
+pub fn to_int(fmt: Format, a: u64, signed: bool, width: u32, rm: RoundingMode, fflags: &mut u64) -> u64 {
+    let (min, max): (i128, i128) = if signed {
+        (-(1 << (width - 1)), (1 << (width - 1)) - 1)
+    } else {
+        (0, (1 << width) - 1)
+    };
+    let saturate = |value: i128| (value as i64 as u64) & if width == 32 { 0xffffffff } else { u64::MAX };
+    let extend = |value: u64| if width == 32 { value as i32 as i64 as u64 } else { value };
+
+    if fmt.is_nan(a) {
+        *fflags |= flags::NV;
+        return extend(saturate(max));
+    }
+    let sign = fmt.sign(a);
+    if fmt.is_inf(a) {
+        *fflags |= flags::NV;
+        return extend(saturate(if sign { min } else { max }));
+    }
+    if fmt.is_zero(a) {
+        return 0;
+    }
+
+    let a = unpack(fmt, a);
+    let (magnitude, inexact) = if a.exp >= 0 {
+        if a.exp > 64 {
+            (u128::MAX, false)
+        } else {
+            (a.sig << a.exp, false)
+        }
+    } else {
+        shift_round(a.sig, (-a.exp) as u32, sign, rm)
+    };
+
+    let value = if magnitude > i128::MAX as u128 {
+        if sign { i128::MIN } else { i128::MAX }
+    } else if sign {
+        -(magnitude as i128)
+    } else {
+        magnitude as i128
+    };
+
+    if value < min || value > max {
+        *fflags |= flags::NV;
+        return extend(saturate(if value < min { min } else { max }));
+    }
+    if inexact {
+        *fflags |= flags::NX;
+    }
+    extend(saturate(value))
+}
+
+/// converts the low `width` bits of `a` to a float
+pub fn from_int(fmt: Format, a: u64, signed: bool, width: u32, rm: RoundingMode, fflags: &mut u64) -> u64 {
+    let value: i128 = match (signed, width) {
+        (true, 32) => a as i32 as i128,
+        (false, 32) => a as u32 as i128,
+        (true, _) => a as i64 as i128,
+        (false, _) => a as i128,
+    };
+    round_pack(fmt, value < 0, 0, value.unsigned_abs(), rm, fflags)
+}
+
+fn less_than(fmt: Format, a: u64, b: u64) -> bool {
+    if fmt.is_zero(a) && fmt.is_zero(b) {
+        return false;
+    }
+    match (fmt.sign(a), fmt.sign(b)) {
+        (false, false) => a < b,
+        (true, true) => a > b,
+        (true, false) => true,
+        (false, true) => false,
+    }
+}
+
+fn equal(fmt: Format, a: u64, b: u64) -> bool {
+    a == b || (fmt.is_zero(a) && fmt.is_zero(b))
+}
+
+/// FEQ is a quiet comparison so only signaling NaNs are invalid
+pub fn eq(fmt: Format, a: u64, b: u64, fflags: &mut u64) -> bool {
+    if propagate_nan(fmt, &[a, b], fflags).is_some() {
+        return false;
+    }
+    equal(fmt, a, b)
+}
+
+pub fn lt(fmt: Format, a: u64, b: u64, fflags: &mut u64) -> bool {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        *fflags |= flags::NV;
+        return false;
+    }
+    less_than(fmt, a, b)
+}
+
+pub fn le(fmt: Format, a: u64, b: u64, fflags: &mut u64) -> bool {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        *fflags |= flags::NV;
+        return false;
+    }
+    less_than(fmt, a, b) || equal(fmt, a, b)
+}
+
+/// FMIN/FMAX return the other operand when only one is a NaN, and treat -0 as less than +0
+pub fn min_max(fmt: Format, a: u64, b: u64, max: bool, fflags: &mut u64) -> u64 {
+    if fmt.is_signaling_nan(a) || fmt.is_signaling_nan(b) {
+        *fflags |= flags::NV;
+    }
+    match (fmt.is_nan(a), fmt.is_nan(b)) {
+        (true, true) => fmt.canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        _ => {
+            let a_less = less_than(fmt, a, b) || (fmt.is_zero(a) && fmt.is_zero(b) && fmt.sign(a));
+            if a_less != max { a } else { b }
+        }
+    }
+}
+
+pub fn classify(fmt: Format, a: u64) -> u64 {
+    let sign = fmt.sign(a);
+    let subnormal = fmt.biased_exp(a) == 0 && !fmt.is_zero(a);
+    let bit = if fmt.is_inf(a) {
+        if sign { 0 } else { 7 }
+    } else if fmt.is_zero(a) {
+        if sign { 3 } else { 4 }
+    } else if fmt.is_signaling_nan(a) {
+        8
+    } else if fmt.is_nan(a) {
+        9
+    } else if subnormal {
+        if sign { 2 } else { 5 }
+    } else if sign {
+        1
+    } else {
+        6
+    };
+    1 << bit
+}
+
+/// FSGNJ, FSGNJN and FSGNJX all keep everything but the sign bit of `a`
+pub fn sign_inject(fmt: Format, a: u64, b: u64, funct3: u64) -> Option<u64> {
+    let sign = match funct3 {
+        0b000 => b & fmt.sign_bit(),
+        0b001 => !b & fmt.sign_bit(),
+        0b010 => (a ^ b) & fmt.sign_bit(),
+        _ => return None,
+    };
+    Some((a & !fmt.sign_bit()) | sign)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RoundingMode::*;
+
+    const ONE: u64 = 0x3f800000;
+    const SNAN: u64 = 0x7f800001;
+    const QNAN: u64 = 0x7fc00000;
+
+    /// the result and the flags it raised
+    fn with_flags(op: impl FnOnce(&mut u64) -> u64) -> (u64, u64) {
+        let mut fflags = 0;
+        let result = op(&mut fflags);
+        (result, fflags)
+    }
+
+    #[test]
+    fn halfway_cases_round_by_mode() {
+        // 1 + 2^-24 is halfway between 1 and the next float up
+        let half_ulp = 0x33800000;
+        for (rm, positive, negative) in [
+            (NearestEven, ONE, ONE | F32.sign_bit()),
+            (TowardZero, ONE, ONE | F32.sign_bit()),
+            (Down, ONE, (ONE + 1) | F32.sign_bit()),
+            (Up, ONE + 1, ONE | F32.sign_bit()),
+            (NearestMaxMagnitude, ONE + 1, (ONE + 1) | F32.sign_bit()),
+        ] {
+            assert_eq!(with_flags(|f| add(F32, ONE, half_ulp, rm, f)), (positive, flags::NX), "{rm:?}");
+            let sign = F32.sign_bit();
+            assert_eq!(with_flags(|f| add(F32, ONE | sign, half_ulp | sign, rm, f)), (negative, flags::NX), "{rm:?}");
+        }
+        // a tie with an odd neighbour below goes up to the even one
+        assert_eq!(with_flags(|f| from_int(F32, (1 << 24) + 3, true, 64, NearestEven, f)), (0x4b800002, flags::NX));
+        assert_eq!(with_flags(|f| to_int(F64, 2.5f64.to_bits(), true, 64, NearestEven, f)), (2, flags::NX));
+        assert_eq!(with_flags(|f| to_int(F64, 2.5f64.to_bits(), true, 64, NearestMaxMagnitude, f)), (3, flags::NX));
+    }
+
+    #[test]
+    fn fused_mul_add_rounds_once() {
+        // (1 + 2^-23)^2 - (1 + 2^-22) is 2^-46, rounding the product first would give 0
+        assert_eq!(with_flags(|f| fused_mul_add(F32, ONE + 1, ONE + 1, ONE + 2, false, true, NearestEven, f)), (0x28800000, 0));
+    }
+
+    #[test]
+    fn subnormals_and_underflow() {
+        // exact subnormal results don't underflow
+        assert_eq!(with_flags(|f| mul(F32, 0x00800000, 0x3f000000, NearestEven, f)), (0x00400000, 0));
+        // half the smallest subnormal ties to zero, or rounds up to it
+        assert_eq!(with_flags(|f| mul(F32, 0x00000001, 0x3f000000, NearestEven, f)), (0, flags::UF | flags::NX));
+        assert_eq!(with_flags(|f| mul(F32, 0x00000001, 0x3f000000, Up, f)), (0x00000001, flags::UF | flags::NX));
+
+        // tininess is after rounding: 2^-126 * (1 - 2^-25) would round to the
+        // smallest normal even with an unbounded exponent so it isn't tiny,
+        // 2^-126 * (1 - 2^-24) wouldn't and is
+        let min_normal = 2f64.powi(-126);
+        let not_tiny = (min_normal * (1.0 - 2f64.powi(-25))).to_bits();
+        let tiny = (min_normal * (1.0 - 2f64.powi(-24))).to_bits();
+        assert_eq!(with_flags(|f| convert(F64, F32, not_tiny, NearestEven, f)), (0x00800000, flags::NX));
+        assert_eq!(with_flags(|f| convert(F64, F32, tiny, NearestEven, f)), (0x00800000, flags::UF | flags::NX));
+    }
+
+    #[test]
+    fn min_max_nans_and_zeros() {
+        assert_eq!(with_flags(|f| min_max(F32, SNAN, ONE, false, f)), (ONE, flags::NV));
+        assert_eq!(with_flags(|f| min_max(F32, ONE, SNAN, true, f)), (ONE, flags::NV));
+        assert_eq!(with_flags(|f| min_max(F32, QNAN, ONE, false, f)), (ONE, 0));
+        assert_eq!(with_flags(|f| min_max(F32, SNAN, QNAN, true, f)), (F32.canonical_nan(), flags::NV));
+        assert_eq!(with_flags(|f| min_max(F32, 0, F32.sign_bit(), false, f)), (F32.sign_bit(), 0));
+        assert_eq!(with_flags(|f| min_max(F32, F32.sign_bit(), 0, true, f)), (0, 0));
+    }
+
+    #[test]
+    fn to_int_saturates() {
+        let int = |a: f64, signed, width, rm| with_flags(|f| to_int(F64, a.to_bits(), signed, width, rm, f));
+        // 32 bit results are sign extended, even unsigned ones
+        assert_eq!(with_flags(|f| to_int(F32, QNAN, true, 32, NearestEven, f)), (0x7fffffff, flags::NV));
+        assert_eq!(int(f64::NEG_INFINITY, true, 32, NearestEven), (0xffffffff80000000, flags::NV));
+        assert_eq!(int(3e9, true, 32, NearestEven), (0x7fffffff, flags::NV));
+        assert_eq!(int(5e9, false, 32, NearestEven), (u64::MAX, flags::NV));
+        assert_eq!(int(-1.5, false, 32, NearestEven), (0, flags::NV));
+        // rounding to zero first makes a small negative number fine unsigned
+        assert_eq!(int(-0.5, false, 32, TowardZero), (0, flags::NX));
+        assert_eq!(int(2f64.powi(63), true, 64, NearestEven), (i64::MAX as u64, flags::NV));
+        assert_eq!(int(-(2f64.powi(63)), true, 64, NearestEven), (i64::MIN as u64, 0));
+        assert_eq!(int(f64::NAN, false, 64, NearestEven), (u64::MAX, flags::NV));
+    }
+}
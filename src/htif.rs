@@ -0,0 +1,326 @@
+use std::{collections::{HashMap, VecDeque}, fs::{File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt}, sync::mpsc::Receiver};
+
+use crate::{bus::DRAM_START, dram::Dram, test_finisher::Shutdown};
+
+// tohost and fromhost hold a device in the top byte, a command in the next and a payload below
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+fn command(device: u64, cmd: u64, payload: u64) -> u64 {
+    device << 56 | cmd << 48 | payload & 0xffff_ffff_ffff
+}
+
+// the syscalls a proxy kernel forwards, numbered like riscv Linux
+mod syscall {
+    pub const OPENAT: u64 = 56;
+    pub const CLOSE: u64 = 57;
+    pub const LSEEK: u64 = 62;
+    pub const READ: u64 = 63;
+    pub const WRITE: u64 = 64;
+    pub const PREAD: u64 = 67;
+    pub const PWRITE: u64 = 68;
+    pub const FSTAT: u64 = 80;
+    pub const EXIT: u64 = 93;
+    pub const OPEN: u64 = 1024;
+    /// fills a buffer with argc, argv and an empty envp for the proxy kernel
+    pub const GETMAINVARS: u64 = 2011;
+}
+
+mod errno {
+    pub const EBADF: i64 = 9;
+    pub const ENOMEM: i64 = 12;
+    pub const EFAULT: i64 = 14;
+    pub const EINVAL: i64 = 22;
+    pub const ENOSYS: i64 = 38;
+}
+
+// open flags, the same on riscv as on every other asm-generic Linux
+const O_ACCMODE: u64 = 0o3;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+
+const AT_FDCWD: i64 = -100;
+
+/// the riscv Linux struct stat
+const STAT_SIZE: usize = 128;
+const S_IFCHR: u32 = 0o020000;
+
+/// the first descriptor handed out after stdin, stdout and stderr
+const FIRST_FD: u64 = 3;
+
+/// the host-target interface test programs and the proxy kernel talk to the
+/// host through, it's a pair of words in memory found by their symbols
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+    /// the target's argv for the proxy kernel, starting with its own path
+    args: Vec<String>,
+    input: Option<Receiver<u8>>,
+    files: HashMap<u64, File>,
+    next_fd: u64,
+    /// a console read waiting for input
+    reading: bool,
+    /// replies waiting for the target to clear fromhost
+    responses: VecDeque<u64>,
+    request: Option<Shutdown>,
+}
+
+type SyscallResult = Result<u64, i64>;
+
+fn host_error(e: io::Error) -> i64 {
+    e.raw_os_error().map_or(errno::EINVAL, i64::from)
+}
+
+fn physical(addr: u64) -> Result<u64, i64> {
+    addr.checked_sub(DRAM_START).ok_or(errno::EFAULT)
+}
+
+fn guest(dram: &Dram, addr: u64, len: u64) -> Result<&[u8], i64> {
+    dram.slice(physical(addr)?, usize::try_from(len).map_err(|_| errno::EFAULT)?).ok_or(errno::EFAULT)
+}
+
+fn read_word(dram: &Dram, addr: u64) -> Option<u64> {
+    guest(dram, addr, 8).ok().map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+}
+
+fn write_word(dram: &mut Dram, addr: u64, value: u64) {
+    if let Ok(word) = guest_mut(dram, addr, 8) {
+        word.copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn guest_mut(dram: &mut Dram, addr: u64, len: u64) -> Result<&mut [u8], i64> {
+    dram.slice_mut(physical(addr)?, usize::try_from(len).map_err(|_| errno::EFAULT)?).ok_or(errno::EFAULT)
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>, args: Vec<String>, input: Option<Receiver<u8>>) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            args,
+            input,
+            files: HashMap::new(),
+            next_fd: FIRST_FD,
+            reading: false,
+            responses: VecDeque::new(),
+            request: None,
+        }
+    }
+
+    /// forgets every open file and pending reply, the host side stays connected
+    pub fn reset(&mut self) {
+        self.files.clear();
+        self.next_fd = FIRST_FD;
+        self.reading = false;
+        self.responses.clear();
+        self.request = None;
+    }
+
+    /// an exit the target asked for, if there's been one since this was last called
+    pub fn take_request(&mut self) -> Option<Shutdown> {
+        self.request.take()
+    }
+
+    /// picks up a command from tohost and hands back any reply waiting to go in fromhost
+    pub fn tick(&mut self, dram: &mut Dram) {
+        if self.reading && let Some(byte) = self.input.as_ref().and_then(|input| input.try_recv().ok()) {
+            self.reading = false;
+            self.responses.push_back(command(DEVICE_CONSOLE, CONSOLE_GETCHAR, 0x100 | byte as u64));
+        }
+
+        // a reply only goes in once the target has taken the last one
+        if let Some(fromhost) = self.fromhost && read_word(dram, fromhost) == Some(0) && let Some(response) = self.responses.pop_front() {
+            write_word(dram, fromhost, response);
+        }
+
+        let value = match read_word(dram, self.tohost) {
+            Some(0) | None => return,
+            Some(value) => value,
+        };
+        // clearing tohost is what tells the target it can send the next command
+        write_word(dram, self.tohost, 0);
+
+        let device = value >> 56;
+        let cmd = value >> 48 & 0xff;
+        let payload = value & 0xffff_ffff_ffff;
+        match (device, cmd) {
+            // riscv-tests put the test number that failed in the upper bits, or 0 for a pass
+            (DEVICE_SYSCALL, 0) if payload == 1 => self.request = Some(Shutdown::Exit(0)),
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => self.request = Some(Shutdown::failure(payload >> 1)),
+            (DEVICE_SYSCALL, 0) => {
+                self.syscall(dram, payload);
+                self.responses.push_back(command(DEVICE_SYSCALL, 0, 1));
+            }
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => self.reading = true,
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                let mut output = io::stderr();
+                let _ = output.write_all(&[payload as u8]);
+                let _ = output.flush();
+            }
+            _ => {}
+        }
+    }
+
+    /// runs the syscall described by the eight words at `addr`, the result replaces the first
+    fn syscall(&mut self, dram: &mut Dram, addr: u64) {
+        let Ok(words) = guest(dram, addr, 64) else { return };
+        let words: Vec<u64> = words.chunks_exact(8).map(|word| u64::from_le_bytes(word.try_into().unwrap())).collect();
+        let [number, a0, a1, a2, a3, ..] = words[..] else { return };
+
+        let result = match number {
+            syscall::OPENAT => self.openat(dram, a0 as i64, a1, a2, a3, words[5]),
+            syscall::OPEN => self.openat(dram, AT_FDCWD, a0, a1, a2, a3),
+            syscall::CLOSE => self.files.remove(&a0).map(|_| 0).ok_or(errno::EBADF),
+            syscall::LSEEK => self.lseek(a0, a1 as i64, a2),
+            syscall::READ => self.read(dram, a0, a1, a2, None),
+            syscall::PREAD => self.read(dram, a0, a1, a2, Some(a3)),
+            syscall::WRITE => self.write(dram, a0, a1, a2, None),
+            syscall::PWRITE => self.write(dram, a0, a1, a2, Some(a3)),
+            syscall::FSTAT => self.fstat(dram, a0, a1),
+            syscall::EXIT => {
+                // a program's nonzero status has to stay a failure past the low 8 bits
+                self.request = Some(if a0 == 0 { Shutdown::Exit(0) } else { Shutdown::failure(a0) });
+                Ok(0)
+            }
+            syscall::GETMAINVARS => self.getmainvars(dram, a0, a1),
+            _ => Err(errno::ENOSYS),
+        };
+
+        write_word(dram, addr, result.unwrap_or_else(|errno| -errno as u64));
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut File, i64> {
+        self.files.get_mut(&fd).ok_or(errno::EBADF)
+    }
+
+    /// `path` is `len` bytes long counting its nul
+    fn openat(&mut self, dram: &Dram, dirfd: i64, path: u64, len: u64, flags: u64, mode: u64) -> SyscallResult {
+        let path = guest(dram, path, len)?;
+        let path = &path[..path.iter().position(|&b| b == 0).unwrap_or(path.len())];
+        let path = String::from_utf8_lossy(path).into_owned();
+        // only the host's working directory can be opened relative to
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(errno::EBADF);
+        }
+
+        let file = OpenOptions::new()
+            .read(flags & O_ACCMODE != O_WRONLY)
+            .write(flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0 && flags & O_EXCL == 0)
+            .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0)
+            .mode(mode as u32)
+            .open(path)
+            .map_err(host_error)?;
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, file);
+        Ok(fd)
+    }
+
+    fn lseek(&mut self, fd: u64, offset: i64, whence: u64) -> SyscallResult {
+        let position = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(errno::EINVAL),
+        };
+        self.file(fd)?.seek(position).map_err(host_error)
+    }
+
+    /// stdin blocks the whole machine until there's something to read, like a real read would block the target
+    fn read(&mut self, dram: &mut Dram, fd: u64, buf: u64, len: u64, offset: Option<u64>) -> SyscallResult {
+        let buf = guest_mut(dram, buf, len)?;
+        if fd == 0 {
+            let Some(input) = &self.input else { return Ok(0) };
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let Ok(byte) = input.recv() else { return Ok(0) };
+            buf[0] = byte;
+            let mut read = 1;
+            while read < buf.len() {
+                let Ok(byte) = input.try_recv() else { break };
+                buf[read] = byte;
+                read += 1;
+            }
+            return Ok(read as u64);
+        }
+
+        let file = self.file(fd)?;
+        let read = match offset {
+            Some(offset) => file.read_at(buf, offset),
+            None => file.read(buf),
+        };
+        read.map(|read| read as u64).map_err(host_error)
+    }
+
+    fn write(&mut self, dram: &Dram, fd: u64, buf: u64, len: u64, offset: Option<u64>) -> SyscallResult {
+        let buf = guest(dram, buf, len)?;
+        let written = match (fd, offset) {
+            (1, None) => io::stdout().write_all(buf).and_then(|_| io::stdout().flush()).map(|_| buf.len()),
+            (2, None) => io::stderr().write_all(buf).map(|_| buf.len()),
+            (_, Some(offset)) => self.file(fd)?.write_at(buf, offset),
+            (_, None) => self.file(fd)?.write(buf),
+        };
+        written.map(|written| written as u64).map_err(host_error)
+    }
+
+    fn fstat(&mut self, dram: &mut Dram, fd: u64, buf: u64) -> SyscallResult {
+        let mut stat = [0u8; STAT_SIZE];
+        if fd < FIRST_FD {
+            // stdin, stdout and stderr look like a terminal so the target's stdio buffers lines
+            stat[16..20].copy_from_slice(&(S_IFCHR | 0o620).to_le_bytes());
+            stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+        } else {
+            let metadata = self.file(fd)?.metadata().map_err(host_error)?;
+            stat[0..8].copy_from_slice(&metadata.dev().to_le_bytes());
+            stat[8..16].copy_from_slice(&metadata.ino().to_le_bytes());
+            stat[16..20].copy_from_slice(&metadata.mode().to_le_bytes());
+            stat[20..24].copy_from_slice(&(metadata.nlink() as u32).to_le_bytes());
+            stat[24..28].copy_from_slice(&metadata.uid().to_le_bytes());
+            stat[28..32].copy_from_slice(&metadata.gid().to_le_bytes());
+            stat[32..40].copy_from_slice(&metadata.rdev().to_le_bytes());
+            stat[48..56].copy_from_slice(&metadata.size().to_le_bytes());
+            stat[56..60].copy_from_slice(&(metadata.blksize() as u32).to_le_bytes());
+            stat[64..72].copy_from_slice(&metadata.blocks().to_le_bytes());
+            stat[72..80].copy_from_slice(&metadata.atime().to_le_bytes());
+            stat[80..88].copy_from_slice(&metadata.atime_nsec().to_le_bytes());
+            stat[88..96].copy_from_slice(&metadata.mtime().to_le_bytes());
+            stat[96..104].copy_from_slice(&metadata.mtime_nsec().to_le_bytes());
+            stat[104..112].copy_from_slice(&metadata.ctime().to_le_bytes());
+            stat[112..120].copy_from_slice(&metadata.ctime_nsec().to_le_bytes());
+        }
+        guest_mut(dram, buf, STAT_SIZE as u64)?.copy_from_slice(&stat);
+        Ok(0)
+    }
+
+    /// argc, the argv pointers, a null, an empty envp and then the strings, all in `buf`
+    fn getmainvars(&self, dram: &mut Dram, buf: u64, limit: u64) -> SyscallResult {
+        let mut words = vec![self.args.len() as u64];
+        let mut strings = Vec::new();
+        let strings_start = buf + (self.args.len() as u64 + 3) * 8;
+        for arg in &self.args {
+            words.push(strings_start + strings.len() as u64);
+            strings.extend_from_slice(arg.as_bytes());
+            strings.push(0);
+        }
+        words.extend([0, 0]);
+
+        let mut vars: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        vars.extend(strings);
+        if vars.len() as u64 > limit {
+            return Err(errno::ENOMEM);
+        }
+        guest_mut(dram, buf, vars.len() as u64)?.copy_from_slice(&vars);
+        Ok(0)
+    }
+}
@@ -0,0 +1,101 @@
+use std::{fs, io, path::Path};
+
+use crate::{bus::{Bus, DRAM_START}, elf::{self, Elf}};
+
+// the header at the front of a RISC-V Linux Image, see
+// Documentation/arch/riscv/boot-image-header.rst
+const LINUX_HEADER_SIZE: usize = 64;
+const LINUX_MAGIC: &[u8] = b"RISCV\0\0\0";
+/// replaces LINUX_MAGIC from version 0.2 on
+const LINUX_MAGIC2: &[u8] = b"RSC\x05";
+
+/// a program to boot, an ELF that says where it goes, a Linux Image that
+/// says how far into memory it goes or a raw binary that goes where it's
+/// told, the last two starting at their first byte
+pub enum Image {
+    Elf(Elf),
+    /// `size` is how much memory the kernel takes up, bss and all
+    Linux { addr: u64, data: Vec<u8>, size: u64 },
+    Raw { addr: u64, data: Vec<u8> },
+}
+
+impl Image {
+    /// ELFs and Linux Images are recognised by their magic numbers, anything
+    /// else is raw and loaded at `raw_addr`
+    pub fn open(path: &Path, raw_addr: u64) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        if elf::is_elf(&bytes) {
+            Ok(Self::Elf(Elf::parse(&bytes)?))
+        } else if let Some(header) = bytes.get(..LINUX_HEADER_SIZE)
+            && (header[48..56] == *LINUX_MAGIC || header[56..60] == *LINUX_MAGIC2)
+        {
+            let text_offset = u64::from_le_bytes(header[8..16].try_into().unwrap());
+            // older kernels leave the size out
+            let image_size = u64::from_le_bytes(header[16..24].try_into().unwrap());
+            let addr = DRAM_START.checked_add(text_offset).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad text_offset in Linux Image header"))?;
+            Ok(Self::Linux { addr, size: image_size.max(bytes.len() as u64), data: bytes })
+        } else {
+            Ok(Self::Raw { addr: raw_addr, data: bytes })
+        }
+    }
+
+    pub fn load(&self, bus: &mut Bus) -> io::Result<()> {
+        let (addr, data, size) = match self {
+            Self::Elf(elf) => return elf.load(bus),
+            Self::Linux { addr, data, size } => (*addr, data, *size),
+            Self::Raw { addr, data } => (*addr, data, data.len() as u64),
+        };
+        bus.load(addr, data, size)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{size} bytes at {addr:#x} don't fit in memory")))
+    }
+
+    /// whether anything is loaded into `start..end`, bss included
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        let ranges = match self {
+            Self::Elf(elf) => elf.segments.iter().map(|segment| (segment.addr, segment.size)).collect(),
+            Self::Linux { addr, size, .. } => vec![(*addr, *size)],
+            Self::Raw { addr, data } => vec![(*addr, data.len() as u64)],
+        };
+        ranges.into_iter().any(|(addr, size)| addr < end && start < addr.saturating_add(size))
+    }
+
+    /// the physical address to start at
+    pub fn entry(&self) -> u64 {
+        match self {
+            Self::Elf(elf) => elf.physical_entry(),
+            Self::Linux { addr, .. } | Self::Raw { addr, .. } => *addr,
+        }
+    }
+
+    /// only ELFs have symbols
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        match self {
+            Self::Elf(elf) => elf.symbol(name),
+            Self::Linux { .. } | Self::Raw { .. } => None,
+        }
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, u64)> {
+        let elf = match self {
+            Self::Elf(elf) => Some(elf),
+            Self::Linux { .. } | Self::Raw { .. } => None,
+        };
+        elf.into_iter().flat_map(Elf::symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlaps() {
+        let raw = Image::Raw { addr: 0x8000_0000, data: vec![0; 0x1000] };
+        assert!(raw.overlaps(0x8000_0fff, 0x8000_2000));
+        assert!(!raw.overlaps(0x8000_1000, 0x8000_2000));
+        // a Linux Image takes up all of its size, not just the bytes in the file
+        let linux = Image::Linux { addr: 0x8020_0000, data: vec![0; 0x1000], size: 0x20_0000 };
+        assert!(linux.overlaps(0x803f_f000, 0x8040_0000));
+        assert!(!linux.overlaps(0x8000_0000, 0x8020_0000));
+    }
+}
@@ -0,0 +1,212 @@
+//! a raw DEFLATE (RFC 1951) decoder, enough to read compressed qcow2 clusters
+
+const MAX_BITS: usize = 15;
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// the order code length code lengths are stored in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl Bits<'_> {
+    fn bits(&mut self, count: u32) -> Option<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self.data.get(self.position / 8)?;
+            value |= ((byte >> (self.position % 8)) as u32 & 1) << i;
+            self.position += 1;
+        }
+        Some(value)
+    }
+
+    fn align_to_byte(&mut self) {
+        self.position = self.position.next_multiple_of(8);
+    }
+}
+
+/// a canonical Huffman code, decoded a bit at a time
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0; MAX_BITS + 2];
+        for bits in 1..=MAX_BITS {
+            offsets[bits + 1] = offsets[bits] + counts[bits];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Option<u16> {
+        // codes of each length follow on from the last code of the length before
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for length in 1..=MAX_BITS {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return self.symbols.get((index + code - first) as usize).copied();
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[0..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..288].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Option<(Huffman, Huffman)> {
+    let literals = bits.bits(5)? as usize + 257;
+    let distances = bits.bits(5)? as usize + 1;
+    let code_lengths = bits.bits(4)? as usize + 4;
+
+    let mut lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths);
+
+    let mut lengths = vec![0u8; literals + distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let (value, repeat) = match code_length_code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.get(i.checked_sub(1)?)?, 3 + bits.bits(2)? as usize),
+            17 => (0, 3 + bits.bits(3)? as usize),
+            18 => (0, 11 + bits.bits(7)? as usize),
+            _ => return None,
+        };
+        lengths.get_mut(i..i + repeat)?.fill(value);
+        i += repeat;
+    }
+
+    Some((Huffman::new(&lengths[..literals]), Huffman::new(&lengths[literals..])))
+}
+
+/// decompresses until the final block or until `limit` bytes have come out
+pub fn inflate(data: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let mut bits = Bits { data, position: 0 };
+    let mut out = Vec::with_capacity(limit);
+
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align_to_byte();
+                let len = bits.bits(16)? as usize;
+                let _complement = bits.bits(16)?;
+                let start = bits.position / 8;
+                out.extend_from_slice(data.get(start..start + len)?);
+                bits.position += len * 8;
+            }
+            kind @ (1 | 2) => {
+                let (literal, distance) = if kind == 1 { fixed_codes() } else { dynamic_codes(&mut bits)? };
+                loop {
+                    let symbol = literal.decode(&mut bits)? as usize;
+                    match symbol {
+                        0..=255 => out.push(symbol as u8),
+                        256 => break,
+                        _ => {
+                            let i = symbol - 257;
+                            let len = *LENGTH_BASE.get(i)? as usize + bits.bits(*LENGTH_EXTRA.get(i)? as u32)? as usize;
+                            let d = distance.decode(&mut bits)? as usize;
+                            let dist = *DIST_BASE.get(d)? as usize + bits.bits(*DIST_EXTRA.get(d)? as u32)? as usize;
+                            let start = out.len().checked_sub(dist)?;
+                            // copies can overlap what they're producing so go a byte at a time
+                            for j in 0..len {
+                                out.push(out[start + j]);
+                            }
+                        }
+                    }
+                    if out.len() >= limit {
+                        break;
+                    }
+                }
+            }
+            _ => return None,
+        }
+
+        if last || out.len() >= limit {
+            out.truncate(limit);
+            return Some(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// parses hex into bytes, the streams here came out of zlib with raw deflate
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn stored_block() {
+        assert_eq!(inflate(&hex("010c00f3ff68656c6c6f2c20776f726c64"), 64).unwrap(), b"hello, world");
+    }
+
+    #[test]
+    fn fixed_huffman_block() {
+        // the matches copy from output they overlap
+        assert_eq!(inflate(&hex("4b4c4a4e44428a00"), 64).unwrap(), b"abcabcabcabcabc!");
+    }
+
+    #[test]
+    fn dynamic_huffman_block() {
+        let data = hex("2d8a810900300cc26e8de9ff376c968208c64402c4d64f70874b8729758f0a39dba207");
+        assert_eq!(data[0] >> 1 & 0b11, 2);
+        assert_eq!(inflate(&data, 64).unwrap(), b"bcabaaabcaaabaabbacabcaacbaaabdadabaacaabbaaabababaaabcccaab");
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        assert_eq!(inflate(&hex("4b4c4a4e44428a00"), 5).unwrap(), b"abcab");
+    }
+
+    #[test]
+    fn malformed_input() {
+        for (what, data) in [
+            ("nothing", ""),
+            ("reserved block type", "07"),
+            ("stored block longer than the data", "010c00f3ff68656c6c6f"),
+            ("match before the start", "0302"),
+            ("truncated code lengths", "2d8a810900"),
+            ("no final block", "000000ffff"),
+        ] {
+            assert_eq!(inflate(&hex(data), 64), None, "{what}");
+        }
+    }
+}
@@ -0,0 +1,37 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    SupervisorSoftware,
+    MachineSoftware,
+    SupervisorTimer,
+    MachineTimer,
+    SupervisorExternal,
+    MachineExternal,
+}
+
+impl Interrupt {
+    /// in the order they are taken when several are pending at once
+    pub const PRIORITY: [Interrupt; 6] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal,
+        Interrupt::SupervisorSoftware,
+        Interrupt::SupervisorTimer,
+    ];
+
+    pub fn to_code(self) -> u64 {
+        match self {
+            Interrupt::SupervisorSoftware => 1,
+            Interrupt::MachineSoftware => 3,
+            Interrupt::SupervisorTimer => 5,
+            Interrupt::MachineTimer => 7,
+            Interrupt::SupervisorExternal => 9,
+            Interrupt::MachineExternal => 11,
+        }
+    }
+
+    /// the bit for this interrupt in mip and mie
+    pub fn mask(self) -> u64 {
+        1 << self.to_code()
+    }
+}
@@ -1,34 +1,337 @@
-use crate::{bus::DRAM_START, cpu::Cpu, exception::Exception};
+use std::{collections::BTreeMap, env, fs::{self, File}, io, path::{Path, PathBuf}, process};
+
+use crate::{bus::{Bus, DRAM_START, DTB_MAX_SIZE}, config::{Config, Serial, DEFAULT_BOOTARGS, DEFAULT_MEMORY_SIZE, USAGE}, cpu::{Cpu, Sbi}, disk::{Disk, Overlay}, exception::Exception, fdt::{Chosen, DeviceTree}, htif::Htif, image::Image, test_finisher::Shutdown, virtio::{block::Block, console::{self, Console, Port}, net::Net, p9::{self, P9}, rng::{Prng, Rng}}, uart::Uart};
 
 mod dram;
 mod exception;
+mod float;
+mod interrupt;
 mod bus;
 mod cpu;
 mod rom;
 mod uart;
 mod clint;
+mod plic;
+mod test_finisher;
+mod virtio;
+mod disk;
+mod inflate;
+mod net;
+mod elf;
+mod htif;
+mod image;
+mod config;
+mod fdt;
+
+/// the name the control port shows up as under /dev/virtio-ports in the guest
+const CONTROL_PORT_NAME: &str = "org.riscv-emulator.control";
+/// where a kernel that isn't an ELF or a Linux Image goes, the 2M in that
+/// RV64 Linux expects to be loaded at and fw_jump jumps to
+const KERNEL_ADDR: u64 = DRAM_START + 0x200000;
 
 fn main() {
-    let mut cpu = Cpu::new();
+    let config = match Config::parse(env::args().skip(1)) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{USAGE}");
+            return;
+        }
+        Err(e) => {
+            eprintln!("{e}, see --help");
+            process::exit(1);
+        }
+    };
+
+    // a device tree of the user's is read first since the memory it describes
+    // is the memory there is
+    let user_tree = config.dtb.as_ref().map(|path| match fs::read(path).and_then(|bytes| DeviceTree::parse(&bytes)) {
+        Ok(tree) => (path, tree),
+        Err(e) => {
+            eprintln!("couldn't read {}: {e}", path.display());
+            process::exit(1);
+        }
+    });
+    let memory_size = match &user_tree {
+        Some((path, tree)) => {
+            let regions = tree.regions();
+            let Some(memory) = regions.iter().find(|region| region.memory && region.addr == DRAM_START) else {
+                eprintln!("{} has no memory at {DRAM_START:#x}", path.display());
+                process::exit(1);
+            };
+            if !config::valid_memory_size(memory.size) {
+                eprintln!("{} has a bad memory size {:#x}", path.display(), memory.size);
+                process::exit(1);
+            }
+            if config.memory_size.is_some_and(|size| size != memory.size) {
+                eprintln!("warning: using the {} MiB of memory {} describes", memory.size >> 20, path.display());
+            }
+            memory.size
+        }
+        None => config.memory_size.unwrap_or(DEFAULT_MEMORY_SIZE),
+    };
+
+    let uart = match &config.serial {
+        Serial::Stdio => Uart::new(),
+        Serial::Null => Uart::with_io(None, Box::new(io::sink())),
+        Serial::File(path) => match File::create(path) {
+            Ok(file) => Uart::with_io(None, Box::new(file)),
+            Err(e) => {
+                eprintln!("couldn't create {}: {e}", path.display());
+                process::exit(1);
+            }
+        },
+        Serial::Socket(path) => match console::unix_socket(path) {
+            Ok((input, output)) => Uart::with_io(Some(input), output),
+            Err(e) => {
+                eprintln!("couldn't listen on {}: {e}", path.display());
+                process::exit(1);
+            }
+        },
+    };
+    let mut cpu = Cpu::new(Bus::new(memory_size, config.clock, uart), config.reset_vector);
+
+    // the firmware is an ELF or a raw binary the boot ROM starts at its load
+    // address, the kernel, initrd and device tree are just put in memory for it to find
+    let open = |path: &Path, addr| match Image::open(path, addr) {
+        Ok(image) => (path.to_path_buf(), image),
+        Err(e) => {
+            eprintln!("couldn't read {}: {e}", path.display());
+            process::exit(1);
+        }
+    };
+    // with the built-in SBI there's no firmware and the kernel comes first
+    let mut images = Vec::new();
+    if !config.sbi {
+        images.push(open(&config.bios, config.bios_addr));
+    }
+    let mut kernel_entry = KERNEL_ADDR;
+    if let Some(kernel) = &config.kernel {
+        let kernel = open(kernel, KERNEL_ADDR);
+        kernel_entry = kernel.1.entry();
+        images.push(kernel);
+    }
+    let mut initrd_range = None;
+    if let Some(initrd) = &config.initrd {
+        // an initrd is data even if it happens to start like an ELF
+        let addr = initrd_addr(memory_size);
+        match fs::read(initrd) {
+            Ok(data) => {
+                initrd_range = Some((addr, addr + data.len() as u64));
+                images.push((initrd.clone(), Image::Raw { addr, data }));
+            }
+            Err(e) => {
+                eprintln!("couldn't read {}: {e}", initrd.display());
+                process::exit(1);
+            }
+        }
+    }
+
+    let (firmware_path, firmware) = &images[0];
+    // riscv-tests and the proxy kernel find the host through the tohost and
+    // fromhost symbols of an ELF
+    if let Some(tohost) = firmware.symbol("tohost") {
+        let mut target_args = vec![firmware_path.display().to_string()];
+        target_args.extend(config.program_args.iter().cloned());
+        // the program's console is HTIF now, so it gets the host's input
+        let input = cpu.bus.uart.take_input();
+        cpu.bus.htif = Some(Htif::new(tohost, firmware.symbol("fromhost"), target_args, input));
+    }
+
+    if config.trace {
+        let mut symbols = BTreeMap::new();
+        for (_, image) in &images {
+            for (name, addr) in image.symbols() {
+                symbols.entry(addr).or_insert_with(|| name.to_string());
+            }
+        }
+        cpu.trace = Some(symbols);
+    }
+
+    for drive in &config.drives {
+        let disk = if drive.overlay.is_some() || drive.discard_overlay {
+            // the base image is never written when there's an overlay
+            let overlay = drive.overlay.clone().unwrap_or_else(|| {
+                env::temp_dir().join(format!("riscv-emulator-{}-{}.overlay", process::id(), cpu.bus.virtio_count()))
+            });
+            disk::open(&drive.path, true)
+                .and_then(|base| Overlay::open(base, &overlay, drive.discard_overlay))
+                .map(|disk| Box::new(disk) as Box<dyn Disk>)
+        } else {
+            disk::open(&drive.path, drive.read_only)
+        };
+
+        match disk {
+            Ok(disk) => {
+                cpu.bus.attach_virtio(Box::new(Block::new(disk)));
+            }
+            Err(e) => {
+                eprintln!("couldn't open {}: {e}", drive.path.display());
+                process::exit(1);
+            }
+        }
+    }
+
+    // a virtio console whose first port writes alongside the UART and whose
+    // second is a control channel for whoever connects to the socket
+    if let Some(path) = &config.console_socket {
+        match console::unix_socket(path) {
+            Ok((input, output)) => {
+                let ports = vec![
+                    Port::new(None, None, Box::new(io::stderr())),
+                    Port::new(Some(CONTROL_PORT_NAME.to_string()), Some(input), output),
+                ];
+                cpu.bus.attach_virtio(Box::new(Console::new(ports)));
+            }
+            Err(e) => {
+                eprintln!("couldn't listen on {}: {e}", path.display());
+                process::exit(1);
+            }
+        }
+    }
+
+    // a host directory the guest can mount with 9p over virtio
+    if let Some(share) = &config.share {
+        match p9::check_root(&share.dir) {
+            Ok(root) => {
+                cpu.bus.attach_virtio(Box::new(P9::new(root, share.tag.clone(), share.security)));
+            }
+            Err(e) => {
+                eprintln!("couldn't share {}: {e}", share.dir.display());
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(network) = &config.net {
+        let backend = net::open(&network.spec).and_then(|backend| match &network.pcap {
+            Some(path) => Ok(Box::new(net::Pcap::new(backend, path)?) as Box<dyn net::Backend>),
+            None => Ok(backend),
+        });
+        match backend {
+            Ok(backend) => {
+                cpu.bus.attach_virtio(Box::new(Net::new(network.mac, backend)));
+            }
+            Err(e) => {
+                eprintln!("couldn't set up networking {}: {e}", network.spec);
+                process::exit(1);
+            }
+        }
+    }
+
+    let prng = match config.rng_seed {
+        Some(seed) => Prng::from_seed(seed),
+        None => Prng::from_host(),
+    };
+    cpu.bus.attach_virtio(Box::new(Rng::new(prng)));
 
-    cpu.bus.dram.load(include_bytes!("../riscv-pk/build/bbl.bin"));
-    cpu.bus.dtb.load(include_bytes!("../emulator.dtb"));
-    cpu.set_pc(DRAM_START);
+    // the device tree is made once every device is attached so it describes all of them
+    let dtb = match user_tree {
+        Some((path, mut tree)) => {
+            for problem in cpu.bus.check_device_tree(&tree) {
+                eprintln!("warning: {}: {problem}", path.display());
+            }
+            // the command line replaces the bootargs in /chosen, and only an
+            // initrd that was loaded is worth telling the kernel about
+            let mut chosen = tree.chosen();
+            if let Some(append) = &config.append {
+                chosen.bootargs = append.clone();
+            }
+            chosen.initrd = initrd_range;
+            tree.set_chosen(&chosen);
+            tree.to_blob()
+        }
+        None => {
+            let bootargs = config.append.clone().unwrap_or_else(|| DEFAULT_BOOTARGS.to_string());
+            cpu.bus.device_tree(&cpu.isa(), &Chosen { bootargs, initrd: initrd_range })
+        }
+    };
+    if dtb.len() as u64 > DTB_MAX_SIZE {
+        eprintln!("the device tree is bigger than the {DTB_MAX_SIZE} bytes it can have");
+        process::exit(1);
+    }
+    let dtb_addr = cpu.bus.dtb_addr();
+    let dtb_end = dtb_addr + dtb.len() as u64;
+    if let Some((path, _)) = images.iter().find(|(_, image)| image.overlaps(dtb_addr, dtb_end)) {
+        eprintln!("the device tree at {dtb_addr:#x}-{dtb_end:#x} would overlap {}", path.display());
+        process::exit(1);
+    }
+    images.push((PathBuf::from("the device tree"), Image::Raw { addr: dtb_addr, data: dtb }));
+    if config.sbi {
+        cpu.set_sbi(Sbi::new(kernel_entry, dtb_addr));
+    } else {
+        cpu.bus.rom.load(&rom::boot_code(images[0].1.entry(), dtb_addr, kernel_entry));
+    }
+    load(&mut cpu, &images);
 
+    let mut executed = 0u64;
     loop {
+        if config.max_insns == Some(executed) {
+            println!("stopped after {executed} instructions");
+            cpu.print_state();
+            exit(&cpu, &config, 124);
+        }
+        executed += 1;
+
+        cpu.tick();
+
+        if let Some(interrupt) = cpu.pending_interrupt() {
+            cpu.handle_interrupt(interrupt);
+        }
+
         if let Err(exception) = cpu.execute() {
             match exception {
-                Exception::IllegalInstruction(_) => todo!(),
-                Exception::LoadAddressMisaligned => todo!(),
-                Exception::LoadAccessFault => todo!(),
-                Exception::StoreAddressMisaligned => todo!(),
-                Exception::StoreAccessFault => todo!(),
+                Exception::InstructionAccessFault(_) => (),
+                Exception::IllegalInstruction(_) => (),
+                Exception::Breakpoint => (),
+                Exception::LoadAddressMisaligned(_) => (),
+                Exception::LoadAccessFault(_) => (),
+                Exception::StoreAddressMisaligned(_) => (),
+                Exception::StoreAccessFault(_) => (),
                 Exception::ECallFromU => (),
                 Exception::ECallFromS => (),
                 Exception::ECallFromM => (),
+                Exception::InstructionPageFault(_) => (),
+                Exception::LoadPageFault(_) => (),
+                Exception::StorePageFault(_) => (),
                 Exception::HardwareError => todo!(),
             }
             cpu.handle_trap(exception);
         }
+
+        match cpu.bus.take_shutdown() {
+            Some(Shutdown::Exit(status)) => exit(&cpu, &config, status),
+            Some(Shutdown::Reset) => {
+                cpu.reset();
+                load(&mut cpu, &images);
+            }
+            None => {}
+        }
+    }
+}
+
+/// puts the firmware and whatever it boots in memory, the boot ROM starts it
+fn load(cpu: &mut Cpu, images: &[(PathBuf, Image)]) {
+    for (path, image) in images {
+        if let Err(e) = image.load(&mut cpu.bus) {
+            eprintln!("couldn't load {}: {e}", path.display());
+            process::exit(1);
+        }
+    }
+}
+
+/// like QEMU, halfway into small memories and 128M in otherwise, far enough
+/// that the kernel won't unpack over it and low enough to be mapped early on
+fn initrd_addr(memory_size: u64) -> u64 {
+    const LIMIT: u64 = 128 * 1024 * 1024;
+    DRAM_START + if memory_size < 2 * LIMIT { memory_size / 2 } else { LIMIT }
+}
+
+fn exit(cpu: &Cpu, config: &Config, status: i32) -> ! {
+    // stdout can be the program's own output, which this isn't
+    if config.tlb_stats {
+        let (itlb, dtlb) = cpu.tlb_stats();
+        eprintln!("itlb: {} hits, {} misses", itlb.hits, itlb.misses);
+        eprintln!("dtlb: {} hits, {} misses", dtlb.hits, dtlb.misses);
     }
+    process::exit(status);
 }
@@ -0,0 +1,215 @@
+use std::{fs::File, io::{self, Read, Write}, os::unix::net::{UnixListener, UnixStream}, path::Path, sync::{Arc, Mutex, mpsc::{self, Receiver, Sender}}, thread, time::{SystemTime, UNIX_EPOCH}};
+
+use slirp::{Forward, Protocol, Slirp};
+
+mod packet;
+mod slirp;
+mod tcp;
+
+/// frames bigger than this from a socket peer mean it's not speaking the same protocol
+const MAX_FRAME_SIZE: usize = 65536;
+
+/// where a network device's Ethernet frames go and come from
+pub trait Backend {
+    /// a frame from the guest
+    fn send(&mut self, frame: &[u8]);
+
+    /// the next frame for the guest, without waiting for one
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+/// a backend running on another thread that frames are passed to and from
+struct Channel {
+    to: Sender<Vec<u8>>,
+    from: Receiver<Vec<u8>>,
+}
+
+impl Backend for Channel {
+    fn send(&mut self, frame: &[u8]) {
+        let _ = self.to.send(frame.to_vec());
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.from.try_recv().ok()
+    }
+}
+
+/// the user-mode NAT stack, the guest gets 10.0.2.15 by DHCP and the host is 10.0.2.2
+fn user(forwards: &[Forward]) -> io::Result<Channel> {
+    let (to_guest, from) = mpsc::channel();
+    let (to, from_guest) = mpsc::channel();
+    Slirp::new(forwards, to_guest)?.spawn(from_guest);
+    Ok(Channel { to, from })
+}
+
+/// raw frames to and from another emulator over a unix socket, each one
+/// preceded by its length as a big endian u32
+pub struct Socket {
+    peer: Arc<Mutex<Option<UnixStream>>>,
+    from: Receiver<Vec<u8>>,
+}
+
+impl Socket {
+    /// waits for peers to connect, one at a time, frames sent with nobody there are lost
+    pub fn listen(path: &Path) -> io::Result<Self> {
+        // a socket left over from an earlier run would stop the bind
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        let peer = Arc::new(Mutex::new(None));
+        let (sender, from) = mpsc::channel();
+
+        let shared = peer.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                *shared.lock().unwrap() = stream.try_clone().ok();
+                if !read_frames(stream, &sender) {
+                    return;
+                }
+                *shared.lock().unwrap() = None;
+            }
+        });
+        Ok(Self { peer, from })
+    }
+
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        let peer = Arc::new(Mutex::new(Some(stream.try_clone()?)));
+        let (sender, from) = mpsc::channel();
+        thread::spawn(move || read_frames(stream, &sender));
+        Ok(Self { peer, from })
+    }
+}
+
+/// passes frames from a peer on until it goes away, returns false if nobody's listening anymore
+fn read_frames(mut stream: UnixStream, sender: &Sender<Vec<u8>>) -> bool {
+    loop {
+        let mut len = [0; 4];
+        if stream.read_exact(&mut len).is_err() {
+            return true;
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME_SIZE {
+            return true;
+        }
+        let mut frame = vec![0; len];
+        if stream.read_exact(&mut frame).is_err() {
+            return true;
+        }
+        if sender.send(frame).is_err() {
+            return false;
+        }
+    }
+}
+
+impl Backend for Socket {
+    fn send(&mut self, frame: &[u8]) {
+        let mut peer = self.peer.lock().unwrap();
+        if let Some(stream) = peer.as_mut() {
+            let mut message = (frame.len() as u32).to_be_bytes().to_vec();
+            message.extend_from_slice(frame);
+            if stream.write_all(&message).is_err() {
+                *peer = None;
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.from.try_recv().ok()
+    }
+}
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// records every frame going through another backend to a pcap file
+pub struct Pcap {
+    inner: Box<dyn Backend>,
+    file: File,
+}
+
+impl Pcap {
+    pub fn new(inner: Box<dyn Backend>, path: &Path) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // no timezone offset or timestamp accuracy
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self { inner, file })
+    }
+
+    fn record(&mut self, frame: &[u8]) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + frame.len());
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(frame);
+        // each frame is written straight away so the capture can be watched live
+        let _ = self.file.write_all(&record);
+    }
+}
+
+impl Backend for Pcap {
+    fn send(&mut self, frame: &[u8]) {
+        self.record(frame);
+        self.inner.send(frame);
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let frame = self.inner.receive()?;
+        self.record(&frame);
+        Some(frame)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// `tcp:HOSTPORT-GUESTPORT` or `udp:HOSTPORT-GUESTPORT`
+fn parse_forward(spec: &str) -> io::Result<Forward> {
+    let bad = || invalid(format!("bad port forward {spec}"));
+    let (protocol, ports) = spec.split_once(':').ok_or_else(bad)?;
+    let protocol = match protocol {
+        "tcp" => Protocol::Tcp,
+        "udp" => Protocol::Udp,
+        _ => return Err(bad()),
+    };
+    let (host_port, guest_port) = ports.split_once('-').ok_or_else(bad)?;
+    Ok(Forward { protocol, host_port: host_port.parse().map_err(|_| bad())?, guest_port: guest_port.parse().map_err(|_| bad())? })
+}
+
+/// a backend from a description like `user,hostfwd=tcp:2222-22`,
+/// `socket,listen=PATH` or `socket,connect=PATH`
+pub fn open(spec: &str) -> io::Result<Box<dyn Backend>> {
+    let mut parts = spec.split(',');
+    let kind = parts.next().unwrap_or_default();
+    let options: Vec<(&str, &str)> = parts.map(|part| part.split_once('=').unwrap_or((part, ""))).collect();
+
+    match kind {
+        "user" => {
+            let mut forwards = Vec::new();
+            for (key, value) in options {
+                match key {
+                    "hostfwd" => forwards.push(parse_forward(value)?),
+                    _ => return Err(invalid(format!("unknown user network option {key}"))),
+                }
+            }
+            Ok(Box::new(user(&forwards)?))
+        }
+        "socket" => match options.as_slice() {
+            [("listen", path)] => Ok(Box::new(Socket::listen(Path::new(path))?)),
+            [("connect", path)] => Ok(Box::new(Socket::connect(Path::new(path))?)),
+            _ => Err(invalid("socket networking needs listen=PATH or connect=PATH".to_string())),
+        },
+        _ => Err(invalid(format!("unknown network backend {kind}"))),
+    }
+}
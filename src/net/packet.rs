@@ -0,0 +1,173 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub type Mac = [u8; 6];
+
+pub const BROADCAST: Mac = [0xff; 6];
+
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub const IPV4_HEADER_SIZE: usize = 20;
+pub const UDP_HEADER_SIZE: usize = 8;
+
+pub mod protocol {
+    pub const ICMP: u8 = 1;
+    pub const TCP: u8 = 6;
+    pub const UDP: u8 = 17;
+}
+
+/// more fragments and the fragment offset, either means this is part of a bigger packet
+const FRAGMENT_MASK: u16 = 0x3fff;
+const DONT_FRAGMENT: u16 = 0x4000;
+const TTL: u8 = 64;
+
+pub fn be_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+pub fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn ipv4_at(bytes: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::from(be_u32(bytes, offset))
+}
+
+/// the ones' complement sum that IP, ICMP, UDP and TCP checksums are built from
+fn sum(data: &[u8], mut total: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        total += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        total += (*last as u32) << 8;
+    }
+    total
+}
+
+fn fold(mut total: u32) -> u16 {
+    while total > 0xffff {
+        total = (total & 0xffff) + (total >> 16);
+    }
+    !(total as u16)
+}
+
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum(data, 0))
+}
+
+/// TCP and UDP checksums also cover the addresses from the IP header
+pub fn transport_checksum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, segment: &[u8]) -> u16 {
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&src.octets());
+    pseudo[4..8].copy_from_slice(&dst.octets());
+    pseudo[9] = protocol;
+    pseudo[10..12].copy_from_slice(&(segment.len() as u16).to_be_bytes());
+    fold(sum(segment, sum(&pseudo, 0)))
+}
+
+pub struct Ethernet<'a> {
+    pub dst: Mac,
+    pub src: Mac,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+pub fn parse_ethernet(frame: &[u8]) -> Option<Ethernet<'_>> {
+    if frame.len() < ETHERNET_HEADER_SIZE {
+        return None;
+    }
+    Some(Ethernet {
+        dst: frame[0..6].try_into().unwrap(),
+        src: frame[6..12].try_into().unwrap(),
+        ethertype: be_u16(frame, 12),
+        payload: &frame[ETHERNET_HEADER_SIZE..],
+    })
+}
+
+pub fn ethernet(dst: Mac, src: Mac, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&src);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub struct Ipv4<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+/// fragments are dropped, nothing the guest sends with a 1500 byte MTU needs them
+pub fn parse_ipv4(packet: &[u8]) -> Option<Ipv4<'_>> {
+    if packet.len() < IPV4_HEADER_SIZE || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = (packet[0] & 0xf) as usize * 4;
+    let total_len = be_u16(packet, 2) as usize;
+    if header_len < IPV4_HEADER_SIZE || total_len < header_len || total_len > packet.len() {
+        return None;
+    }
+    if be_u16(packet, 6) & FRAGMENT_MASK != 0 {
+        return None;
+    }
+    Some(Ipv4 {
+        src: ipv4_at(packet, 12),
+        dst: ipv4_at(packet, 16),
+        protocol: packet[9],
+        payload: &packet[header_len..total_len],
+    })
+}
+
+pub fn ipv4(id: u16, src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0; IPV4_HEADER_SIZE];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&((IPV4_HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+    packet[4..6].copy_from_slice(&id.to_be_bytes());
+    packet[6..8].copy_from_slice(&DONT_FRAGMENT.to_be_bytes());
+    packet[8] = TTL;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&src.octets());
+    packet[16..20].copy_from_slice(&dst.octets());
+    let checksum = checksum(&packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+pub struct Udp<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+pub fn parse_udp(datagram: &[u8]) -> Option<Udp<'_>> {
+    if datagram.len() < UDP_HEADER_SIZE {
+        return None;
+    }
+    let len = be_u16(datagram, 4) as usize;
+    if len < UDP_HEADER_SIZE || len > datagram.len() {
+        return None;
+    }
+    Some(Udp { src_port: be_u16(datagram, 0), dst_port: be_u16(datagram, 2), payload: &datagram[UDP_HEADER_SIZE..len] })
+}
+
+pub fn udp(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(UDP_HEADER_SIZE + payload.len());
+    datagram.extend_from_slice(&src.port().to_be_bytes());
+    datagram.extend_from_slice(&dst.port().to_be_bytes());
+    datagram.extend_from_slice(&((UDP_HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    let checksum = match transport_checksum(*src.ip(), *dst.ip(), protocol::UDP, &datagram) {
+        // a zero checksum means there isn't one, so all ones is sent instead
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    datagram
+}
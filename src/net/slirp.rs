@@ -0,0 +1,383 @@
+use std::{collections::{HashMap, hash_map::Entry}, fs, io, net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket}, sync::mpsc::{Receiver, RecvTimeoutError, Sender}, thread, time::{Duration, Instant}};
+
+use super::{packet::{self, BROADCAST, ETHERTYPE_ARP, ETHERTYPE_IPV4, Mac, protocol}, tcp::Tcp};
+
+/// the addresses of the network the guest sees, the same ones QEMU's user networking uses
+pub const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+pub const DNS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+pub const GUEST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+/// the MAC every address on the virtual network answers ARP with
+pub const GATEWAY_MAC: Mac = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+
+/// how long the stack waits for a frame from the guest before checking host sockets
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+/// UDP mappings nobody has used for this long are closed
+const UDP_TIMEOUT: Duration = Duration::from_secs(120);
+const DNS_PORT: u16 = 53;
+const MAX_DATAGRAM: usize = 65536;
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const ARP_PACKET_SIZE: usize = 28;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+mod dhcp {
+    pub const SERVER_PORT: u16 = 67;
+    pub const CLIENT_PORT: u16 = 68;
+    pub const BOOTREQUEST: u8 = 1;
+    pub const BOOTREPLY: u8 = 2;
+    pub const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+    /// the fixed part of a BOOTP message before the options
+    pub const HEADER_SIZE: usize = 236;
+    pub const LEASE_TIME: u32 = 86400;
+
+    pub mod option {
+        pub const PAD: u8 = 0;
+        pub const SUBNET_MASK: u8 = 1;
+        pub const ROUTER: u8 = 3;
+        pub const DNS: u8 = 6;
+        pub const LEASE_TIME: u8 = 51;
+        pub const MESSAGE_TYPE: u8 = 53;
+        pub const SERVER_ID: u8 = 54;
+        pub const END: u8 = 255;
+    }
+
+    pub mod message {
+        pub const DISCOVER: u8 = 1;
+        pub const OFFER: u8 = 2;
+        pub const REQUEST: u8 = 3;
+        pub const ACK: u8 = 5;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// a port on the host's loopback interface that's forwarded to a port on the guest
+#[derive(Clone, Copy, Debug)]
+pub struct Forward {
+    pub protocol: Protocol,
+    pub host_port: u16,
+    pub guest_port: u16,
+}
+
+/// where frames for the guest go, addressed to whatever MAC the guest last used
+pub struct Link {
+    to_guest: Sender<Vec<u8>>,
+    guest_mac: Mac,
+    ip_id: u16,
+}
+
+impl Link {
+    pub fn send_ipv4(&mut self, src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) {
+        self.ip_id = self.ip_id.wrapping_add(1);
+        let packet = packet::ipv4(self.ip_id, src, dst, protocol, payload);
+        self.send(self.guest_mac, ETHERTYPE_IPV4, &packet);
+    }
+
+    pub fn send_udp(&mut self, src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) {
+        self.send_ipv4(*src.ip(), *dst.ip(), protocol::UDP, &packet::udp(src, dst, payload));
+    }
+
+    fn send(&mut self, dst: Mac, ethertype: u16, payload: &[u8]) {
+        let _ = self.to_guest.send(packet::ethernet(dst, GATEWAY_MAC, ethertype, payload));
+    }
+}
+
+/// the guest's view of where a host address is, the host's loopback is the gateway
+fn guest_view(addr: SocketAddr) -> Option<SocketAddrV4> {
+    match addr {
+        SocketAddr::V4(addr) if addr.ip().is_loopback() => Some(SocketAddrV4::new(GATEWAY, addr.port())),
+        SocketAddr::V4(addr) => Some(addr),
+        SocketAddr::V6(_) => None,
+    }
+}
+
+/// the host's first IPv4 nameserver, which guest DNS queries are sent on to
+fn host_nameserver() -> Option<SocketAddr> {
+    let resolv = fs::read_to_string("/etc/resolv.conf").ok()?;
+    resolv.lines().find_map(|line| {
+        let ip: Ipv4Addr = line.strip_prefix("nameserver")?.trim().parse().ok()?;
+        Some(SocketAddr::new(IpAddr::V4(ip), DNS_PORT))
+    })
+}
+
+/// datagrams from one guest port, sent out of a host socket of its own
+struct UdpMapping {
+    socket: UdpSocket,
+    /// what the guest addressed each host address as, so replies come back from there
+    peers: HashMap<SocketAddr, SocketAddrV4>,
+    last_used: Instant,
+}
+
+struct UdpForward {
+    socket: UdpSocket,
+    guest_port: u16,
+}
+
+/// a user-mode network stack along the lines of slirp, the guest's connections
+/// become ordinary host sockets so nothing needs root
+pub struct Slirp {
+    link: Link,
+    nameserver: Option<SocketAddr>,
+    udp: HashMap<u16, UdpMapping>,
+    udp_forwards: Vec<UdpForward>,
+    /// host peers of forwarded UDP ports by the guest port and the port the
+    /// gateway sent from, so the guest's replies find their way back
+    udp_forward_peers: HashMap<(u16, u16), (usize, SocketAddr)>,
+    tcp: Tcp,
+}
+
+impl Slirp {
+    /// binds the forwarded ports now so mistakes show up before the guest starts
+    pub fn new(forwards: &[Forward], to_guest: Sender<Vec<u8>>) -> io::Result<Self> {
+        let mut udp_forwards = Vec::new();
+        let mut tcp_listeners = Vec::new();
+        for forward in forwards {
+            let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, forward.host_port);
+            match forward.protocol {
+                Protocol::Tcp => {
+                    let listener = TcpListener::bind(addr)?;
+                    listener.set_nonblocking(true)?;
+                    tcp_listeners.push((listener, forward.guest_port));
+                }
+                Protocol::Udp => {
+                    let socket = UdpSocket::bind(addr)?;
+                    socket.set_nonblocking(true)?;
+                    udp_forwards.push(UdpForward { socket, guest_port: forward.guest_port });
+                }
+            }
+        }
+
+        Ok(Self {
+            link: Link { to_guest, guest_mac: BROADCAST, ip_id: 0 },
+            nameserver: host_nameserver(),
+            udp: HashMap::new(),
+            udp_forwards,
+            udp_forward_peers: HashMap::new(),
+            tcp: Tcp::new(tcp_listeners),
+        })
+    }
+
+    /// runs the stack on its own thread, frames go in and out through channels
+    pub fn spawn(mut self, from_guest: Receiver<Vec<u8>>) {
+        thread::spawn(move || {
+            loop {
+                match from_guest.recv_timeout(POLL_INTERVAL) {
+                    Ok(frame) => {
+                        self.handle_frame(&frame);
+                        for frame in from_guest.try_iter() {
+                            self.handle_frame(&frame);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+                self.poll();
+            }
+        });
+    }
+
+    /// the host address a guest destination ends up at, if it's somewhere the guest can reach
+    fn host_addr(&self, dst: SocketAddrV4) -> Option<SocketAddr> {
+        match *dst.ip() {
+            GATEWAY => Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), dst.port())),
+            DNS if dst.port() == DNS_PORT => self.nameserver,
+            ip if ip.is_broadcast() || ip.is_multicast() || is_local(ip) => None,
+            _ => Some(SocketAddr::V4(dst)),
+        }
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) {
+        let Some(ethernet) = packet::parse_ethernet(frame) else { return };
+        // unicast frames for other machines aren't ours to answer
+        if ethernet.dst != GATEWAY_MAC && ethernet.dst[0] & 1 == 0 {
+            return;
+        }
+        self.link.guest_mac = ethernet.src;
+        match ethernet.ethertype {
+            ETHERTYPE_ARP => self.handle_arp(ethernet.payload),
+            ETHERTYPE_IPV4 => {
+                let Some(ip) = packet::parse_ipv4(ethernet.payload) else { return };
+                match ip.protocol {
+                    protocol::ICMP => self.handle_icmp(ip.src, ip.dst, ip.payload),
+                    protocol::UDP => self.handle_udp(ip.dst, ip.payload),
+                    protocol::TCP => {
+                        let dst_port = ip.payload.get(2..4).map_or(0, |port| u16::from_be_bytes([port[0], port[1]]));
+                        let host_addr = self.host_addr(SocketAddrV4::new(ip.dst, dst_port));
+                        self.tcp.handle(&mut self.link, ip.src, ip.dst, ip.payload, host_addr);
+                    }
+                    _ => {}
+                }
+            }
+            // IPv6 and anything else doesn't go anywhere
+            _ => {}
+        }
+    }
+
+    /// answers for every address on the virtual network except the guest's own
+    fn handle_arp(&mut self, arp: &[u8]) {
+        if arp.len() < ARP_PACKET_SIZE || packet::be_u16(arp, 6) != ARP_REQUEST {
+            return;
+        }
+        let sender_mac = &arp[8..14];
+        let sender_ip = &arp[14..18];
+        let target_ip = Ipv4Addr::from(packet::be_u32(arp, 24));
+        if !is_local(target_ip) || target_ip == GUEST {
+            return;
+        }
+
+        let mut reply = arp[..8].to_vec();
+        reply[6..8].copy_from_slice(&ARP_REPLY.to_be_bytes());
+        reply.extend_from_slice(&GATEWAY_MAC);
+        reply.extend_from_slice(&target_ip.octets());
+        reply.extend_from_slice(sender_mac);
+        reply.extend_from_slice(sender_ip);
+        self.link.send(self.link.guest_mac, ETHERTYPE_ARP, &reply);
+    }
+
+    /// only the virtual addresses answer pings, reaching further would need raw sockets
+    fn handle_icmp(&mut self, src: Ipv4Addr, dst: Ipv4Addr, icmp: &[u8]) {
+        if icmp.len() < 8 || icmp[0] != ICMP_ECHO_REQUEST || !is_local(dst) || dst == GUEST {
+            return;
+        }
+        let mut reply = icmp.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        reply[2..4].fill(0);
+        let checksum = packet::checksum(&reply);
+        reply[2..4].copy_from_slice(&checksum.to_be_bytes());
+        self.link.send_ipv4(dst, src, protocol::ICMP, &reply);
+    }
+
+    fn handle_udp(&mut self, dst: Ipv4Addr, datagram: &[u8]) {
+        let Some(udp) = packet::parse_udp(datagram) else { return };
+        if udp.dst_port == dhcp::SERVER_PORT {
+            self.handle_dhcp(udp.payload);
+            return;
+        }
+
+        // replies to datagrams that came in through a forwarded port
+        if dst == GATEWAY
+            && let Some(&(forward, peer)) = self.udp_forward_peers.get(&(udp.src_port, udp.dst_port))
+        {
+            let _ = self.udp_forwards[forward].socket.send_to(udp.payload, peer);
+            return;
+        }
+
+        let dst = SocketAddrV4::new(dst, udp.dst_port);
+        let Some(host_addr) = self.host_addr(dst) else { return };
+        let mapping = match self.udp.entry(udp.src_port) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let Ok(socket) = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) else { return };
+                if socket.set_nonblocking(true).is_err() {
+                    return;
+                }
+                entry.insert(UdpMapping { socket, peers: HashMap::new(), last_used: Instant::now() })
+            }
+        };
+        mapping.peers.insert(host_addr, dst);
+        mapping.last_used = Instant::now();
+        let _ = mapping.socket.send_to(udp.payload, host_addr);
+    }
+
+    /// hands the guest its one fixed address
+    fn handle_dhcp(&mut self, message: &[u8]) {
+        if message.len() < dhcp::HEADER_SIZE + 4 || message[0] != dhcp::BOOTREQUEST || message[dhcp::HEADER_SIZE..dhcp::HEADER_SIZE + 4] != dhcp::MAGIC_COOKIE {
+            return;
+        }
+
+        let mut options = &message[dhcp::HEADER_SIZE + 4..];
+        let mut kind = None;
+        while let [code, rest @ ..] = options {
+            match *code {
+                dhcp::option::PAD => options = rest,
+                dhcp::option::END => break,
+                _ => {
+                    let [len, rest @ ..] = rest else { break };
+                    let Some(value) = rest.get(..*len as usize) else { break };
+                    if *code == dhcp::option::MESSAGE_TYPE {
+                        kind = value.first().copied();
+                    }
+                    options = &rest[*len as usize..];
+                }
+            }
+        }
+        let reply_kind = match kind {
+            Some(dhcp::message::DISCOVER) => dhcp::message::OFFER,
+            Some(dhcp::message::REQUEST) => dhcp::message::ACK,
+            _ => return,
+        };
+
+        let mut reply = vec![0; dhcp::HEADER_SIZE];
+        reply[0] = dhcp::BOOTREPLY;
+        // hardware type, address length, hops, then the transaction id and the flags
+        reply[1..12].copy_from_slice(&message[1..12]);
+        reply[3] = 0;
+        reply[8..10].fill(0);
+        reply[16..20].copy_from_slice(&GUEST.octets());
+        reply[20..24].copy_from_slice(&GATEWAY.octets());
+        reply[28..44].copy_from_slice(&message[28..44]);
+        reply.extend_from_slice(&dhcp::MAGIC_COOKIE);
+        let mut option = |code: u8, value: &[u8]| {
+            reply.push(code);
+            reply.push(value.len() as u8);
+            reply.extend_from_slice(value);
+        };
+        option(dhcp::option::MESSAGE_TYPE, &[reply_kind]);
+        option(dhcp::option::SERVER_ID, &GATEWAY.octets());
+        option(dhcp::option::LEASE_TIME, &dhcp::LEASE_TIME.to_be_bytes());
+        option(dhcp::option::SUBNET_MASK, &NETMASK.octets());
+        option(dhcp::option::ROUTER, &GATEWAY.octets());
+        option(dhcp::option::DNS, &DNS.octets());
+        reply.push(dhcp::option::END);
+
+        self.link.send_udp(
+            SocketAddrV4::new(GATEWAY, dhcp::SERVER_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, dhcp::CLIENT_PORT),
+            &reply,
+        );
+    }
+
+    /// passes on whatever the host sockets have for the guest
+    fn poll(&mut self) {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let now = Instant::now();
+
+        self.udp.retain(|&guest_port, mapping| {
+            while let Ok((len, from)) = mapping.socket.recv_from(&mut buf) {
+                mapping.last_used = now;
+                let src = match mapping.peers.get(&from) {
+                    Some(&src) => src,
+                    None => {
+                        let Some(src) = guest_view(from) else { continue };
+                        src
+                    }
+                };
+                self.link.send_udp(src, SocketAddrV4::new(GUEST, guest_port), &buf[..len]);
+            }
+            now - mapping.last_used < UDP_TIMEOUT
+        });
+
+        for (index, forward) in self.udp_forwards.iter().enumerate() {
+            while let Ok((len, from)) = forward.socket.recv_from(&mut buf) {
+                self.udp_forward_peers.insert((forward.guest_port, from.port()), (index, from));
+                self.link.send_udp(SocketAddrV4::new(GATEWAY, from.port()), SocketAddrV4::new(GUEST, forward.guest_port), &buf[..len]);
+            }
+        }
+
+        self.tcp.poll(&mut self.link);
+    }
+}
+
+/// addresses on the virtual network
+pub fn is_local(ip: Ipv4Addr) -> bool {
+    u32::from(ip) & u32::from(NETMASK) == u32::from(GATEWAY) & u32::from(NETMASK)
+}
+
@@ -0,0 +1,443 @@
+use std::{collections::HashMap, io::{self, Read, Write}, net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream}, sync::mpsc::{self, Receiver, TryRecvError}, thread, time::{Duration, Instant}};
+
+use super::{packet::{self, protocol}, slirp::{GATEWAY, GUEST, Link}};
+
+mod flags {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = 0x10;
+}
+
+const HEADER_SIZE: usize = 20;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+/// the biggest segment that fits in a 1500 byte MTU
+const MSS: u16 = 1460;
+/// what RFC 1122 says to assume when the guest doesn't say
+const DEFAULT_GUEST_MSS: u16 = 536;
+/// bytes held in each direction before the sender is made to wait
+const BUFFER_SIZE: usize = 64 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// the guest is a few microseconds away so anything unacknowledged for this long is lost
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(250);
+const MAX_RETRANSMITS: u32 = 12;
+
+/// the guest's end of a connection and the remote end as the guest sees it
+type Key = (SocketAddrV4, SocketAddrV4);
+
+struct Segment<'a> {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &'a [u8],
+}
+
+fn parse(segment: &[u8]) -> Option<Segment<'_>> {
+    if segment.len() < HEADER_SIZE {
+        return None;
+    }
+    let header_len = (segment[12] >> 4) as usize * 4;
+    if header_len < HEADER_SIZE || header_len > segment.len() {
+        return None;
+    }
+
+    let mut mss = None;
+    let mut options = &segment[HEADER_SIZE..header_len];
+    while let [kind, rest @ ..] = options {
+        match *kind {
+            OPTION_END => break,
+            OPTION_NOP => options = rest,
+            _ => {
+                let [len, ..] = rest else { break };
+                let len = *len as usize;
+                if len < 2 || len > options.len() {
+                    break;
+                }
+                if *kind == OPTION_MSS && len == 4 {
+                    mss = Some(packet::be_u16(options, 2));
+                }
+                options = &options[len..];
+            }
+        }
+    }
+
+    Some(Segment {
+        src_port: packet::be_u16(segment, 0),
+        dst_port: packet::be_u16(segment, 2),
+        seq: packet::be_u32(segment, 4),
+        ack: packet::be_u32(segment, 8),
+        flags: segment[13],
+        window: packet::be_u16(segment, 14),
+        mss,
+        payload: &segment[header_len..],
+    })
+}
+
+fn send_segment(link: &mut Link, key: Key, seq: u32, ack: u32, control: u8, window: u16, payload: &[u8]) {
+    let (guest, remote) = key;
+    let header_len = if control & flags::SYN != 0 { HEADER_SIZE + 4 } else { HEADER_SIZE };
+    let mut segment = vec![0; header_len];
+    segment[0..2].copy_from_slice(&remote.port().to_be_bytes());
+    segment[2..4].copy_from_slice(&guest.port().to_be_bytes());
+    segment[4..8].copy_from_slice(&seq.to_be_bytes());
+    segment[8..12].copy_from_slice(&ack.to_be_bytes());
+    segment[12] = (header_len / 4) as u8 * 16;
+    segment[13] = control;
+    segment[14..16].copy_from_slice(&window.to_be_bytes());
+    if control & flags::SYN != 0 {
+        segment[20] = OPTION_MSS;
+        segment[21] = 4;
+        segment[22..24].copy_from_slice(&MSS.to_be_bytes());
+    }
+    segment.extend_from_slice(payload);
+    let checksum = packet::transport_checksum(*remote.ip(), *guest.ip(), protocol::TCP, &segment);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    link.send_ipv4(*remote.ip(), *guest.ip(), protocol::TCP, &segment);
+}
+
+/// answers a segment that doesn't belong to any connection
+fn reset(link: &mut Link, key: Key, segment: &Segment) {
+    if segment.flags & flags::RST != 0 {
+        return;
+    }
+    if segment.flags & flags::ACK != 0 {
+        send_segment(link, key, segment.ack, 0, flags::RST, 0, &[]);
+    } else {
+        let len = segment.payload.len() as u32 + (segment.flags & flags::SYN != 0) as u32 + (segment.flags & flags::FIN != 0) as u32;
+        send_segment(link, key, 0, segment.seq.wrapping_add(len), flags::RST | flags::ACK, 0, &[]);
+    }
+}
+
+/// whether sequence number `a` comes after `b`
+fn after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// one guest connection and the host socket it's been turned into
+struct Connection {
+    stream: Option<TcpStream>,
+    /// the host connection while it's still being made on another thread
+    connecting: Option<Receiver<io::Result<TcpStream>>>,
+
+    /// our SYN's sequence number, everything we send counts on from here
+    iss: u32,
+    snd_una: u32,
+    /// the end of the furthest thing we've ever sent
+    snd_max: u32,
+    syn_acked: bool,
+    /// data for the guest from `snd_una`, the first `sent` bytes are in flight
+    to_guest: Vec<u8>,
+    sent: usize,
+    host_eof: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    guest_window: u32,
+    guest_mss: usize,
+
+    /// the next sequence number expected from the guest, known once its SYN shows up
+    rcv_nxt: Option<u32>,
+    to_host: Vec<u8>,
+    guest_fin: bool,
+    host_shut: bool,
+
+    last_progress: Instant,
+    retransmits: u32,
+}
+
+impl Connection {
+    fn new(iss: u32, rcv_nxt: Option<u32>, guest_mss: Option<u16>) -> Self {
+        Self {
+            stream: None,
+            connecting: None,
+            iss,
+            snd_una: iss,
+            snd_max: iss,
+            syn_acked: false,
+            to_guest: Vec::new(),
+            sent: 0,
+            host_eof: false,
+            fin_sent: false,
+            fin_acked: false,
+            guest_window: 0,
+            guest_mss: guest_mss.unwrap_or(DEFAULT_GUEST_MSS).min(MSS) as usize,
+            rcv_nxt,
+            to_host: Vec::new(),
+            guest_fin: false,
+            host_shut: false,
+            last_progress: Instant::now(),
+            retransmits: 0,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (BUFFER_SIZE - self.to_host.len()).min(u16::MAX as usize) as u16
+    }
+
+    fn send(&mut self, link: &mut Link, key: Key, seq: u32, control: u8, payload: &[u8]) {
+        let (ack, control) = match self.rcv_nxt {
+            Some(rcv_nxt) => (rcv_nxt, control | flags::ACK),
+            None => (0, control),
+        };
+        send_segment(link, key, seq, ack, control, self.window(), payload);
+        let len = payload.len() as u32 + (control & flags::SYN != 0) as u32 + (control & flags::FIN != 0) as u32;
+        let end = seq.wrapping_add(len);
+        if after(end, self.snd_max) {
+            self.snd_max = end;
+        }
+    }
+
+    fn send_syn(&mut self, link: &mut Link, key: Key) {
+        self.send(link, key, self.iss, flags::SYN, &[]);
+    }
+
+    /// gives up on the connection, letting the guest know
+    fn abort(&mut self, link: &mut Link, key: Key) {
+        self.send(link, key, self.snd_max, flags::RST, &[]);
+    }
+
+    /// returns false once the connection is finished with
+    fn handle(&mut self, link: &mut Link, key: Key, segment: &Segment) -> bool {
+        if segment.flags & flags::RST != 0 {
+            return false;
+        }
+        // the guest's SYN is answered once the host connection is made
+        if self.connecting.is_some() {
+            return true;
+        }
+
+        let mut need_ack = false;
+        if self.rcv_nxt.is_none() {
+            // our SYN to the guest is only answered by a SYN-ACK
+            if segment.flags & (flags::SYN | flags::ACK) != flags::SYN | flags::ACK || segment.ack != self.iss.wrapping_add(1) {
+                return true;
+            }
+            self.rcv_nxt = Some(segment.seq.wrapping_add(1));
+            self.guest_mss = segment.mss.unwrap_or(DEFAULT_GUEST_MSS).min(MSS) as usize;
+            need_ack = true;
+        }
+
+        if segment.flags & flags::ACK != 0 {
+            let acked = segment.ack.wrapping_sub(self.snd_una);
+            if acked > 0 && acked <= self.snd_max.wrapping_sub(self.snd_una) {
+                let mut acked = acked as usize;
+                if !self.syn_acked {
+                    self.syn_acked = true;
+                    acked -= 1;
+                }
+                let data = acked.min(self.to_guest.len());
+                self.to_guest.drain(..data);
+                self.sent = self.sent.saturating_sub(data);
+                if acked > data {
+                    self.fin_acked = true;
+                }
+                self.snd_una = segment.ack;
+                self.retransmits = 0;
+                self.last_progress = Instant::now();
+            }
+            self.guest_window = segment.window as u32;
+        }
+
+        let rcv_nxt = self.rcv_nxt.unwrap();
+        let seq = segment.seq.wrapping_add((segment.flags & flags::SYN != 0) as u32);
+        let offset = rcv_nxt.wrapping_sub(seq) as i32;
+        if offset < 0 {
+            // something before this went missing, asking again for what's next gets it resent
+            need_ack = true;
+        } else {
+            let offset = offset as usize;
+            let new = segment.payload.get(offset..).unwrap_or(&[]);
+            let mut rcv_nxt = rcv_nxt;
+            // data that won't fit is dropped and comes again once the window opens
+            if !new.is_empty() && !self.guest_fin && self.to_host.len() + new.len() <= BUFFER_SIZE {
+                self.to_host.extend_from_slice(new);
+                rcv_nxt = rcv_nxt.wrapping_add(new.len() as u32);
+            }
+            if !segment.payload.is_empty() {
+                need_ack = true;
+            }
+            if segment.flags & flags::FIN != 0 {
+                if !self.guest_fin && rcv_nxt == seq.wrapping_add(segment.payload.len() as u32) {
+                    self.guest_fin = true;
+                    rcv_nxt = rcv_nxt.wrapping_add(1);
+                }
+                need_ack = true;
+            }
+            self.rcv_nxt = Some(rcv_nxt);
+        }
+
+        if need_ack {
+            self.send(link, key, self.snd_max, 0, &[]);
+        }
+        !(self.fin_acked && self.guest_fin)
+    }
+
+    /// moves data between the host socket and the guest, returns false once the connection is finished with
+    fn poll(&mut self, link: &mut Link, key: Key, now: Instant) -> bool {
+        if let Some(connecting) = &self.connecting {
+            match connecting.try_recv() {
+                Ok(Ok(stream)) => {
+                    if stream.set_nonblocking(true).is_err() {
+                        self.abort(link, key);
+                        return false;
+                    }
+                    let _ = stream.set_nodelay(true);
+                    self.stream = Some(stream);
+                    self.connecting = None;
+                    self.send_syn(link, key);
+                    self.last_progress = now;
+                }
+                Err(TryRecvError::Empty) => return true,
+                Ok(Err(_)) | Err(TryRecvError::Disconnected) => {
+                    self.abort(link, key);
+                    return false;
+                }
+            }
+        }
+        let Some(stream) = self.stream.as_mut() else { return false };
+
+        while !self.to_host.is_empty() {
+            match stream.write(&self.to_host) {
+                Ok(n) => {
+                    self.to_host.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.abort(link, key);
+                    return false;
+                }
+            }
+        }
+        if self.guest_fin && self.to_host.is_empty() && !self.host_shut {
+            let _ = stream.shutdown(Shutdown::Write);
+            self.host_shut = true;
+        }
+
+        let mut buf = [0; 4096];
+        while !self.host_eof && self.to_guest.len() < BUFFER_SIZE {
+            let room = (BUFFER_SIZE - self.to_guest.len()).min(buf.len());
+            match stream.read(&mut buf[..room]) {
+                Ok(0) => self.host_eof = true,
+                Ok(n) => self.to_guest.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.abort(link, key);
+                    return false;
+                }
+            }
+        }
+
+        let outstanding = self.snd_max != self.snd_una;
+        if outstanding && now - self.last_progress > RETRANSMIT_TIMEOUT {
+            self.retransmits += 1;
+            if self.retransmits > MAX_RETRANSMITS {
+                self.abort(link, key);
+                return false;
+            }
+            // go back to the last thing the guest acknowledged and send everything again
+            self.last_progress = now;
+            self.sent = 0;
+            self.fin_sent = self.fin_acked;
+            if !self.syn_acked {
+                self.send_syn(link, key);
+            }
+        } else if !outstanding {
+            self.last_progress = now;
+        }
+
+        if self.syn_acked && self.rcv_nxt.is_some() {
+            while self.sent < self.to_guest.len() && (self.sent as u32) < self.guest_window {
+                let n = (self.to_guest.len() - self.sent).min(self.guest_mss).min(self.guest_window as usize - self.sent);
+                let seq = self.snd_una.wrapping_add(self.sent as u32);
+                let data = self.to_guest[self.sent..self.sent + n].to_vec();
+                self.send(link, key, seq, flags::PSH, &data);
+                self.sent += n;
+            }
+            if self.host_eof && !self.fin_sent && self.sent == self.to_guest.len() {
+                let seq = self.snd_una.wrapping_add(self.to_guest.len() as u32);
+                self.send(link, key, seq, flags::FIN, &[]);
+                self.fin_sent = true;
+            }
+        }
+
+        !(self.fin_acked && self.guest_fin)
+    }
+}
+
+/// the TCP half of the user-mode stack
+pub struct Tcp {
+    connections: HashMap<Key, Connection>,
+    /// forwarded host ports and the guest port each goes to
+    listeners: Vec<(TcpListener, u16)>,
+    next_iss: u32,
+}
+
+impl Tcp {
+    pub fn new(listeners: Vec<(TcpListener, u16)>) -> Self {
+        Self { connections: HashMap::new(), listeners, next_iss: 0x1000 }
+    }
+
+    fn iss(&mut self) -> u32 {
+        self.next_iss = self.next_iss.wrapping_add(64000);
+        self.next_iss
+    }
+
+    /// a segment from the guest, `host_addr` is where its destination is on the host
+    pub fn handle(&mut self, link: &mut Link, src: Ipv4Addr, dst: Ipv4Addr, segment: &[u8], host_addr: Option<SocketAddr>) {
+        let Some(segment) = parse(segment) else { return };
+        let key = (SocketAddrV4::new(src, segment.src_port), SocketAddrV4::new(dst, segment.dst_port));
+
+        if let Some(connection) = self.connections.get_mut(&key) {
+            if !connection.handle(link, key, &segment) {
+                self.connections.remove(&key);
+            }
+            return;
+        }
+
+        let Some(host_addr) = host_addr.filter(|_| segment.flags & (flags::SYN | flags::ACK) == flags::SYN) else {
+            reset(link, key, &segment);
+            return;
+        };
+
+        // connecting can take a while so it happens on its own thread
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = sender.send(TcpStream::connect_timeout(&host_addr, CONNECT_TIMEOUT));
+        });
+        let mut connection = Connection::new(self.iss(), Some(segment.seq.wrapping_add(1)), segment.mss);
+        connection.connecting = Some(receiver);
+        connection.guest_window = segment.window as u32;
+        self.connections.insert(key, connection);
+    }
+
+    pub fn poll(&mut self, link: &mut Link) {
+        let mut accepted = Vec::new();
+        for (listener, guest_port) in &self.listeners {
+            while let Ok((stream, peer)) = listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    let _ = stream.set_nodelay(true);
+                    accepted.push((stream, SocketAddrV4::new(GUEST, *guest_port), SocketAddrV4::new(GATEWAY, peer.port())));
+                }
+            }
+        }
+        for (stream, guest, remote) in accepted {
+            // a connection to a forwarded port starts with us sending the guest a SYN
+            let key = (guest, remote);
+            let mut connection = Connection::new(self.iss(), None, None);
+            connection.stream = Some(stream);
+            connection.send_syn(link, key);
+            self.connections.insert(key, connection);
+        }
+
+        let now = Instant::now();
+        self.connections.retain(|key, connection| connection.poll(link, *key, now));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::exception::Exception;
+
+/// matches riscv,ndev in the device tree, source 0 is reserved
+pub const NUM_SOURCES: usize = 54;
+
+const PRIORITY_BASE: u64 = 0x0;
+const PENDING_BASE: u64 = 0x1000;
+const ENABLE_BASE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT_BASE: u64 = 0x200000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const THRESHOLD: u64 = 0x0;
+const CLAIM_COMPLETE: u64 = 0x4;
+
+const MAX_PRIORITY: u32 = 7;
+const WORDS: usize = NUM_SOURCES.div_ceil(32);
+
+/// each hart has a machine mode context followed by a supervisor mode context
+pub fn machine_context(hart: usize) -> usize {
+    hart * 2
+}
+
+pub fn supervisor_context(hart: usize) -> usize {
+    hart * 2 + 1
+}
+
+struct Context {
+    enable: [u32; WORDS],
+    threshold: u32,
+}
+
+pub struct Plic {
+    priority: [u32; NUM_SOURCES],
+    pending: [u32; WORDS],
+    /// the level of each source's interrupt line
+    level: [u32; WORDS],
+    /// claimed sources that haven't been completed yet, they can't become pending again until then
+    claimed: [u32; WORDS],
+    contexts: Vec<Context>,
+}
+
+fn bit(bits: &[u32; WORDS], source: usize) -> bool {
+    bits[source / 32] & (1 << (source % 32)) != 0
+}
+
+fn set_bit(bits: &mut [u32; WORDS], source: usize, value: bool) {
+    if value {
+        bits[source / 32] |= 1 << (source % 32);
+    } else {
+        bits[source / 32] &= !(1 << (source % 32));
+    }
+}
+
+impl Plic {
+    pub fn new(harts: usize) -> Self {
+        Self {
+            priority: [0; NUM_SOURCES],
+            pending: [0; WORDS],
+            level: [0; WORDS],
+            claimed: [0; WORDS],
+            contexts: (0..harts * 2).map(|_| Context { enable: [0; WORDS], threshold: 0 }).collect(),
+        }
+    }
+
+    /// drives the interrupt line of a level triggered source
+    pub fn set_irq(&mut self, source: usize, level: bool) {
+        if source == 0 || source >= NUM_SOURCES {
+            return;
+        }
+        set_bit(&mut self.level, source, level);
+        if level && !bit(&self.claimed, source) {
+            set_bit(&mut self.pending, source, true);
+        }
+    }
+
+    /// the pending and enabled source with the highest priority above the context's threshold
+    fn best_source(&self, context: usize) -> Option<usize> {
+        let context = &self.contexts[context];
+        let mut best = None;
+        let mut best_priority = context.threshold;
+        for source in 1..NUM_SOURCES {
+            // ties go to the lowest source number
+            if bit(&self.pending, source) && bit(&context.enable, source) && self.priority[source] > best_priority {
+                best = Some(source);
+                best_priority = self.priority[source];
+            }
+        }
+        best
+    }
+
+    /// the level of the external interrupt line into a context
+    pub fn interrupting(&self, context: usize) -> bool {
+        self.best_source(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best_source(context) {
+            Some(source) => {
+                set_bit(&mut self.pending, source, false);
+                set_bit(&mut self.claimed, source, true);
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: u32) {
+        let source = source as usize;
+        // completions for sources the context can't see are ignored
+        if source == 0 || source >= NUM_SOURCES || !bit(&self.contexts[context].enable, source) {
+            return;
+        }
+        set_bit(&mut self.claimed, source, false);
+        if bit(&self.level, source) {
+            set_bit(&mut self.pending, source, true);
+        }
+    }
+
+    /// finds which context's register `addr` falls in for a block of `stride` sized registers
+    fn context_register(&self, addr: u64, base: u64, stride: u64) -> Option<(usize, u64)> {
+        let context = ((addr - base) / stride) as usize;
+        if context < self.contexts.len() {
+            Some((context, (addr - base) % stride))
+        } else {
+            None
+        }
+    }
+
+    pub fn read(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
+        if size != 32 {
+            return Err(Exception::HardwareError);
+        }
+
+        let value = match addr {
+            PRIORITY_BASE..PENDING_BASE => {
+                self.priority.get((addr / 4) as usize).copied().unwrap_or(0)
+            }
+            PENDING_BASE..ENABLE_BASE => {
+                self.pending.get(((addr - PENDING_BASE) / 4) as usize).copied().unwrap_or(0)
+            }
+            ENABLE_BASE..CONTEXT_BASE => match self.context_register(addr, ENABLE_BASE, ENABLE_STRIDE) {
+                Some((context, offset)) => self.contexts[context].enable.get((offset / 4) as usize).copied().unwrap_or(0),
+                None => 0,
+            },
+            CONTEXT_BASE.. => match self.context_register(addr, CONTEXT_BASE, CONTEXT_STRIDE) {
+                Some((context, THRESHOLD)) => self.contexts[context].threshold,
+                Some((context, CLAIM_COMPLETE)) => self.claim(context),
+                _ => 0,
+            },
+        };
+        Ok(value as u64)
+    }
+
+    pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::HardwareError);
+        }
+
+        let value = value as u32;
+        match addr {
+            PRIORITY_BASE..PENDING_BASE => {
+                if let Some(priority) = self.priority.get_mut((addr / 4) as usize) {
+                    // source 0 doesn't exist so its priority is hardwired to 0
+                    if addr != 0 {
+                        *priority = value.min(MAX_PRIORITY);
+                    }
+                }
+            }
+            // pending bits are read only
+            PENDING_BASE..ENABLE_BASE => {}
+            ENABLE_BASE..CONTEXT_BASE => {
+                if let Some((context, offset)) = self.context_register(addr, ENABLE_BASE, ENABLE_STRIDE)
+                    && let Some(enable) = self.contexts[context].enable.get_mut((offset / 4) as usize)
+                {
+                    // source 0 can't be enabled
+                    *enable = if offset == 0 { value & !1 } else { value };
+                }
+            }
+            CONTEXT_BASE.. => match self.context_register(addr, CONTEXT_BASE, CONTEXT_STRIDE) {
+                Some((context, THRESHOLD)) => self.contexts[context].threshold = value.min(MAX_PRIORITY),
+                Some((context, CLAIM_COMPLETE)) => self.complete(context, value),
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+}
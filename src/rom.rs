@@ -1,5 +1,35 @@
 use crate::exception::Exception;
 
+// OpenSBI's struct fw_dynamic_info, version 2 is the one with boot_hart
+const FW_DYNAMIC_INFO_MAGIC: u64 = 0x4942534f;
+const FW_DYNAMIC_INFO_VERSION: u64 = 2;
+const FW_DYNAMIC_INFO_NEXT_MODE_S: u64 = 1;
+
+/// what the ROM starts with, the same reset code as QEMU's virt machine so
+/// firmware is started the way it would be there
+const RESET_CODE: [u32; 6] = [
+    0x00000297, // auipc t0, 0
+    0x02828613, // addi  a2, t0, 40     fw_dynamic_info
+    0xf1402573, // csrr  a0, mhartid
+    0x0202b583, // ld    a1, 32(t0)     the device tree
+    0x0182b283, // ld    t0, 24(t0)     the firmware
+    0x00028067, // jr    t0
+];
+
+/// the reset code followed by what it loads, the firmware's entry point, the
+/// device tree's address and an OpenSBI fw_dynamic_info telling fw_dynamic to
+/// go on to the kernel at `kernel` in S-mode, anything else ignores that
+pub fn boot_code(firmware: u64, dtb: u64, kernel: u64) -> Vec<u8> {
+    let boot_hart = 0;
+    let options = 0;
+    let fw_dynamic_info = [FW_DYNAMIC_INFO_MAGIC, FW_DYNAMIC_INFO_VERSION, kernel, FW_DYNAMIC_INFO_NEXT_MODE_S, options, boot_hart];
+    RESET_CODE
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .chain([firmware, dtb].iter().chain(&fw_dynamic_info).flat_map(|field| field.to_le_bytes()))
+        .collect()
+}
+
 pub struct Rom {
     rom: Vec<u8>
 }
@@ -13,7 +43,20 @@ impl Rom {
         self.rom = data.to_vec()
     }
 
+    /// puts `data` at `offset` followed by zeros up to `size`, growing the ROM to fit
+    pub fn load_at(&mut self, offset: usize, data: &[u8], size: usize) {
+        if self.rom.len() < offset + size {
+            self.rom.resize(offset + size, 0);
+        }
+        self.rom[offset..offset + data.len()].copy_from_slice(data);
+        self.rom[offset + data.len()..offset + size].fill(0);
+    }
+
+    /// anything past what was loaded reads as zeros
     pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
+        if addr as usize + size as usize / 8 > self.rom.len() {
+            return Ok(0);
+        }
         match size {
             8 => Ok(self.read8(addr)),
             16 => Ok(self.read16(addr)),
@@ -0,0 +1,66 @@
+use crate::exception::Exception;
+
+// the low half of a write says what to do, for a failure the high half is the exit status
+pub const FAIL: u64 = 0x3333;
+/// what syscon-poweroff writes
+pub const PASS: u64 = 0x5555;
+/// what syscon-reboot writes
+pub const RESET: u64 = 0x7777;
+
+/// what the guest asked the machine to do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shutdown {
+    Exit(i32),
+    Reset,
+}
+
+impl Shutdown {
+    /// exits with `code` as a failure, which it has to stay once only its
+    /// low 8 bits make it to whoever started the emulator
+    pub fn failure(code: u64) -> Self {
+        Shutdown::Exit(match code & 0xff {
+            0 => 1,
+            code => code as i32,
+        })
+    }
+}
+
+/// the SiFive test finisher, Linux drives it through syscon-poweroff and
+/// syscon-reboot and test programs write their result to it
+pub struct TestFinisher {
+    request: Option<Shutdown>,
+}
+
+impl TestFinisher {
+    pub fn new() -> Self {
+        Self { request: None }
+    }
+
+    /// the last request, if there's been one since this was last called
+    pub fn take_request(&mut self) -> Option<Shutdown> {
+        self.request.take()
+    }
+
+    pub fn read(&self, _addr: u64, size: u8) -> Result<u64, Exception> {
+        match size {
+            32 => Ok(0),
+            _ => Err(Exception::HardwareError),
+        }
+    }
+
+    pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::HardwareError);
+        }
+        if addr != 0 {
+            return Ok(());
+        }
+        match value & 0xffff {
+            FAIL => self.request = Some(Shutdown::failure(value >> 16 & 0xffff)),
+            PASS => self.request = Some(Shutdown::Exit(0)),
+            RESET => self.request = Some(Shutdown::Reset),
+            _ => {}
+        }
+        Ok(())
+    }
+}
@@ -1,24 +1,302 @@
+use std::{collections::VecDeque, io::{Read, Write}, sync::mpsc::{self, Receiver}, thread};
+
 use crate::exception::Exception;
 
+const FIFO_SIZE: usize = 16;
+/// ticks a partially filled receive FIFO waits before raising a character timeout
+const RX_TIMEOUT_TICKS: u64 = 4096;
+
+// register offsets
+const RBR_THR_DLL: u64 = 0;
+const IER_DLM: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+mod ier {
+    pub const RX_AVAILABLE: u8 = 1 << 0;
+    pub const THR_EMPTY: u8 = 1 << 1;
+    pub const RX_LINE_STATUS: u8 = 1 << 2;
+    pub const MODEM_STATUS: u8 = 1 << 3;
+}
+
+mod iir {
+    pub const NONE: u8 = 0x01;
+    pub const RX_LINE_STATUS: u8 = 0x06;
+    pub const RX_AVAILABLE: u8 = 0x04;
+    pub const RX_TIMEOUT: u8 = 0x0c;
+    pub const THR_EMPTY: u8 = 0x02;
+    pub const MODEM_STATUS: u8 = 0x00;
+    pub const FIFOS_ENABLED: u8 = 0xc0;
+}
+
+mod fcr {
+    pub const ENABLE: u8 = 1 << 0;
+    pub const CLEAR_RX: u8 = 1 << 1;
+    pub const CLEAR_TX: u8 = 1 << 2;
+}
+
+mod lsr {
+    pub const DATA_READY: u8 = 1 << 0;
+    pub const OVERRUN: u8 = 1 << 1;
+    pub const THR_EMPTY: u8 = 1 << 5;
+    pub const TRANSMITTER_EMPTY: u8 = 1 << 6;
+}
 
+mod mcr {
+    pub const DTR: u8 = 1 << 0;
+    pub const RTS: u8 = 1 << 1;
+    pub const OUT1: u8 = 1 << 2;
+    pub const OUT2: u8 = 1 << 3;
+    pub const LOOPBACK: u8 = 1 << 4;
+}
 
+const LCR_DLAB: u8 = 1 << 7;
+
+/// starts a thread feeding bytes from `input` into a channel so the UART can poll it
+pub fn spawn_reader(mut input: impl Read + Send + 'static) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut byte = [0u8];
+        while let Ok(1) = input.read(&mut byte) {
+            if sender.send(byte[0]).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// an NS16550A with 16 byte FIFOs, transmitting is instant so THR is always empty
 pub struct Uart {
+    input: Option<Receiver<u8>>,
+    output: Box<dyn Write + Send>,
+
+    rx_fifo: VecDeque<u8>,
+    rx_idle_ticks: u64,
 
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr_errors: u8,
+    scr: u8,
+    divisor: u16,
+    /// latched when THR empties and cleared by reading IIR or writing THR
+    thr_empty_pending: bool,
+    /// the delta bits of MSR, only changed by loopback mode
+    msr_delta: u8,
 }
 
 impl Uart {
     pub fn new() -> Self {
-        Self {  }
+        Self::with_io(Some(spawn_reader(std::io::stdin())), Box::new(std::io::stderr()))
+    }
+
+    pub fn with_io(input: Option<Receiver<u8>>, output: Box<dyn Write + Send>) -> Self {
+        Self {
+            input,
+            output,
+            rx_fifo: VecDeque::new(),
+            rx_idle_ticks: 0,
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: mcr::OUT2,
+            lsr_errors: 0,
+            scr: 0,
+            divisor: 0,
+            thr_empty_pending: false,
+            msr_delta: 0,
+        }
+    }
+
+    /// clears every register, the host side stays connected
+    pub fn reset(&mut self) {
+        let output = std::mem::replace(&mut self.output, Box::new(std::io::sink()));
+        *self = Self::with_io(self.input.take(), output);
     }
 
-    pub fn read(&self, _addr: u64, _size: u8) -> Result<u64, Exception> {
-        Ok(0)
+    /// hands host input over to another device, the UART gets none after this
+    pub fn take_input(&mut self) -> Option<Receiver<u8>> {
+        self.input.take()
+    }
+
+    fn fifos_enabled(&self) -> bool {
+        self.fcr & fcr::ENABLE != 0
+    }
+
+    fn rx_trigger_level(&self) -> usize {
+        if !self.fifos_enabled() {
+            return 1;
+        }
+        match self.fcr >> 6 {
+            0b00 => 1,
+            0b01 => 4,
+            0b10 => 8,
+            _ => 14,
+        }
     }
 
-    pub fn write(&self, addr: u64, value: u64, _size: u8) -> Result<(), Exception> {
-        if addr == 0 {
-            eprint!("{}", value as u8 as char)
+    fn receive(&mut self, byte: u8) {
+        let capacity = if self.fifos_enabled() { FIFO_SIZE } else { 1 };
+        if self.rx_fifo.len() >= capacity {
+            self.lsr_errors |= lsr::OVERRUN;
+            return;
+        }
+        self.rx_fifo.push_back(byte);
+        self.rx_idle_ticks = 0;
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & mcr::LOOPBACK != 0 {
+            self.receive(byte);
+        } else {
+            let _ = self.output.write_all(&[byte]);
+            let _ = self.output.flush();
+        }
+        self.thr_empty_pending = self.ier & ier::THR_EMPTY != 0;
+    }
+
+    /// sends bytes the way a firmware console would, past whatever the guest set the registers to
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.transmit(byte);
+        }
+    }
+
+    /// the next byte received for a firmware console
+    pub fn read_byte(&mut self) -> Option<u8> {
+        self.rx_fifo.pop_front()
+    }
+
+    /// pulls host input into the receive FIFO
+    pub fn tick(&mut self) {
+        self.rx_idle_ticks = self.rx_idle_ticks.saturating_add(1);
+
+        if self.mcr & mcr::LOOPBACK != 0 {
+            return;
+        }
+        let capacity = if self.fifos_enabled() { FIFO_SIZE } else { 1 };
+        while self.rx_fifo.len() < capacity {
+            let Some(byte) = self.input.as_ref().and_then(|input| input.try_recv().ok()) else {
+                break;
+            };
+            self.receive(byte);
+        }
+    }
+
+    fn modem_status(&self) -> u8 {
+        let lines = if self.mcr & mcr::LOOPBACK != 0 {
+            // CTS, DSR, RI and DCD are looped back from RTS, DTR, OUT1 and OUT2
+            ((self.mcr & mcr::RTS) << 3) |
+            ((self.mcr & mcr::DTR) << 5) |
+            ((self.mcr & mcr::OUT1) << 4) |
+            ((self.mcr & mcr::OUT2) << 4)
+        } else {
+            // pretend a terminal is always attached with CTS, DSR and DCD asserted
+            0xb0
+        };
+        lines | self.msr_delta
+    }
+
+    /// the highest priority interrupt the UART is raising, in IIR encoding
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & ier::RX_LINE_STATUS != 0 && self.lsr_errors != 0 {
+            iir::RX_LINE_STATUS
+        } else if self.ier & ier::RX_AVAILABLE != 0 && self.rx_fifo.len() >= self.rx_trigger_level() {
+            iir::RX_AVAILABLE
+        } else if self.ier & ier::RX_AVAILABLE != 0 && !self.rx_fifo.is_empty() && self.rx_idle_ticks >= RX_TIMEOUT_TICKS {
+            iir::RX_TIMEOUT
+        } else if self.ier & ier::THR_EMPTY != 0 && self.thr_empty_pending {
+            iir::THR_EMPTY
+        } else if self.ier & ier::MODEM_STATUS != 0 && self.msr_delta != 0 {
+            iir::MODEM_STATUS
+        } else {
+            iir::NONE
+        }
+    }
+
+    /// the level of the UART's interrupt line
+    pub fn interrupting(&self) -> bool {
+        self.interrupt_id() != iir::NONE
+    }
+
+    pub fn read(&mut self, addr: u64, _size: u8) -> Result<u64, Exception> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match addr {
+            RBR_THR_DLL if dlab => self.divisor as u8,
+            RBR_THR_DLL => {
+                self.rx_idle_ticks = 0;
+                self.rx_fifo.pop_front().unwrap_or(0)
+            }
+            IER_DLM if dlab => (self.divisor >> 8) as u8,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                // reading IIR acknowledges the THR empty interrupt
+                if id == iir::THR_EMPTY {
+                    self.thr_empty_pending = false;
+                }
+                id | if self.fifos_enabled() { iir::FIFOS_ENABLED } else { 0 }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let data_ready = if self.rx_fifo.is_empty() { 0 } else { lsr::DATA_READY };
+                let errors = self.lsr_errors;
+                self.lsr_errors = 0;
+                data_ready | errors | lsr::THR_EMPTY | lsr::TRANSMITTER_EMPTY
+            }
+            MSR => {
+                let msr = self.modem_status();
+                self.msr_delta = 0;
+                msr
+            }
+            SCR => self.scr,
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    pub fn write(&mut self, addr: u64, value: u64, _size: u8) -> Result<(), Exception> {
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match addr {
+            RBR_THR_DLL if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            RBR_THR_DLL => self.transmit(value),
+            IER_DLM if dlab => self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8,
+            IER_DLM => {
+                // enabling the THR empty interrupt fires it straight away as THR is always empty
+                if value & ier::THR_EMPTY != 0 && self.ier & ier::THR_EMPTY == 0 {
+                    self.thr_empty_pending = true;
+                }
+                self.ier = value & 0x0f;
+            }
+            IIR_FCR => {
+                if value & fcr::CLEAR_RX != 0 {
+                    self.rx_fifo.clear();
+                }
+                // the transmit FIFO is always empty so CLEAR_TX has nothing to do
+                self.fcr = value & !(fcr::CLEAR_RX | fcr::CLEAR_TX);
+            }
+            LCR => self.lcr = value,
+            MCR => {
+                let old_status = self.modem_status();
+                self.mcr = value & 0x1f;
+                // changes to the looped back lines show up as MSR deltas
+                let changed = (old_status ^ self.modem_status()) & 0xf0;
+                self.msr_delta |= (changed >> 4) & 0x0b;
+                if changed & 0x40 != 0 && self.modem_status() & 0x40 == 0 {
+                    self.msr_delta |= 0x04;
+                }
+            }
+            SCR => self.scr = value,
+            _ => {}
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
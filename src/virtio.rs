@@ -0,0 +1,457 @@
+use crate::{bus::DRAM_START, dram::Dram, exception::Exception};
+
+pub mod block;
+pub mod console;
+pub mod net;
+pub mod p9;
+pub mod rng;
+
+/// the largest queue the transport offers, drivers may pick anything smaller
+pub const QUEUE_SIZE_MAX: u16 = 256;
+
+mod reg {
+    pub const MAGIC_VALUE: u64 = 0x000;
+    pub const VERSION: u64 = 0x004;
+    pub const DEVICE_ID: u64 = 0x008;
+    pub const VENDOR_ID: u64 = 0x00c;
+    pub const DEVICE_FEATURES: u64 = 0x010;
+    pub const DEVICE_FEATURES_SEL: u64 = 0x014;
+    pub const DRIVER_FEATURES: u64 = 0x020;
+    pub const DRIVER_FEATURES_SEL: u64 = 0x024;
+    pub const QUEUE_SEL: u64 = 0x030;
+    pub const QUEUE_NUM_MAX: u64 = 0x034;
+    pub const QUEUE_NUM: u64 = 0x038;
+    pub const QUEUE_READY: u64 = 0x044;
+    pub const QUEUE_NOTIFY: u64 = 0x050;
+    pub const INTERRUPT_STATUS: u64 = 0x060;
+    pub const INTERRUPT_ACK: u64 = 0x064;
+    pub const STATUS: u64 = 0x070;
+    pub const QUEUE_DESC_LOW: u64 = 0x080;
+    pub const QUEUE_DESC_HIGH: u64 = 0x084;
+    pub const QUEUE_DRIVER_LOW: u64 = 0x090;
+    pub const QUEUE_DRIVER_HIGH: u64 = 0x094;
+    pub const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+    pub const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+    pub const CONFIG_GENERATION: u64 = 0x0fc;
+    pub const CONFIG: u64 = 0x100;
+}
+
+const MAGIC: u32 = 0x74726976;
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x554d4551;
+
+pub mod feature {
+    pub const RING_INDIRECT_DESC: u64 = 1 << 28;
+    pub const VERSION_1: u64 = 1 << 32;
+}
+
+mod status {
+    pub const FEATURES_OK: u32 = 8;
+    pub const DEVICE_NEEDS_RESET: u32 = 64;
+}
+
+mod interrupt {
+    pub const USED_BUFFER: u32 = 1 << 0;
+    pub const CONFIG_CHANGE: u32 = 1 << 1;
+}
+
+mod desc {
+    pub const SIZE: u64 = 16;
+    pub const NEXT: u16 = 1;
+    pub const WRITE: u16 = 2;
+    pub const INDIRECT: u16 = 4;
+}
+
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// reads guest physical memory, `None` if it isn't all DRAM
+pub fn read_memory(dram: &Dram, addr: u64, buf: &mut [u8]) -> Option<()> {
+    buf.copy_from_slice(dram.slice(addr.checked_sub(DRAM_START)?, buf.len())?);
+    Some(())
+}
+
+pub fn write_memory(dram: &mut Dram, addr: u64, data: &[u8]) -> Option<()> {
+    dram.slice_mut(addr.checked_sub(DRAM_START)?, data.len())?.copy_from_slice(data);
+    Some(())
+}
+
+fn read_u16(dram: &Dram, addr: u64) -> Option<u16> {
+    let mut buf = [0; 2];
+    read_memory(dram, addr, &mut buf)?;
+    Some(u16::from_le_bytes(buf))
+}
+
+/// a descriptor chain the driver made available, split into the buffers the
+/// device reads from and the ones it writes to
+pub struct Chain {
+    head: u16,
+    readable: Vec<(u64, u32)>,
+    writable: Vec<(u64, u32)>,
+}
+
+impl Chain {
+    /// gathers everything the driver gave the device to read
+    pub fn read_all(&self, dram: &Dram) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        for &(addr, len) in &self.readable {
+            let start = data.len();
+            data.resize(start + len as usize, 0);
+            read_memory(dram, addr, &mut data[start..])?;
+        }
+        Some(data)
+    }
+
+    /// how many bytes the device can write back
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|&(_, len)| len as usize).sum()
+    }
+
+    /// scatters `data` over the writable buffers, returns how much fit
+    pub fn write_all(&self, dram: &mut Dram, mut data: &[u8]) -> Option<u32> {
+        let mut written = 0;
+        for &(addr, len) in &self.writable {
+            if data.is_empty() {
+                break;
+            }
+            let n = data.len().min(len as usize);
+            write_memory(dram, addr, &data[..n])?;
+            data = &data[n..];
+            written += n as u32;
+        }
+        Some(written)
+    }
+}
+
+/// a split virtqueue
+#[derive(Default)]
+pub struct Queue {
+    size: u16,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    last_avail: u16,
+    used_idx: u16,
+    /// set once a buffer is used and the driver wants to hear about it
+    interrupt: bool,
+}
+
+impl Queue {
+    /// walks one descriptor table, which is either the queue's or an indirect one
+    fn walk(&self, dram: &Dram, table: u64, table_len: u16, mut index: u16, chain: &mut Chain, allow_indirect: bool) -> Option<()> {
+        // a chain can't be longer than its table, anything more is a loop
+        for _ in 0..table_len {
+            if index >= table_len {
+                return None;
+            }
+            let mut raw = [0; desc::SIZE as usize];
+            read_memory(dram, table + index as u64 * desc::SIZE, &mut raw)?;
+            let addr = u64::from_le_bytes(raw[0..8].try_into().unwrap());
+            let len = u32::from_le_bytes(raw[8..12].try_into().unwrap());
+            let flags = u16::from_le_bytes(raw[12..14].try_into().unwrap());
+            let next = u16::from_le_bytes(raw[14..16].try_into().unwrap());
+
+            if flags & desc::INDIRECT != 0 {
+                if !allow_indirect || !(len as u64).is_multiple_of(desc::SIZE) {
+                    return None;
+                }
+                let entries = u16::try_from(len as u64 / desc::SIZE).ok()?;
+                self.walk(dram, addr, entries, 0, chain, false)?;
+            } else if addr.checked_sub(DRAM_START).and_then(|addr| dram.slice(addr, len as usize)).is_none() {
+                // caught here, before anything's allocated for the buffer
+                return None;
+            } else if flags & desc::WRITE != 0 {
+                chain.writable.push((addr, len));
+            } else if chain.writable.is_empty() {
+                chain.readable.push((addr, len));
+            } else {
+                // readable buffers must all come before the writable ones
+                return None;
+            }
+
+            if flags & desc::NEXT == 0 {
+                return Some(());
+            }
+            index = next;
+        }
+        None
+    }
+
+    /// takes the next available chain, `Err` means the driver handed over something malformed
+    pub fn pop(&mut self, dram: &Dram) -> Result<Option<Chain>, ()> {
+        if !self.ready || self.size == 0 {
+            return Ok(None);
+        }
+
+        let avail_idx = read_u16(dram, self.driver + 2).ok_or(())?;
+        if avail_idx == self.last_avail {
+            return Ok(None);
+        }
+
+        let slot = self.last_avail % self.size;
+        let head = read_u16(dram, self.driver + 4 + slot as u64 * 2).ok_or(())?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = Chain { head, readable: Vec::new(), writable: Vec::new() };
+        self.walk(dram, self.desc, self.size, head, &mut chain, true).ok_or(())?;
+        // buffers that overlap could still add up to more than all of memory
+        let total = |buffers: &[(u64, u32)]| buffers.iter().map(|&(_, len)| len as u64).sum::<u64>();
+        if total(&chain.readable) > dram.size() || total(&chain.writable) > dram.size() {
+            return Err(());
+        }
+        Ok(Some(chain))
+    }
+
+    /// returns a chain to the driver with `len` bytes written into it
+    pub fn push(&mut self, dram: &mut Dram, chain: &Chain, len: u32) -> Result<(), ()> {
+        let slot = self.used_idx % self.size;
+        let mut entry = [0; 8];
+        entry[0..4].copy_from_slice(&(chain.head as u32).to_le_bytes());
+        entry[4..8].copy_from_slice(&len.to_le_bytes());
+        write_memory(dram, self.device + 4 + slot as u64 * 8, &entry).ok_or(())?;
+
+        // the ring entry has to be visible before the index that publishes it
+        self.used_idx = self.used_idx.wrapping_add(1);
+        write_memory(dram, self.device + 2, &self.used_idx.to_le_bytes()).ok_or(())?;
+
+        let flags = read_u16(dram, self.driver).ok_or(())?;
+        if flags & AVAIL_F_NO_INTERRUPT == 0 {
+            self.interrupt = true;
+        }
+        Ok(())
+    }
+}
+
+/// a device sitting behind the virtio-mmio transport
+pub trait VirtioDevice {
+    fn device_id(&self) -> u32;
+
+    /// device specific feature bits, the transport adds the ones it implements
+    fn features(&self) -> u64;
+
+    fn num_queues(&self) -> usize;
+
+    fn read_config(&self, offset: u64) -> u8;
+
+    fn write_config(&mut self, _offset: u64, _value: u8) {}
+
+    /// the features the driver accepted, once they're settled
+    fn set_features(&mut self, _features: u64) {}
+
+    /// the driver has made buffers available on `queue`
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], dram: &mut Dram) -> Result<(), ()>;
+
+    /// called every tick so devices fed by the host can fill their queues
+    fn poll(&mut self, _queues: &mut [Queue], _dram: &mut Dram) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// a slot with no device behind it still identifies as virtio-mmio, with a
+/// device ID of 0 so drivers skip it
+pub fn read_empty_slot(addr: u64) -> u64 {
+    match addr {
+        reg::MAGIC_VALUE => MAGIC as u64,
+        reg::VERSION => VERSION as u64,
+        _ => 0,
+    }
+}
+
+/// the virtio-mmio version 2 register interface in front of a device
+pub struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    queues: Vec<Queue>,
+    queue_sel: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    status: u32,
+    interrupt_status: u32,
+    config_generation: u32,
+}
+
+impl VirtioMmio {
+    pub fn new(device: Box<dyn VirtioDevice>) -> Self {
+        let queues = (0..device.num_queues()).map(|_| Queue::default()).collect();
+        Self {
+            device,
+            queues,
+            queue_sel: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            status: 0,
+            interrupt_status: 0,
+            config_generation: 0,
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | feature::VERSION_1 | feature::RING_INDIRECT_DESC
+    }
+
+    pub fn reset(&mut self) {
+        self.queues.iter_mut().for_each(|queue| *queue = Queue::default());
+        self.queue_sel = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.interrupt_status = 0;
+        self.device.reset();
+    }
+
+    /// collects the interrupts the queues asked for, and stops the device
+    /// if it was handed something it couldn't make sense of
+    fn after_queue_access(&mut self, result: Result<(), ()>) {
+        if result.is_err() {
+            self.status |= status::DEVICE_NEEDS_RESET;
+            self.interrupt_status |= interrupt::CONFIG_CHANGE;
+        }
+        for queue in self.queues.iter_mut() {
+            if queue.interrupt {
+                queue.interrupt = false;
+                self.interrupt_status |= interrupt::USED_BUFFER;
+            }
+        }
+    }
+
+    pub fn tick(&mut self, dram: &mut Dram) {
+        if self.status & status::DEVICE_NEEDS_RESET == 0 {
+            let result = self.device.poll(&mut self.queues, dram);
+            self.after_queue_access(result);
+        }
+    }
+
+    /// the level of the device's interrupt line
+    pub fn interrupting(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn read_config(&self, offset: u64, size: u8) -> u64 {
+        (0..size as u64 / 8).fold(0, |value, i| value | (self.device.read_config(offset + i) as u64) << (i * 8))
+    }
+
+    pub fn read(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
+        if addr >= reg::CONFIG {
+            return Ok(self.read_config(addr - reg::CONFIG, size));
+        }
+        if size != 32 {
+            return Err(Exception::HardwareError);
+        }
+
+        let value = match addr {
+            reg::MAGIC_VALUE => MAGIC,
+            reg::VERSION => VERSION,
+            reg::DEVICE_ID => self.device.device_id(),
+            reg::VENDOR_ID => VENDOR_ID,
+            reg::DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            reg::QUEUE_NUM_MAX if (self.queue_sel as usize) < self.queues.len() => QUEUE_SIZE_MAX as u32,
+            reg::QUEUE_READY => self.selected_queue().is_some_and(|queue| queue.ready) as u32,
+            reg::INTERRUPT_STATUS => self.interrupt_status,
+            reg::STATUS => self.status,
+            reg::CONFIG_GENERATION => self.config_generation,
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    pub fn write(&mut self, addr: u64, value: u64, size: u8, dram: &mut Dram) -> Result<(), Exception> {
+        if addr >= reg::CONFIG {
+            for i in 0..size as u64 / 8 {
+                self.device.write_config(addr - reg::CONFIG + i, (value >> (i * 8)) as u8);
+            }
+            return Ok(());
+        }
+        if size != 32 {
+            return Err(Exception::HardwareError);
+        }
+
+        let value = value as u32;
+        /// replaces one half of a 64 bit queue address
+        fn set_half(field: &mut u64, value: u32, high: bool) {
+            *field = if high {
+                (*field & 0xffffffff) | (value as u64) << 32
+            } else {
+                (*field & !0xffffffff) | value as u64
+            };
+        }
+
+        match addr {
+            reg::DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            reg::DRIVER_FEATURES => {
+                let shift = match self.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return Ok(()),
+                };
+                self.driver_features = (self.driver_features & !(0xffffffff << shift)) | (value as u64) << shift;
+            }
+            reg::DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            reg::QUEUE_SEL => self.queue_sel = value,
+            reg::QUEUE_NUM => {
+                // sizes have to be a power of two no bigger than the maximum
+                if let Some(queue) = self.selected_queue()
+                    && value.is_power_of_two()
+                    && value <= QUEUE_SIZE_MAX as u32
+                {
+                    queue.size = value as u16;
+                }
+            }
+            reg::QUEUE_READY => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = value & 1 != 0;
+                }
+            }
+            reg::QUEUE_NOTIFY if self.status & status::DEVICE_NEEDS_RESET == 0 && (value as usize) < self.queues.len() => {
+                let result = self.device.notify(value as usize, &mut self.queues, dram);
+                self.after_queue_access(result);
+            }
+            reg::INTERRUPT_ACK => self.interrupt_status &= !value,
+            reg::STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else {
+                    let mut value = value;
+                    // only the modern interface is implemented, and drivers can't ask for
+                    // features the device never offered
+                    if value & status::FEATURES_OK != 0
+                        && (self.driver_features & feature::VERSION_1 == 0 || self.driver_features & !self.device_features() != 0)
+                    {
+                        value &= !status::FEATURES_OK;
+                    } else if value & status::FEATURES_OK != 0 && self.status & status::FEATURES_OK == 0 {
+                        self.device.set_features(self.driver_features);
+                    }
+                    self.status = value | (self.status & status::DEVICE_NEEDS_RESET);
+                }
+            }
+            reg::QUEUE_DESC_LOW | reg::QUEUE_DESC_HIGH => {
+                if let Some(queue) = self.selected_queue() {
+                    set_half(&mut queue.desc, value, addr == reg::QUEUE_DESC_HIGH);
+                }
+            }
+            reg::QUEUE_DRIVER_LOW | reg::QUEUE_DRIVER_HIGH => {
+                if let Some(queue) = self.selected_queue() {
+                    set_half(&mut queue.driver, value, addr == reg::QUEUE_DRIVER_HIGH);
+                }
+            }
+            reg::QUEUE_DEVICE_LOW | reg::QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = self.selected_queue() {
+                    set_half(&mut queue.device, value, addr == reg::QUEUE_DEVICE_HIGH);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use std::io;
+
+use crate::{disk::Disk, dram::Dram};
+
+use super::{Chain, Queue, VirtioDevice};
+
+const DEVICE_ID: u32 = 2;
+pub const SECTOR_SIZE: u64 = 512;
+
+mod feature {
+    pub const SEG_MAX: u64 = 1 << 2;
+    pub const RO: u64 = 1 << 5;
+    pub const FLUSH: u64 = 1 << 9;
+}
+
+mod request {
+    pub const IN: u32 = 0;
+    pub const OUT: u32 = 1;
+    pub const FLUSH: u32 = 4;
+    pub const GET_ID: u32 = 8;
+}
+
+mod status {
+    pub const OK: u8 = 0;
+    pub const IOERR: u8 = 1;
+    pub const UNSUPP: u8 = 2;
+}
+
+/// type, reserved and sector
+const HEADER_SIZE: usize = 16;
+/// the most data segments a request can have, leaving room for the header and status
+const SEG_MAX: u32 = super::QUEUE_SIZE_MAX as u32 - 2;
+
+/// a virtio-blk device serving a disk image
+pub struct Block {
+    disk: Box<dyn Disk>,
+    /// capacity in sectors
+    capacity: u64,
+}
+
+impl Block {
+    pub fn new(disk: Box<dyn Disk>) -> Self {
+        let capacity = disk.len() / SECTOR_SIZE;
+        Self { disk, capacity }
+    }
+
+    fn read_sectors(&mut self, sector: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        self.disk.read_at(sector * SECTOR_SIZE, &mut data)?;
+        Ok(data)
+    }
+
+    /// checks a transfer of `len` bytes starting at `sector` stays on the disk
+    fn in_range(&self, sector: u64, len: usize) -> bool {
+        let sectors = (len as u64).div_ceil(SECTOR_SIZE);
+        sector.checked_add(sectors).is_some_and(|end| end <= self.capacity)
+    }
+
+    /// carries out one request, returning the data to hand back followed by the status byte
+    fn handle(&mut self, chain: &Chain, dram: &Dram) -> Option<Vec<u8>> {
+        let readable = chain.read_all(dram)?;
+        if readable.len() < HEADER_SIZE || chain.writable_len() == 0 {
+            return None;
+        }
+        let kind = u32::from_le_bytes(readable[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(readable[8..16].try_into().unwrap());
+        let data = &readable[HEADER_SIZE..];
+        // the last writable byte is always the status
+        let data_len = chain.writable_len() - 1;
+
+        let (mut response, status) = match kind {
+            request::IN if !self.in_range(sector, data_len) => (Vec::new(), status::IOERR),
+            request::IN => match self.read_sectors(sector, data_len) {
+                Ok(data) => (data, status::OK),
+                Err(_) => (Vec::new(), status::IOERR),
+            },
+            request::OUT if self.disk.is_read_only() || !self.in_range(sector, data.len()) => (Vec::new(), status::IOERR),
+            request::OUT => match self.disk.write_at(sector * SECTOR_SIZE, data) {
+                Ok(()) => (Vec::new(), status::OK),
+                Err(_) => (Vec::new(), status::IOERR),
+            },
+            request::FLUSH => match self.disk.flush() {
+                Ok(()) => (Vec::new(), status::OK),
+                Err(_) => (Vec::new(), status::IOERR),
+            },
+            request::GET_ID => (b"riscv-emulator".to_vec(), status::OK),
+            _ => (Vec::new(), status::UNSUPP),
+        };
+
+        // the status always goes in the last writable byte
+        response.resize(data_len, 0);
+        response.push(status);
+        Some(response)
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        feature::SEG_MAX | feature::FLUSH | if self.disk.is_read_only() { feature::RO } else { 0 }
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        let mut config = [0u8; 16];
+        config[0..8].copy_from_slice(&self.capacity.to_le_bytes());
+        // size_max is left at 0 as segments can be any size
+        config[12..16].copy_from_slice(&SEG_MAX.to_le_bytes());
+        config.get(offset as usize).copied().unwrap_or(0)
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], dram: &mut Dram) -> Result<(), ()> {
+        let queue = &mut queues[queue];
+        while let Some(chain) = queue.pop(dram)? {
+            let response = self.handle(&chain, dram).ok_or(())?;
+            let written = chain.write_all(dram, &response).ok_or(())?;
+            queue.push(dram, &chain, written)?;
+        }
+        Ok(())
+    }
+}
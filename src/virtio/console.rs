@@ -0,0 +1,278 @@
+use std::{collections::VecDeque, io::{self, Read, Write}, os::unix::net::{UnixListener, UnixStream}, path::Path, sync::{Arc, Mutex, mpsc::{self, Receiver}}, thread};
+
+use crate::dram::Dram;
+
+use super::{Queue, VirtioDevice};
+
+const DEVICE_ID: u32 = 3;
+
+mod feature {
+    pub const MULTIPORT: u64 = 1 << 1;
+    pub const EMERG_WRITE: u64 = 1 << 2;
+}
+
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+/// the events carried by control messages
+mod event {
+    pub const DEVICE_READY: u16 = 0;
+    pub const DEVICE_ADD: u16 = 1;
+    pub const PORT_READY: u16 = 3;
+    pub const CONSOLE_PORT: u16 = 4;
+    pub const PORT_OPEN: u16 = 6;
+    pub const PORT_NAME: u16 = 7;
+}
+
+/// id, event and value
+const CONTROL_MESSAGE_SIZE: usize = 8;
+
+/// config space offsets
+const CONFIG_MAX_NR_PORTS: usize = 4;
+const CONFIG_EMERG_WR: u64 = 8;
+
+/// one end of a console channel on the host side
+pub struct Port {
+    /// ports after the first are named so guest agents can find them under /dev/virtio-ports
+    name: Option<String>,
+    input: Option<Receiver<u8>>,
+    output: Box<dyn Write + Send>,
+    /// host input waiting for the guest to offer buffers
+    pending: VecDeque<u8>,
+}
+
+impl Port {
+    pub fn new(name: Option<String>, input: Option<Receiver<u8>>, output: Box<dyn Write + Send>) -> Self {
+        Self { name, input, output, pending: VecDeque::new() }
+    }
+}
+
+/// output kept for the next client to connect, anything past this is dropped
+const SOCKET_BACKLOG: usize = 64 * 1024;
+
+#[derive(Default)]
+struct SocketState {
+    client: Option<UnixStream>,
+    backlog: Vec<u8>,
+}
+
+/// writes to whichever client is connected to a socket, holding onto output while nobody is
+struct SocketWriter {
+    state: Arc<Mutex<SocketState>>,
+}
+
+impl Write for SocketWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(client) = state.client.as_mut()
+            && client.write_all(buf).is_ok()
+        {
+            return Ok(buf.len());
+        }
+        state.client = None;
+        let room = SOCKET_BACKLOG - state.backlog.len();
+        state.backlog.extend_from_slice(&buf[..buf.len().min(room)]);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// listens on a unix socket for one client at a time, returning the bytes it
+/// sends and somewhere to write bytes back to it
+pub fn unix_socket(path: &Path) -> io::Result<(Receiver<u8>, Box<dyn Write + Send>)> {
+    // a socket left over from an earlier run would stop the bind
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    let state = Arc::new(Mutex::new(SocketState::default()));
+    let (sender, receiver) = mpsc::channel();
+
+    let shared = state.clone();
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let Ok(mut client) = client else { continue };
+            {
+                let mut state = shared.lock().unwrap();
+                let backlog = std::mem::take(&mut state.backlog);
+                if client.write_all(&backlog).is_err() {
+                    continue;
+                }
+                state.client = client.try_clone().ok();
+            }
+            let mut byte = [0u8];
+            while let Ok(1) = client.read(&mut byte) {
+                if sender.send(byte[0]).is_err() {
+                    return;
+                }
+            }
+            shared.lock().unwrap().client = None;
+        }
+    });
+
+    Ok((receiver, Box::new(SocketWriter { state })))
+}
+
+/// a multiport virtio-console, port 0 is the console and the rest are plain channels
+pub struct Console {
+    ports: Vec<Port>,
+    multiport: bool,
+    /// control messages waiting for the guest to offer buffers
+    control: VecDeque<Vec<u8>>,
+}
+
+impl Console {
+    pub fn new(ports: Vec<Port>) -> Self {
+        assert!(!ports.is_empty(), "a console needs at least one port");
+        Self { ports, multiport: false, control: VecDeque::new() }
+    }
+
+    /// the receive queue of a port, the transmit queue follows it
+    fn rx_queue(port: usize) -> usize {
+        if port == 0 { 0 } else { 2 + port * 2 }
+    }
+
+    /// the port a data queue belongs to and whether it's the transmit queue
+    fn queue_port(queue: usize) -> Option<(usize, bool)> {
+        match queue {
+            0 | 1 => Some((0, queue == 1)),
+            CONTROL_RX | CONTROL_TX => None,
+            _ => Some((queue / 2 - 1, queue % 2 == 1)),
+        }
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16, extra: &[u8]) {
+        let mut message = Vec::with_capacity(CONTROL_MESSAGE_SIZE + extra.len());
+        message.extend_from_slice(&id.to_le_bytes());
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(extra);
+        self.control.push_back(message);
+    }
+
+    fn handle_control(&mut self, message: &[u8]) {
+        if message.len() < CONTROL_MESSAGE_SIZE {
+            return;
+        }
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+
+        match event {
+            event::DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() as u32 {
+                    self.send_control(id, event::DEVICE_ADD, 0, &[]);
+                }
+            }
+            event::PORT_READY if value == 1 => {
+                let Some(port) = self.ports.get(id as usize) else { return };
+                let name = port.name.clone();
+                if id == 0 {
+                    self.send_control(id, event::CONSOLE_PORT, 1, &[]);
+                }
+                if let Some(name) = name {
+                    self.send_control(id, event::PORT_NAME, 0, name.as_bytes());
+                }
+                // the host end is always open
+                self.send_control(id, event::PORT_OPEN, 1, &[]);
+            }
+            // the guest opening and closing ports doesn't change anything on the host side
+            _ => {}
+        }
+    }
+
+    /// hands pending input and control messages to the guest while it has buffers for them
+    fn deliver(&mut self, queues: &mut [Queue], dram: &mut Dram) -> Result<(), ()> {
+        let ports = if self.multiport { self.ports.len() } else { 1 };
+        for (index, port) in self.ports.iter_mut().enumerate().take(ports) {
+            if let Some(input) = &port.input {
+                port.pending.extend(input.try_iter());
+            }
+
+            let queue = &mut queues[Self::rx_queue(index)];
+            while !port.pending.is_empty() {
+                let Some(chain) = queue.pop(dram)? else { break };
+                let n = chain.writable_len().min(port.pending.len());
+                let data: Vec<u8> = port.pending.drain(..n).collect();
+                let written = chain.write_all(dram, &data).ok_or(())?;
+                queue.push(dram, &chain, written)?;
+            }
+        }
+
+        if self.multiport {
+            let queue = &mut queues[CONTROL_RX];
+            while let Some(message) = self.control.front() {
+                let Some(chain) = queue.pop(dram)? else { break };
+                let written = chain.write_all(dram, message).ok_or(())?;
+                queue.push(dram, &chain, written)?;
+                self.control.pop_front();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Console {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        feature::MULTIPORT | feature::EMERG_WRITE
+    }
+
+    fn num_queues(&self) -> usize {
+        // a pair for each port plus the control pair after the first port
+        self.ports.len() * 2 + 2
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        let mut config = [0u8; 12];
+        // cols and rows stay 0 as F_SIZE isn't offered
+        config[CONFIG_MAX_NR_PORTS..CONFIG_MAX_NR_PORTS + 4].copy_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        config.get(offset as usize).copied().unwrap_or(0)
+    }
+
+    fn write_config(&mut self, offset: u64, value: u8) {
+        // emergency writes go straight out of the console port
+        if offset == CONFIG_EMERG_WR {
+            let _ = self.ports[0].output.write_all(&[value]);
+            let _ = self.ports[0].output.flush();
+        }
+    }
+
+    fn set_features(&mut self, features: u64) {
+        self.multiport = features & feature::MULTIPORT != 0;
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], dram: &mut Dram) -> Result<(), ()> {
+        if queue == CONTROL_TX {
+            while let Some(chain) = queues[queue].pop(dram)? {
+                let message = chain.read_all(dram).ok_or(())?;
+                queues[queue].push(dram, &chain, 0)?;
+                self.handle_control(&message);
+            }
+        } else if let Some((port, true)) = Self::queue_port(queue) {
+            while let Some(chain) = queues[queue].pop(dram)? {
+                let data = chain.read_all(dram).ok_or(())?;
+                queues[queue].push(dram, &chain, 0)?;
+                if let Some(port) = self.ports.get_mut(port) {
+                    let _ = port.output.write_all(&data);
+                    let _ = port.output.flush();
+                }
+            }
+        }
+        // new receive buffers or control replies may have something waiting for them
+        self.deliver(queues, dram)
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], dram: &mut Dram) -> Result<(), ()> {
+        self.deliver(queues, dram)
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control.clear();
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+
+use crate::{dram::Dram, net::Backend};
+
+use super::{Queue, VirtioDevice};
+
+const DEVICE_ID: u32 = 1;
+
+mod feature {
+    pub const MAC: u64 = 1 << 5;
+    pub const STATUS: u64 = 1 << 16;
+}
+
+const RX: usize = 0;
+const TX: usize = 1;
+
+/// virtio_net_hdr with num_buffers, which VERSION_1 always has. nothing is
+/// offloaded so it's all zeros going to the guest and ignored coming from it
+const HEADER_SIZE: usize = 12;
+/// num_buffers, each frame fits in one buffer without mergeable receive buffers
+const HEADER_NUM_BUFFERS: usize = 10;
+
+const STATUS_LINK_UP: u16 = 1;
+
+/// frames held while the guest has no receive buffers, the oldest go first past this
+const RX_BACKLOG: usize = 256;
+
+/// a virtio-net device passing frames between the guest and a backend
+pub struct Net {
+    mac: [u8; 6],
+    backend: Box<dyn Backend>,
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl Net {
+    pub fn new(mac: [u8; 6], backend: Box<dyn Backend>) -> Self {
+        Self { mac, backend, pending: VecDeque::new() }
+    }
+
+    fn deliver(&mut self, queues: &mut [Queue], dram: &mut Dram) -> Result<(), ()> {
+        while let Some(frame) = self.backend.receive() {
+            if self.pending.len() == RX_BACKLOG {
+                self.pending.pop_front();
+            }
+            self.pending.push_back(frame);
+        }
+
+        let queue = &mut queues[RX];
+        while let Some(frame) = self.pending.front() {
+            let Some(chain) = queue.pop(dram)? else { break };
+            let mut data = vec![0; HEADER_SIZE];
+            data[HEADER_NUM_BUFFERS..HEADER_NUM_BUFFERS + 2].copy_from_slice(&1u16.to_le_bytes());
+            data.extend_from_slice(frame);
+            // frames too big for the buffer are cut short, the guest drops them
+            data.truncate(chain.writable_len());
+            let written = chain.write_all(dram, &data).ok_or(())?;
+            queue.push(dram, &chain, written)?;
+            self.pending.pop_front();
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        feature::MAC | feature::STATUS
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        let mut config = [0u8; 8];
+        config[0..6].copy_from_slice(&self.mac);
+        config[6..8].copy_from_slice(&STATUS_LINK_UP.to_le_bytes());
+        config.get(offset as usize).copied().unwrap_or(0)
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], dram: &mut Dram) -> Result<(), ()> {
+        if queue == TX {
+            while let Some(chain) = queues[TX].pop(dram)? {
+                let data = chain.read_all(dram).ok_or(())?;
+                queues[TX].push(dram, &chain, 0)?;
+                if let Some(frame) = data.get(HEADER_SIZE..) {
+                    self.backend.send(frame);
+                }
+            }
+        }
+        // new receive buffers may have frames waiting for them
+        self.deliver(queues, dram)
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], dram: &mut Dram) -> Result<(), ()> {
+        self.deliver(queues, dram)
+    }
+
+    fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
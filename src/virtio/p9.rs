@@ -0,0 +1,789 @@
+use std::{collections::HashMap, fs::{self, File, FileTimes, Metadata, OpenOptions}, io, os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt}, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::dram::Dram;
+
+use super::{Queue, VirtioDevice};
+
+const DEVICE_ID: u32 = 9;
+
+mod feature {
+    pub const MOUNT_TAG: u64 = 1 << 0;
+}
+
+const VERSION: &str = "9P2000.L";
+/// the biggest message either side can send, the guest can ask for less
+const MAX_MSIZE: u32 = 512 * 1024;
+/// size, type, tag, fid, offset and count come before the data of reads and writes
+const IO_HEADER_SIZE: u32 = 24;
+
+/// message types, each reply is its request plus one
+mod message {
+    pub const RLERROR: u8 = 7;
+    pub const TSTATFS: u8 = 8;
+    pub const TLOPEN: u8 = 12;
+    pub const TLCREATE: u8 = 14;
+    pub const TSYMLINK: u8 = 16;
+    pub const TMKNOD: u8 = 18;
+    pub const TRENAME: u8 = 20;
+    pub const TREADLINK: u8 = 22;
+    pub const TGETATTR: u8 = 24;
+    pub const TSETATTR: u8 = 26;
+    pub const TXATTRWALK: u8 = 30;
+    pub const TXATTRCREATE: u8 = 32;
+    pub const TREADDIR: u8 = 40;
+    pub const TFSYNC: u8 = 50;
+    pub const TLOCK: u8 = 52;
+    pub const TGETLOCK: u8 = 54;
+    pub const TLINK: u8 = 70;
+    pub const TMKDIR: u8 = 72;
+    pub const TRENAMEAT: u8 = 74;
+    pub const TUNLINKAT: u8 = 76;
+    pub const TVERSION: u8 = 100;
+    pub const TAUTH: u8 = 102;
+    pub const TATTACH: u8 = 104;
+    pub const TFLUSH: u8 = 108;
+    pub const TWALK: u8 = 110;
+    pub const TREAD: u8 = 116;
+    pub const TWRITE: u8 = 118;
+    pub const TCLUNK: u8 = 120;
+    pub const TREMOVE: u8 = 122;
+}
+
+/// Linux errno values, which is what 9P2000.L sends back
+mod errno {
+    pub const EIO: u32 = 5;
+    pub const EBADF: u32 = 9;
+    pub const ENOTDIR: u32 = 20;
+    pub const EINVAL: u32 = 22;
+    pub const EROFS: u32 = 30;
+    pub const ELOOP: u32 = 40;
+    pub const EPROTO: u32 = 71;
+    pub const EOPNOTSUPP: u32 = 95;
+}
+
+mod qid_type {
+    pub const DIR: u8 = 0x80;
+    pub const SYMLINK: u8 = 0x02;
+    pub const FILE: u8 = 0x00;
+}
+
+/// open flags as the Linux client sends them
+mod open_flags {
+    pub const ACCMODE: u32 = 0o3;
+    pub const WRONLY: u32 = 0o1;
+    pub const RDWR: u32 = 0o2;
+    pub const TRUNC: u32 = 0o1000;
+    pub const APPEND: u32 = 0o2000;
+}
+
+mod setattr {
+    pub const MODE: u32 = 1 << 0;
+    pub const UID: u32 = 1 << 1;
+    pub const GID: u32 = 1 << 2;
+    pub const SIZE: u32 = 1 << 3;
+    pub const ATIME: u32 = 1 << 4;
+    pub const MTIME: u32 = 1 << 5;
+    pub const ATIME_SET: u32 = 1 << 7;
+    pub const MTIME_SET: u32 = 1 << 8;
+}
+
+/// the fields of Rgetattr that are filled in
+const GETATTR_BASIC: u64 = 0x7ff;
+const AT_REMOVEDIR: u32 = 0x200;
+const V9FS_MAGIC: u32 = 0x01021997;
+const LOCK_SUCCESS: u8 = 0;
+const LOCK_TYPE_UNLCK: u8 = 2;
+
+/// the host's O_NOFOLLOW, so a file swapped for a symlink after it was
+/// checked fails to open instead of leading out of the share
+#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+const O_NOFOLLOW: i32 = 0o100000;
+#[cfg(not(any(target_arch = "aarch64", target_arch = "arm")))]
+const O_NOFOLLOW: i32 = 0o400000;
+
+type Result<T> = std::result::Result<T, u32>;
+
+fn errno(e: io::Error) -> u32 {
+    e.raw_os_error().map_or(errno::EIO, |code| code as u32)
+}
+
+/// pulls little endian fields out of a request
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, n: usize) -> Result<&[u8]> {
+        if self.data.len() < n {
+            return Err(errno::EPROTO);
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| errno::EINVAL)
+    }
+}
+
+/// builds a reply, the size is filled in once it's finished
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn new(kind: u8, tag: u16) -> Self {
+        let mut writer = Self { data: vec![0; 4] };
+        writer.u8(kind);
+        writer.u16(tag);
+        writer
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u16(value.len() as u16);
+        self.data.extend_from_slice(value.as_bytes());
+    }
+
+    fn qid(&mut self, qid: Qid) {
+        self.u8(qid.kind);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.data.len() as u32;
+        self.data[0..4].copy_from_slice(&size.to_le_bytes());
+        self.data
+    }
+}
+
+/// the server's unique identity for a file
+#[derive(Clone, Copy)]
+struct Qid {
+    kind: u8,
+    version: u32,
+    path: u64,
+}
+
+fn qid(metadata: &Metadata) -> Qid {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        qid_type::DIR
+    } else if file_type.is_symlink() {
+        qid_type::SYMLINK
+    } else {
+        qid_type::FILE
+    };
+    Qid { kind, version: 0, path: metadata.ino() }
+}
+
+/// the d_type readdir reports for each entry
+fn dirent_type(metadata: &Metadata) -> u8 {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        4
+    } else if file_type.is_symlink() {
+        10
+    } else if file_type.is_char_device() {
+        2
+    } else if file_type.is_block_device() {
+        6
+    } else if file_type.is_fifo() {
+        1
+    } else if file_type.is_socket() {
+        12
+    } else {
+        8
+    }
+}
+
+/// a name the guest wants to create or look up within a directory
+fn check_name(name: &str) -> Result<&str> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(errno::EINVAL);
+    }
+    Ok(name)
+}
+
+/// a file the guest has a handle on, the path is relative to the share's root
+struct Fid {
+    path: PathBuf,
+    file: Option<File>,
+    /// the directory listing being read, taken when reading starts from the beginning
+    entries: Vec<(String, Metadata)>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Self {
+        Self { path, file: None, entries: Vec::new() }
+    }
+}
+
+/// how the guest's view of ownership and permissions relates to the host's
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Security {
+    /// files carry the host's owners and modes, and guest changes go straight through
+    Passthrough,
+    /// like passthrough, but nothing on the host can be changed
+    ReadOnly,
+}
+
+/// a virtio-9p device sharing a host directory using 9P2000.L
+pub struct P9 {
+    root: PathBuf,
+    tag: String,
+    security: Security,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl P9 {
+    pub fn new(root: PathBuf, tag: String, security: Security) -> Self {
+        Self { root, tag, security, msize: MAX_MSIZE, fids: HashMap::new() }
+    }
+
+    fn host_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path)
+    }
+
+    fn fid(&self, fid: u32) -> Result<&Fid> {
+        self.fids.get(&fid).ok_or(errno::EBADF)
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or(errno::EBADF)
+    }
+
+    fn writable(&self) -> Result<()> {
+        match self.security {
+            Security::ReadOnly => Err(errno::EROFS),
+            Security::Passthrough => Ok(()),
+        }
+    }
+
+    /// symlinks are never followed on the host, the guest resolves them itself
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        fs::symlink_metadata(self.host_path(path)).map_err(errno)
+    }
+
+    /// the guest's path for a child of a directory fid
+    fn child(&self, dfid: u32, name: &str) -> Result<PathBuf> {
+        let dir = &self.fid(dfid)?.path;
+        if !self.metadata(dir)?.is_dir() {
+            return Err(errno::ENOTDIR);
+        }
+        Ok(dir.join(check_name(name)?))
+    }
+
+    /// creating files as the guest user only works when the host lets us, so
+    /// ownership is changed where possible and left alone otherwise
+    fn set_owner(&self, path: &Path, gid: u32) {
+        let _ = std::os::unix::fs::lchown(self.host_path(path), None, Some(gid));
+    }
+
+    /// points fids at a path, or anything under it, to where it's been moved
+    fn renamed(&mut self, from: &Path, to: &Path) {
+        for fid in self.fids.values_mut() {
+            if fid.path == from {
+                fid.path = to.to_path_buf();
+            } else if let Ok(rest) = fid.path.strip_prefix(from) {
+                fid.path = to.join(rest);
+            }
+        }
+    }
+
+    fn handle(&mut self, kind: u8, tag: u16, request: &mut Reader) -> Result<Writer> {
+        let mut reply = Writer::new(kind + 1, tag);
+        match kind {
+            message::TVERSION => {
+                let msize = request.u32()?;
+                let version = request.string()?;
+                if msize <= IO_HEADER_SIZE {
+                    return Err(errno::EINVAL);
+                }
+                self.msize = msize.min(MAX_MSIZE);
+                // a new session throws away everything from the last one
+                self.fids.clear();
+                reply.u32(self.msize);
+                reply.string(if version == VERSION { VERSION } else { "unknown" });
+            }
+            message::TAUTH => return Err(errno::EOPNOTSUPP),
+            message::TATTACH => {
+                let fid = request.u32()?;
+                let _afid = request.u32()?;
+                let _uname = request.string()?;
+                let _aname = request.string()?;
+                let _n_uname = request.u32()?;
+                let metadata = self.metadata(Path::new(""))?;
+                self.fids.insert(fid, Fid::new(PathBuf::new()));
+                reply.qid(qid(&metadata));
+            }
+            message::TFLUSH => {
+                // every request is finished before the next one is read so there's nothing to cancel
+                let _oldtag = request.u16()?;
+            }
+            message::TWALK => {
+                let fid = request.u32()?;
+                let newfid = request.u32()?;
+                let nwname = request.u16()?;
+                let mut path = self.fid(fid)?.path.clone();
+                if newfid != fid && self.fids.contains_key(&newfid) {
+                    return Err(errno::EINVAL);
+                }
+
+                let mut qids = Vec::new();
+                for i in 0..nwname {
+                    let name = request.string()?;
+                    if name.is_empty() || name.contains('/') {
+                        return Err(errno::EINVAL);
+                    }
+                    if !self.metadata(&path)?.is_dir() {
+                        if i == 0 {
+                            return Err(errno::ENOTDIR);
+                        }
+                        break;
+                    }
+                    let next = match name.as_str() {
+                        // the root is its own parent so the guest can't walk out of the share
+                        ".." => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+                        "." => path.clone(),
+                        _ => path.join(&name),
+                    };
+                    match self.metadata(&next) {
+                        Ok(metadata) => qids.push(qid(&metadata)),
+                        Err(e) if i == 0 => return Err(e),
+                        Err(_) => break,
+                    }
+                    path = next;
+                }
+
+                // a partial walk reports how far it got but doesn't create the new fid
+                if qids.len() == nwname as usize {
+                    self.fids.insert(newfid, Fid::new(path));
+                }
+                reply.u16(qids.len() as u16);
+                for qid in qids {
+                    reply.qid(qid);
+                }
+            }
+            message::TLOPEN => {
+                let fid = request.u32()?;
+                let flags = request.u32()?;
+                let path = self.fid(fid)?.path.clone();
+                let metadata = self.metadata(&path)?;
+                if metadata.file_type().is_symlink() {
+                    return Err(errno::ELOOP);
+                }
+                if flags & (open_flags::ACCMODE | open_flags::TRUNC | open_flags::APPEND) != 0 {
+                    self.writable()?;
+                }
+                if !metadata.is_dir() {
+                    let accmode = flags & open_flags::ACCMODE;
+                    let file = OpenOptions::new()
+                        .read(accmode != open_flags::WRONLY)
+                        .write(accmode == open_flags::WRONLY || accmode == open_flags::RDWR)
+                        .append(flags & open_flags::APPEND != 0)
+                        .truncate(flags & open_flags::TRUNC != 0)
+                        .custom_flags(O_NOFOLLOW)
+                        .open(self.host_path(&path))
+                        .map_err(errno)?;
+                    self.fid_mut(fid)?.file = Some(file);
+                }
+                reply.qid(qid(&metadata));
+                reply.u32(self.msize - IO_HEADER_SIZE);
+            }
+            message::TLCREATE => {
+                let fid = request.u32()?;
+                let name = request.string()?;
+                let flags = request.u32()?;
+                let mode = request.u32()?;
+                let gid = request.u32()?;
+                self.writable()?;
+                let path = self.child(fid, &name)?;
+                let accmode = flags & open_flags::ACCMODE;
+                let file = OpenOptions::new()
+                    .read(accmode != open_flags::WRONLY)
+                    .write(true)
+                    .append(flags & open_flags::APPEND != 0)
+                    .create_new(true)
+                    .mode(mode & 0o7777)
+                    .open(self.host_path(&path))
+                    .map_err(errno)?;
+                self.set_owner(&path, gid);
+                let metadata = file.metadata().map_err(errno)?;
+
+                // the fid now refers to the new file rather than the directory
+                let fid = self.fid_mut(fid)?;
+                fid.path = path;
+                fid.file = Some(file);
+                reply.qid(qid(&metadata));
+                reply.u32(self.msize - IO_HEADER_SIZE);
+            }
+            message::TSYMLINK => {
+                let fid = request.u32()?;
+                let name = request.string()?;
+                let target = request.string()?;
+                let gid = request.u32()?;
+                self.writable()?;
+                let path = self.child(fid, &name)?;
+                std::os::unix::fs::symlink(target, self.host_path(&path)).map_err(errno)?;
+                self.set_owner(&path, gid);
+                reply.qid(qid(&self.metadata(&path)?));
+            }
+            message::TMKNOD => return Err(errno::EOPNOTSUPP),
+            message::TRENAME => {
+                let fid = request.u32()?;
+                let dfid = request.u32()?;
+                let name = request.string()?;
+                self.writable()?;
+                let from = self.fid(fid)?.path.clone();
+                let to = self.child(dfid, &name)?;
+                fs::rename(self.host_path(&from), self.host_path(&to)).map_err(errno)?;
+                self.renamed(&from, &to);
+            }
+            message::TREADLINK => {
+                let fid = request.u32()?;
+                let target = fs::read_link(self.host_path(&self.fid(fid)?.path)).map_err(errno)?;
+                reply.string(&target.to_string_lossy());
+            }
+            message::TGETATTR => {
+                let fid = request.u32()?;
+                let _request_mask = request.u64()?;
+                let metadata = self.metadata(&self.fid(fid)?.path)?;
+                reply.u64(GETATTR_BASIC);
+                reply.qid(qid(&metadata));
+                reply.u32(metadata.mode());
+                reply.u32(metadata.uid());
+                reply.u32(metadata.gid());
+                reply.u64(metadata.nlink());
+                reply.u64(metadata.rdev());
+                reply.u64(metadata.size());
+                reply.u64(metadata.blksize());
+                reply.u64(metadata.blocks());
+                reply.u64(metadata.atime() as u64);
+                reply.u64(metadata.atime_nsec() as u64);
+                reply.u64(metadata.mtime() as u64);
+                reply.u64(metadata.mtime_nsec() as u64);
+                reply.u64(metadata.ctime() as u64);
+                reply.u64(metadata.ctime_nsec() as u64);
+                // btime, gen and data_version aren't reported
+                reply.u64(0);
+                reply.u64(0);
+                reply.u64(0);
+                reply.u64(0);
+            }
+            message::TSETATTR => {
+                let fid = request.u32()?;
+                let valid = request.u32()?;
+                let mode = request.u32()?;
+                let uid = request.u32()?;
+                let gid = request.u32()?;
+                let size = request.u64()?;
+                let atime = Duration::new(request.u64()?, request.u64()? as u32);
+                let mtime = Duration::new(request.u64()?, request.u64()? as u32);
+                self.writable()?;
+                let path = self.fid(fid)?.path.clone();
+                // all but the owner would be changed on whatever a symlink points at
+                if valid & (setattr::MODE | setattr::SIZE | setattr::ATIME | setattr::MTIME) != 0 && self.metadata(&path)?.file_type().is_symlink() {
+                    return Err(errno::ELOOP);
+                }
+                let path = self.host_path(&path);
+
+                if valid & setattr::MODE != 0 {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+                }
+                if valid & (setattr::UID | setattr::GID) != 0 {
+                    let uid = (valid & setattr::UID != 0).then_some(uid);
+                    let gid = (valid & setattr::GID != 0).then_some(gid);
+                    std::os::unix::fs::lchown(&path, uid, gid).map_err(errno)?;
+                }
+                if valid & setattr::SIZE != 0 {
+                    OpenOptions::new().write(true).custom_flags(O_NOFOLLOW).open(&path).and_then(|file| file.set_len(size)).map_err(errno)?;
+                }
+                if valid & (setattr::ATIME | setattr::MTIME) != 0 {
+                    // times without the _SET bits mean now
+                    let time = |set: u32, time: Duration| if valid & set != 0 { UNIX_EPOCH + time } else { SystemTime::now() };
+                    let mut times = FileTimes::new();
+                    if valid & setattr::ATIME != 0 {
+                        times = times.set_accessed(time(setattr::ATIME_SET, atime));
+                    }
+                    if valid & setattr::MTIME != 0 {
+                        times = times.set_modified(time(setattr::MTIME_SET, mtime));
+                    }
+                    OpenOptions::new().read(true).custom_flags(O_NOFOLLOW).open(&path).and_then(|file| file.set_times(times)).map_err(errno)?;
+                }
+            }
+            message::TXATTRWALK | message::TXATTRCREATE => return Err(errno::EOPNOTSUPP),
+            message::TREADDIR => {
+                let fid = request.u32()?;
+                let offset = request.u64()?;
+                let count = request.u32()?.min(self.msize - IO_HEADER_SIZE) as usize;
+                let path = self.fid(fid)?.path.clone();
+
+                if offset == 0 {
+                    let mut entries = vec![
+                        (".".to_string(), self.metadata(&path)?),
+                        ("..".to_string(), self.metadata(path.parent().unwrap_or(Path::new("")))?),
+                    ];
+                    for entry in fs::read_dir(self.host_path(&path)).map_err(errno)? {
+                        let entry = entry.map_err(errno)?;
+                        if let Ok(metadata) = entry.metadata() {
+                            entries.push((entry.file_name().to_string_lossy().into_owned(), metadata));
+                        }
+                    }
+                    self.fid_mut(fid)?.entries = entries;
+                }
+
+                // offsets are just the index of the next entry
+                let mut data = Writer { data: Vec::new() };
+                for (i, (name, metadata)) in self.fid(fid)?.entries.iter().enumerate().skip(offset as usize) {
+                    let entry_len = 13 + 8 + 1 + 2 + name.len();
+                    if data.data.len() + entry_len > count {
+                        break;
+                    }
+                    data.qid(qid(metadata));
+                    data.u64(i as u64 + 1);
+                    data.u8(dirent_type(metadata));
+                    data.string(name);
+                }
+                reply.u32(data.data.len() as u32);
+                reply.data.extend_from_slice(&data.data);
+            }
+            message::TFSYNC => {
+                let fid = request.u32()?;
+                let datasync = request.u32()?;
+                if let Some(file) = &self.fid(fid)?.file {
+                    if datasync != 0 { file.sync_data() } else { file.sync_all() }.map_err(errno)?;
+                }
+            }
+            message::TLOCK => {
+                // locks only matter between guests sharing a directory which can't happen
+                reply.u8(LOCK_SUCCESS);
+            }
+            message::TGETLOCK => {
+                let _fid = request.u32()?;
+                let _kind = request.u8()?;
+                let start = request.u64()?;
+                let length = request.u64()?;
+                let proc_id = request.u32()?;
+                let client_id = request.string()?;
+                reply.u8(LOCK_TYPE_UNLCK);
+                reply.u64(start);
+                reply.u64(length);
+                reply.u32(proc_id);
+                reply.string(&client_id);
+            }
+            message::TLINK => {
+                let dfid = request.u32()?;
+                let fid = request.u32()?;
+                let name = request.string()?;
+                self.writable()?;
+                let target = self.host_path(&self.fid(fid)?.path);
+                let path = self.child(dfid, &name)?;
+                fs::hard_link(target, self.host_path(&path)).map_err(errno)?;
+            }
+            message::TMKDIR => {
+                let dfid = request.u32()?;
+                let name = request.string()?;
+                let mode = request.u32()?;
+                let gid = request.u32()?;
+                self.writable()?;
+                let path = self.child(dfid, &name)?;
+                fs::create_dir(self.host_path(&path)).map_err(errno)?;
+                fs::set_permissions(self.host_path(&path), fs::Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+                self.set_owner(&path, gid);
+                reply.qid(qid(&self.metadata(&path)?));
+            }
+            message::TRENAMEAT => {
+                let olddirfid = request.u32()?;
+                let oldname = request.string()?;
+                let newdirfid = request.u32()?;
+                let newname = request.string()?;
+                self.writable()?;
+                let from = self.child(olddirfid, &oldname)?;
+                let to = self.child(newdirfid, &newname)?;
+                fs::rename(self.host_path(&from), self.host_path(&to)).map_err(errno)?;
+                self.renamed(&from, &to);
+            }
+            message::TUNLINKAT => {
+                let dirfd = request.u32()?;
+                let name = request.string()?;
+                let flags = request.u32()?;
+                self.writable()?;
+                let path = self.host_path(&self.child(dirfd, &name)?);
+                if flags & AT_REMOVEDIR != 0 { fs::remove_dir(path) } else { fs::remove_file(path) }.map_err(errno)?;
+            }
+            message::TSTATFS => {
+                let fid = request.u32()?;
+                self.fid(fid)?;
+                // std can't ask the host for its filesystem's numbers so these are made up
+                reply.u32(V9FS_MAGIC);
+                reply.u32(4096);
+                reply.u64(1 << 28);
+                reply.u64(1 << 27);
+                reply.u64(1 << 27);
+                reply.u64(1 << 24);
+                reply.u64(1 << 23);
+                reply.u64(0);
+                reply.u32(255);
+            }
+            message::TREAD => {
+                let fid = request.u32()?;
+                let offset = request.u64()?;
+                let count = request.u32()?.min(self.msize - IO_HEADER_SIZE);
+                let file = self.fid(fid)?.file.as_ref().ok_or(errno::EBADF)?;
+                let mut data = vec![0; count as usize];
+                let mut read = 0;
+                // keep going until the end of the file so short reads don't look like it
+                while read < data.len() {
+                    match file.read_at(&mut data[read..], offset + read as u64) {
+                        Ok(0) => break,
+                        Ok(n) => read += n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(errno(e)),
+                    }
+                }
+                reply.u32(read as u32);
+                reply.data.extend_from_slice(&data[..read]);
+            }
+            message::TWRITE => {
+                let fid = request.u32()?;
+                let offset = request.u64()?;
+                let count = request.u32()?;
+                let data = request.bytes(count as usize)?;
+                self.writable()?;
+                let file = self.fid(fid)?.file.as_ref().ok_or(errno::EBADF)?;
+                file.write_all_at(data, offset).map_err(errno)?;
+                reply.u32(count);
+            }
+            message::TCLUNK => {
+                let fid = request.u32()?;
+                self.fids.remove(&fid).ok_or(errno::EBADF)?;
+            }
+            message::TREMOVE => {
+                let fid = request.u32()?;
+                // the fid goes away even if the remove fails
+                let Fid { path, .. } = self.fids.remove(&fid).ok_or(errno::EBADF)?;
+                self.writable()?;
+                if path.components().next().is_none() {
+                    return Err(errno::EINVAL);
+                }
+                let host_path = self.host_path(&path);
+                if self.metadata(&path)?.is_dir() { fs::remove_dir(host_path) } else { fs::remove_file(host_path) }.map_err(errno)?;
+            }
+            _ => return Err(errno::EOPNOTSUPP),
+        }
+        Ok(reply)
+    }
+
+    /// answers one request, errors come back as Rlerror
+    fn respond(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut reader = Reader { data: request };
+        let header = |reader: &mut Reader| -> Result<(u8, u16)> {
+            let _size = reader.u32()?;
+            Ok((reader.u8()?, reader.u16()?))
+        };
+        let Ok((kind, tag)) = header(&mut reader) else {
+            let mut reply = Writer::new(message::RLERROR, 0);
+            reply.u32(errno::EPROTO);
+            return reply.finish();
+        };
+
+        match self.handle(kind, tag, &mut reader) {
+            Ok(reply) => reply.finish(),
+            Err(code) => {
+                let mut reply = Writer::new(message::RLERROR, tag);
+                reply.u32(code);
+                reply.finish()
+            }
+        }
+    }
+}
+
+impl VirtioDevice for P9 {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        feature::MOUNT_TAG
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // the tag's length followed by the tag, which is what the guest passes to mount
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        config.get(offset as usize).copied().unwrap_or(0)
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], dram: &mut Dram) -> std::result::Result<(), ()> {
+        let queue = &mut queues[queue];
+        while let Some(chain) = queue.pop(dram)? {
+            let request = chain.read_all(dram).ok_or(())?;
+            let mut reply = self.respond(&request);
+            // a reply that doesn't fit is turned into an error the guest can see
+            if reply.len() > chain.writable_len() {
+                let tag = request.get(5..7).map_or(0, |tag| u16::from_le_bytes(tag.try_into().unwrap()));
+                let mut error = Writer::new(message::RLERROR, tag);
+                error.u32(errno::EIO);
+                reply = error.finish();
+            }
+            let written = chain.write_all(dram, &reply).ok_or(())?;
+            queue.push(dram, &chain, written)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.msize = MAX_MSIZE;
+        self.fids.clear();
+    }
+}
+
+/// the share has to be an existing directory, symlinks in the path to it are
+/// resolved up front
+pub fn check_root(root: &Path) -> io::Result<PathBuf> {
+    let root = root.canonicalize()?;
+    if !root.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a directory"));
+    }
+    Ok(root)
+}
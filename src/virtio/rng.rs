@@ -0,0 +1,96 @@
+use std::{collections::hash_map::RandomState, hash::{BuildHasher, Hasher}};
+
+use crate::dram::Dram;
+
+use super::{Queue, VirtioDevice};
+
+const DEVICE_ID: u32 = 4;
+
+/// xoshiro256**, fast and good enough to keep the guest's entropy pool happy
+/// but not for anything that needs to be secure
+pub struct Prng {
+    state: [u64; 4],
+}
+
+impl Prng {
+    /// the same seed always gives the same stream
+    pub fn from_seed(seed: u64) -> Self {
+        // splitmix64 spreads the seed over the state so it's never all zeros
+        let mut x = seed;
+        let mut next = || {
+            x = x.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        };
+        Self { state: [next(), next(), next(), next()] }
+    }
+
+    /// seeds from the host, std's hash map keys are random per process
+    pub fn from_host() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        Self::from_seed(hasher.finish())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// a virtio-rng device, every buffer the driver offers comes back full
+pub struct Rng {
+    prng: Prng,
+}
+
+impl Rng {
+    pub fn new(prng: Prng) -> Self {
+        Self { prng }
+    }
+}
+
+impl VirtioDevice for Rng {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, _offset: u64) -> u8 {
+        0
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], dram: &mut Dram) -> Result<(), ()> {
+        let queue = &mut queues[queue];
+        while let Some(chain) = queue.pop(dram)? {
+            let mut data = vec![0; chain.writable_len()];
+            self.prng.fill(&mut data);
+            let written = chain.write_all(dram, &data).ok_or(())?;
+            queue.push(dram, &chain, written)?;
+        }
+        Ok(())
+    }
+}